@@ -0,0 +1,839 @@
+// Concentrated liquidity (Uniswap v3 style), a separate pool type next to Pool.
+//
+// Prices are sqrt(price) in Q64.64, price = token B per token A. Liquidity is
+// added over a [tick_lower, tick_upper) range, where tick t is the price 1.0001^t.
+// Only ranges containing the current tick are active, so the swap loop walks the
+// initialized ticks and adds or removes their liquidity_net as it crosses them.
+//
+// Ticks live in TickArray accounts of TICK_ARRAY_SIZE ticks, one every
+// tick_spacing. Positions are Position accounts keyed by an NFT mint, whoever
+// holds the NFT owns the position.
+//
+// Swap fees go to the positions that were in range, accounted with Uniswap v3's
+// fee growth accumulators, less Amm::protocol_fee_share which the pool keeps aside
+// like Pool::protocol_fees_a / _b.
+use anchor_lang::prelude::*;
+use std::ops::DerefMut;
+
+use crate::math::{self, U256};
+use crate::{ErrorCode, FEE_DENOMINATOR};
+
+// Bumped whenever the ClmmPool layout changes
+pub const CLMM_POOL_VERSION: u8 = 1;
+
+// sqrt(1.0001^MAX_TICK) is just under 2^32, so every sqrt price fits in Q64.64
+// and the amount math stays within U256
+pub const MIN_TICK: i32 = -443_636;
+pub const MAX_TICK: i32 = 443_636;
+pub const MIN_SQRT_PRICE_X64: u128 = 4_295_048_017;
+pub const MAX_SQRT_PRICE_X64: u128 = 79_226_673_515_401_279_992_447_579_062;
+
+pub const MAX_TICK_SPACING: u16 = 1_000;
+pub const TICK_ARRAY_SIZE: usize = 60;
+
+// Swaps may cross into this many tick arrays, passed as remaining accounts
+pub const MAX_SWAP_TICK_ARRAYS: usize = 3;
+
+#[account]
+#[derive(InitSpace, Default)]
+pub struct ClmmPool {
+    pub version: u8, // CLMM_POOL_VERSION at the time the account was written
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub vault_a: Pubkey,
+    pub vault_b: Pubkey,
+    pub bump: u8,
+    pub tick_spacing: u16,
+    pub fee: u16, // basis points, see FEE_DENOMINATOR
+    pub creator: Pubkey,
+    pub sqrt_price_x64: u128,
+    pub tick_current: i32, // largest tick with sqrt_price_at_tick(tick) <= sqrt_price_x64
+    pub liquidity: u128,   // liquidity of the ranges containing tick_current
+    // Fees earned per unit of liquidity over the pool's life, Q64.64, wrapping
+    pub fee_growth_global_a_x64: u128,
+    pub fee_growth_global_b_x64: u128,
+    pub status: u8, // POOL_*_DISABLED bits, see set_clmm_pool_status
+    // Protocol share of the swap fees, still in the vaults until collected
+    pub protocol_fees_a: u64,
+    pub protocol_fees_b: u64,
+}
+
+impl ClmmPool {
+    // True if the two mints are this pool's pair, in either order
+    pub fn is_pair(&self, mint_x: &Pubkey, mint_y: &Pubkey) -> bool {
+        (*mint_x == self.mint_a && *mint_y == self.mint_b)
+            || (*mint_x == self.mint_b && *mint_y == self.mint_a)
+    }
+
+    // Vault holding mint_x, assumes mint_x is one of the pair (see is_pair)
+    pub fn vault_for(&self, mint_x: &Pubkey) -> Pubkey {
+        if *mint_x == self.mint_a {
+            self.vault_a
+        } else {
+            self.vault_b
+        }
+    }
+}
+
+#[zero_copy]
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct Tick {
+    pub liquidity_net: i128,   // added when crossed left to right, removed right to left
+    pub liquidity_gross: u128, // total liquidity referencing the tick, 0 = uninitialized
+    // Fee growth on the other side of the tick from tick_current, Uniswap v3 convention
+    pub fee_growth_outside_a_x64: u128,
+    pub fee_growth_outside_b_x64: u128,
+    pub initialized: u8,
+    pub padding: [u8; 15],
+}
+
+#[account(zero_copy)]
+pub struct TickArray {
+    pub pool: Pubkey,
+    pub start_tick_index: i32, // multiple of tick_spacing * TICK_ARRAY_SIZE
+    pub padding: [u8; 12],
+    pub ticks: [Tick; TICK_ARRAY_SIZE],
+}
+
+#[account]
+#[derive(InitSpace, Default)]
+pub struct Position {
+    pub pool: Pubkey,
+    pub position_mint: Pubkey, // NFT, its holder owns the position
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: u128,
+    // Fee growth inside the range when fees were last accrued
+    pub fee_growth_inside_a_last_x64: u128,
+    pub fee_growth_inside_b_last_x64: u128,
+    pub fees_owed_a: u64,
+    pub fees_owed_b: u64,
+    pub bump: u8,
+}
+
+// Start of the tick array holding `tick`
+pub fn tick_array_start(tick: i32, tick_spacing: u16) -> i32 {
+    let span = tick_spacing as i32 * TICK_ARRAY_SIZE as i32;
+    tick.div_euclid(span) * span
+}
+
+// A usable position bound: in range and on the pool's tick spacing
+pub fn check_tick(tick: i32, tick_spacing: u16) -> Result<()> {
+    require!((MIN_TICK..=MAX_TICK).contains(&tick), ErrorCode::InvalidTick);
+    require!(tick % tick_spacing as i32 == 0, ErrorCode::InvalidTick);
+    Ok(())
+}
+
+impl TickArray {
+    // Slot of `tick` in this array
+    fn offset(&self, tick: i32, tick_spacing: u16) -> Result<usize> {
+        let spacing = tick_spacing as i32;
+        let relative = tick - self.start_tick_index;
+        require!(relative >= 0 && relative % spacing == 0, ErrorCode::InvalidTickArray);
+
+        let offset = (relative / spacing) as usize;
+        require!(offset < TICK_ARRAY_SIZE, ErrorCode::InvalidTickArray);
+        Ok(offset)
+    }
+
+    pub fn tick_mut(&mut self, tick: i32, tick_spacing: u16) -> Result<&mut Tick> {
+        let offset = self.offset(tick, tick_spacing)?;
+        Ok(&mut self.ticks[offset])
+    }
+
+    // Next tick the swap has to stop at, as (tick, initialized), searching at or
+    // below tick_current for a_to_b and above it otherwise. Without an initialized
+    // tick that is the array's edge. None once the search has left this array.
+    pub fn next_tick(&self, tick_current: i32, tick_spacing: u16, a_to_b: bool) -> Option<(i32, bool)> {
+        let spacing = tick_spacing as i32;
+        let current = (tick_current - self.start_tick_index).div_euclid(spacing);
+        let tick_at = |offset: usize| self.start_tick_index + offset as i32 * spacing;
+
+        if a_to_b {
+            if current < 0 {
+                return None;
+            }
+            let first = (current as usize).min(TICK_ARRAY_SIZE - 1);
+            let found = (0..=first).rev().find(|offset| self.ticks[*offset].initialized != 0);
+            Some(found.map_or((self.start_tick_index, false), |offset| (tick_at(offset), true)))
+        } else {
+            let first = (current + 1).max(0) as usize;
+            if first >= TICK_ARRAY_SIZE {
+                return None;
+            }
+            let found = (first..TICK_ARRAY_SIZE).find(|offset| self.ticks[*offset].initialized != 0);
+            Some(found.map_or((tick_at(TICK_ARRAY_SIZE - 1), false), |offset| (tick_at(offset), true)))
+        }
+    }
+}
+
+// 1 / sqrt(1.0001)^(2^i) in Q128.128 for i = 1.., Uniswap v3's TickMath constants
+const TICK_FACTORS: [(u32, u128); 18] = [
+    (0x2, 0xfff97272373d413259a46990580e213a),
+    (0x4, 0xfff2e50f5f656932ef12357cf3c7fdcc),
+    (0x8, 0xffe5caca7e10e4e61c3624eaa0941cd0),
+    (0x10, 0xffcb9843d60f6159c9db58835c926644),
+    (0x20, 0xff973b41fa98c081472e6896dfb254c0),
+    (0x40, 0xff2ea16466c96a3843ec78b326b52861),
+    (0x80, 0xfe5dee046a99a2a811c461f1969c3053),
+    (0x100, 0xfcbe86c7900a88aedcffc83b479aa3a4),
+    (0x200, 0xf987a7253ac413176f2b074cf7815e54),
+    (0x400, 0xf3392b0822b70005940c7a398e4b70f3),
+    (0x800, 0xe7159475a2c29b7443b29c7fa6e889d9),
+    (0x1000, 0xd097f3bdfd2022b8845ad8f792aa5825),
+    (0x2000, 0xa9f746462d870fdf8a65dc1f90e061e5),
+    (0x4000, 0x70d869a156d2a1b890bb3df62baf32f7),
+    (0x8000, 0x31be135f97d08fd981231505542fcfa6),
+    (0x10000, 0x9aa508b5b7a84e1c677de54f3e99bc9),
+    (0x20000, 0x5d6af8dedb81196699c329225ee604),
+    (0x40000, 0x2216e584f5fa1ea926041bedfe98),
+];
+
+// sqrt(1.0001^tick) in Q64.64, rounded up
+pub fn sqrt_price_at_tick(tick: i32) -> Result<u128> {
+    require!((MIN_TICK..=MAX_TICK).contains(&tick), ErrorCode::InvalidTick);
+
+    let abs_tick = tick.unsigned_abs();
+    let mut ratio = if abs_tick & 1 != 0 {
+        U256::from(0xfffcb933bd6fad37aa2d162d1a594001u128)
+    } else {
+        U256::one() << 128
+    };
+    for (bit, factor) in TICK_FACTORS {
+        if abs_tick & bit != 0 {
+            ratio = (ratio * U256::from(factor)) >> 128;
+        }
+    }
+    if tick > 0 {
+        ratio = U256::MAX / ratio;
+    }
+
+    let remainder = !(ratio & U256::from(u64::MAX)).is_zero();
+    let sqrt_price = (ratio >> 64) + U256::from(remainder as u8);
+    Ok(math::u256_to_u128(sqrt_price).ok_or(ErrorCode::MathOverflow)?)
+}
+
+// Largest tick with sqrt_price_at_tick(tick) <= sqrt_price_x64, by binary search
+pub fn tick_at_sqrt_price(sqrt_price_x64: u128) -> Result<i32> {
+    require!(
+        (MIN_SQRT_PRICE_X64..MAX_SQRT_PRICE_X64).contains(&sqrt_price_x64),
+        ErrorCode::InvalidSqrtPrice
+    );
+
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while low < high {
+        let middle = low + (high - low + 1) / 2;
+        if sqrt_price_at_tick(middle)? <= sqrt_price_x64 {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    Ok(low)
+}
+
+// liquidity + delta, None on underflow or overflow
+pub fn add_delta(liquidity: u128, delta: i128) -> Option<u128> {
+    if delta < 0 {
+        liquidity.checked_sub(delta.unsigned_abs())
+    } else {
+        liquidity.checked_add(delta as u128)
+    }
+}
+
+fn div_round(numerator: U256, denominator: U256, round_up: bool) -> U256 {
+    let quotient = numerator / denominator;
+    if round_up && !(numerator % denominator).is_zero() {
+        quotient + 1
+    } else {
+        quotient
+    }
+}
+
+// Token A between two prices: L * (sqrt_upper - sqrt_lower) / (sqrt_upper * sqrt_lower)
+pub fn amount_a_delta(sqrt_price_a: u128, sqrt_price_b: u128, liquidity: u128, round_up: bool) -> Option<u128> {
+    let (lower, upper) = if sqrt_price_a < sqrt_price_b { (sqrt_price_a, sqrt_price_b) } else { (sqrt_price_b, sqrt_price_a) };
+    if lower == 0 {
+        return None;
+    }
+
+    let numerator = (U256::from(liquidity) << 64).checked_mul(U256::from(upper - lower))?;
+    let amount = div_round(div_round(numerator, U256::from(upper), round_up), U256::from(lower), round_up);
+    math::u256_to_u128(amount)
+}
+
+// Token B between two prices: L * (sqrt_upper - sqrt_lower)
+pub fn amount_b_delta(sqrt_price_a: u128, sqrt_price_b: u128, liquidity: u128, round_up: bool) -> Option<u128> {
+    let (lower, upper) = if sqrt_price_a < sqrt_price_b { (sqrt_price_a, sqrt_price_b) } else { (sqrt_price_b, sqrt_price_a) };
+
+    let numerator = U256::from(liquidity) * U256::from(upper - lower);
+    math::u256_to_u128(div_round(numerator, U256::one() << 64, round_up))
+}
+
+// Price after adding `amount_in` of the input token, rounded so the pool never
+// gives away more than the input pays for:
+//   A in (price down): L * sqrt / (L + amount * sqrt), rounded up
+//   B in (price up):   sqrt + amount / L, rounded down
+fn next_sqrt_price_from_input(sqrt_price_x64: u128, liquidity: u128, amount_in: u128, a_to_b: bool) -> Option<u128> {
+    if amount_in == 0 {
+        return Some(sqrt_price_x64);
+    }
+    let sqrt_price = U256::from(sqrt_price_x64);
+    let liquidity_x64 = U256::from(liquidity) << 64;
+
+    let next = if a_to_b {
+        let denominator = liquidity_x64.checked_add(U256::from(amount_in).checked_mul(sqrt_price)?)?;
+        match liquidity_x64.checked_mul(sqrt_price) {
+            Some(numerator) => div_round(numerator, denominator, true),
+            // Same value, divided through by sqrt first
+            None => div_round(liquidity_x64, liquidity_x64 / sqrt_price + U256::from(amount_in), true),
+        }
+    } else {
+        sqrt_price.checked_add((U256::from(amount_in) << 64) / U256::from(liquidity))?
+    };
+    math::u256_to_u128(next)
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SwapStep {
+    pub sqrt_price_next_x64: u128,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee_amount: u64,
+}
+
+// One exact-input step towards sqrt_price_target within a single liquidity range.
+// Stops at the target if the input is enough to get there, otherwise wherever
+// the input runs out. The fee is taken from the input first, like calculate_amm_output.
+pub fn compute_swap_step(
+    sqrt_price_x64: u128,
+    sqrt_price_target_x64: u128,
+    liquidity: u128,
+    amount_remaining: u64,
+    fee_bps: u16,
+) -> Option<SwapStep> {
+    let a_to_b = sqrt_price_x64 >= sqrt_price_target_x64;
+    let fee_multiplier = FEE_DENOMINATOR.checked_sub(fee_bps as u64)?;
+    let amount_remaining_less_fee =
+        math::mul_div_floor(amount_remaining as u128, fee_multiplier as u128, FEE_DENOMINATOR as u128)?;
+
+    let amount_in_to_target = if a_to_b {
+        amount_a_delta(sqrt_price_target_x64, sqrt_price_x64, liquidity, true)
+    } else {
+        amount_b_delta(sqrt_price_x64, sqrt_price_target_x64, liquidity, true)
+    };
+    // None means more than any u64 input could pay, so the target is out of reach
+    let reaches_target = amount_in_to_target.is_some_and(|amount| amount_remaining_less_fee >= amount);
+
+    let sqrt_price_next_x64 = if reaches_target {
+        sqrt_price_target_x64
+    } else {
+        next_sqrt_price_from_input(sqrt_price_x64, liquidity, amount_remaining_less_fee, a_to_b)?
+    };
+
+    let (amount_in, amount_out) = if a_to_b {
+        (
+            amount_a_delta(sqrt_price_next_x64, sqrt_price_x64, liquidity, true)?,
+            amount_b_delta(sqrt_price_next_x64, sqrt_price_x64, liquidity, false)?,
+        )
+    } else {
+        (
+            amount_b_delta(sqrt_price_x64, sqrt_price_next_x64, liquidity, true)?,
+            amount_a_delta(sqrt_price_x64, sqrt_price_next_x64, liquidity, false)?,
+        )
+    };
+    let amount_in = math::to_u64(amount_in)?;
+
+    // Short of the target the whole remainder is spent, whatever is not input is fee
+    let fee_amount = if reaches_target {
+        math::to_u64(math::mul_div_ceil(amount_in as u128, fee_bps as u128, fee_multiplier as u128)?)?
+    } else {
+        amount_remaining.checked_sub(amount_in)?
+    };
+
+    Some(SwapStep {
+        sqrt_price_next_x64,
+        amount_in,
+        amount_out: math::to_u64(amount_out)?,
+        fee_amount,
+    })
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SwapResult {
+    pub amount_out: u64,
+    pub fee_amount: u64,
+    pub protocol_fee: u64, // part of fee_amount
+}
+
+// Runs an exact-input swap against the pool, crossing ticks as needed. `tick_arrays`
+// must start with the array holding tick_current and continue in swap direction.
+// The whole input must be used before sqrt_price_limit_x64 is reached.
+// protocol_fee_share (basis points) of every step's fee goes to the protocol.
+pub fn swap<A: DerefMut<Target = TickArray>>(
+    pool: &mut ClmmPool,
+    tick_arrays: &mut [A],
+    a_to_b: bool,
+    amount_in: u64,
+    sqrt_price_limit_x64: u128,
+    protocol_fee_share: u16,
+) -> Result<SwapResult> {
+    if a_to_b {
+        require!(
+            sqrt_price_limit_x64 < pool.sqrt_price_x64 && sqrt_price_limit_x64 > MIN_SQRT_PRICE_X64,
+            ErrorCode::InvalidSqrtPrice
+        );
+    } else {
+        require!(
+            sqrt_price_limit_x64 > pool.sqrt_price_x64 && sqrt_price_limit_x64 < MAX_SQRT_PRICE_X64,
+            ErrorCode::InvalidSqrtPrice
+        );
+    }
+
+    // Arrays must be adjacent, in the order the price will move through them
+    let span = pool.tick_spacing as i32 * TICK_ARRAY_SIZE as i32;
+    let mut expected_start = tick_array_start(pool.tick_current, pool.tick_spacing);
+    for tick_array in tick_arrays.iter() {
+        require!(tick_array.start_tick_index == expected_start, ErrorCode::InvalidTickArray);
+        expected_start += if a_to_b { -span } else { span };
+    }
+
+    let mut amount_remaining = amount_in;
+    let mut result = SwapResult::default();
+    let mut array_index = 0;
+    while amount_remaining > 0 && pool.sqrt_price_x64 != sqrt_price_limit_x64 {
+        let tick_array = tick_arrays.get_mut(array_index).ok_or(ErrorCode::InvalidTickArray)?;
+        let Some((next_tick, initialized)) = tick_array.next_tick(pool.tick_current, pool.tick_spacing, a_to_b) else {
+            array_index += 1;
+            continue;
+        };
+
+        let next_tick = next_tick.clamp(MIN_TICK, MAX_TICK);
+        let sqrt_price_next_tick = sqrt_price_at_tick(next_tick)?;
+        let sqrt_price_target = if a_to_b {
+            sqrt_price_next_tick.max(sqrt_price_limit_x64)
+        } else {
+            sqrt_price_next_tick.min(sqrt_price_limit_x64)
+        };
+
+        let step = compute_swap_step(pool.sqrt_price_x64, sqrt_price_target, pool.liquidity, amount_remaining, pool.fee)
+            .ok_or(ErrorCode::MathOverflow)?;
+        amount_remaining = amount_remaining
+            .checked_sub(step.amount_in)
+            .and_then(|amount| amount.checked_sub(step.fee_amount))
+            .ok_or(ErrorCode::MathOverflow)?;
+        result.amount_out = result.amount_out.checked_add(step.amount_out).ok_or(ErrorCode::MathOverflow)?;
+        result.fee_amount = result.fee_amount.checked_add(step.fee_amount).ok_or(ErrorCode::MathOverflow)?;
+
+        // The protocol's share rounds down in favour of the LPs, like calculate_protocol_share
+        let protocol_fee = math::mul_div_floor(step.fee_amount as u128, protocol_fee_share as u128, FEE_DENOMINATOR as u128)
+            .and_then(math::to_u64)
+            .ok_or(ErrorCode::MathOverflow)?;
+        result.protocol_fee = result.protocol_fee.checked_add(protocol_fee).ok_or(ErrorCode::MathOverflow)?;
+        let lp_fee = step.fee_amount - protocol_fee;
+
+        // The rest is shared by the liquidity active during this step
+        if let Some(growth) = ((lp_fee as u128) << 64).checked_div(pool.liquidity) {
+            if a_to_b {
+                pool.fee_growth_global_a_x64 = pool.fee_growth_global_a_x64.wrapping_add(growth);
+            } else {
+                pool.fee_growth_global_b_x64 = pool.fee_growth_global_b_x64.wrapping_add(growth);
+            }
+        }
+
+        if step.sqrt_price_next_x64 == sqrt_price_next_tick {
+            if initialized {
+                let tick = tick_array.tick_mut(next_tick, pool.tick_spacing)?;
+                tick.fee_growth_outside_a_x64 = pool.fee_growth_global_a_x64.wrapping_sub(tick.fee_growth_outside_a_x64);
+                tick.fee_growth_outside_b_x64 = pool.fee_growth_global_b_x64.wrapping_sub(tick.fee_growth_outside_b_x64);
+                let liquidity_net = if a_to_b { -tick.liquidity_net } else { tick.liquidity_net };
+                pool.liquidity = add_delta(pool.liquidity, liquidity_net).ok_or(ErrorCode::MathOverflow)?;
+            }
+            pool.tick_current = if a_to_b { next_tick - 1 } else { next_tick };
+        } else if step.sqrt_price_next_x64 != pool.sqrt_price_x64 {
+            pool.tick_current = tick_at_sqrt_price(step.sqrt_price_next_x64)?;
+        }
+        pool.sqrt_price_x64 = step.sqrt_price_next_x64;
+    }
+
+    // The input is already in the vault, stopping short would keep the rest
+    require!(amount_remaining == 0, ErrorCode::SlippageExceeded);
+
+    if a_to_b {
+        pool.protocol_fees_a = pool.protocol_fees_a.checked_add(result.protocol_fee).ok_or(ErrorCode::MathOverflow)?;
+    } else {
+        pool.protocol_fees_b = pool.protocol_fees_b.checked_add(result.protocol_fee).ok_or(ErrorCode::MathOverflow)?;
+    }
+    Ok(result)
+}
+
+// Fee growth inside [tick_lower, tick_upper), Uniswap v3's below/above split
+pub fn fee_growth_inside(pool: &ClmmPool, position: &Position, lower: &Tick, upper: &Tick) -> (u128, u128) {
+    let inside = |global: u128, lower_outside: u128, upper_outside: u128| {
+        let below = if pool.tick_current >= position.tick_lower { lower_outside } else { global.wrapping_sub(lower_outside) };
+        let above = if pool.tick_current < position.tick_upper { upper_outside } else { global.wrapping_sub(upper_outside) };
+        global.wrapping_sub(below).wrapping_sub(above)
+    };
+    (
+        inside(pool.fee_growth_global_a_x64, lower.fee_growth_outside_a_x64, upper.fee_growth_outside_a_x64),
+        inside(pool.fee_growth_global_b_x64, lower.fee_growth_outside_b_x64, upper.fee_growth_outside_b_x64),
+    )
+}
+
+fn update_tick(pool: &ClmmPool, tick: &mut Tick, tick_index: i32, liquidity_delta: i128, is_upper: bool) -> Result<()> {
+    if tick.liquidity_gross == 0 {
+        // By convention all growth so far happened below the tick
+        if tick_index <= pool.tick_current {
+            tick.fee_growth_outside_a_x64 = pool.fee_growth_global_a_x64;
+            tick.fee_growth_outside_b_x64 = pool.fee_growth_global_b_x64;
+        }
+        tick.initialized = 1;
+    }
+
+    tick.liquidity_gross = add_delta(tick.liquidity_gross, liquidity_delta).ok_or(ErrorCode::MathOverflow)?;
+    let net_delta = if is_upper { liquidity_delta.checked_neg() } else { Some(liquidity_delta) };
+    tick.liquidity_net = net_delta
+        .and_then(|delta| tick.liquidity_net.checked_add(delta))
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(())
+}
+
+// Adds (or with a negative delta removes) liquidity from a position, accruing
+// its fees first. Returns the token amounts the change is worth, rounded up when
+// adding and down when removing. A zero delta only accrues fees.
+pub fn modify_position(
+    pool: &mut ClmmPool,
+    position: &mut Position,
+    lower: &mut Tick,
+    upper: &mut Tick,
+    liquidity_delta: i128,
+) -> Result<(u64, u64)> {
+    if liquidity_delta != 0 {
+        update_tick(pool, lower, position.tick_lower, liquidity_delta, false)?;
+        update_tick(pool, upper, position.tick_upper, liquidity_delta, true)?;
+    }
+
+    let (inside_a, inside_b) = fee_growth_inside(pool, position, lower, upper);
+    let owed = |inside: u128, last: u128| -> Result<u64> {
+        let owed = (U256::from(inside.wrapping_sub(last)) * U256::from(position.liquidity)) >> 64;
+        Ok(math::u256_to_u128(owed).and_then(math::to_u64).ok_or(ErrorCode::MathOverflow)?)
+    };
+    let owed_a = owed(inside_a, position.fee_growth_inside_a_last_x64)?;
+    let owed_b = owed(inside_b, position.fee_growth_inside_b_last_x64)?;
+    position.fees_owed_a = position.fees_owed_a.checked_add(owed_a).ok_or(ErrorCode::MathOverflow)?;
+    position.fees_owed_b = position.fees_owed_b.checked_add(owed_b).ok_or(ErrorCode::MathOverflow)?;
+    position.fee_growth_inside_a_last_x64 = inside_a;
+    position.fee_growth_inside_b_last_x64 = inside_b;
+    position.liquidity = add_delta(position.liquidity, liquidity_delta).ok_or(ErrorCode::InsufficientBalance)?;
+
+    // Ticks no position references any more are cleared, so swaps skip them
+    for tick in [lower, upper] {
+        if tick.liquidity_gross == 0 {
+            *tick = Tick::default();
+        }
+    }
+
+    let in_range = pool.tick_current >= position.tick_lower && pool.tick_current < position.tick_upper;
+    if in_range {
+        pool.liquidity = add_delta(pool.liquidity, liquidity_delta).ok_or(ErrorCode::MathOverflow)?;
+    }
+
+    let round_up = liquidity_delta > 0;
+    let liquidity = liquidity_delta.unsigned_abs();
+    let sqrt_lower = sqrt_price_at_tick(position.tick_lower)?;
+    let sqrt_upper = sqrt_price_at_tick(position.tick_upper)?;
+    let (amount_a, amount_b) = if pool.tick_current < position.tick_lower {
+        (amount_a_delta(sqrt_lower, sqrt_upper, liquidity, round_up), Some(0))
+    } else if in_range {
+        (
+            amount_a_delta(pool.sqrt_price_x64, sqrt_upper, liquidity, round_up),
+            amount_b_delta(sqrt_lower, pool.sqrt_price_x64, liquidity, round_up),
+        )
+    } else {
+        (Some(0), amount_b_delta(sqrt_lower, sqrt_upper, liquidity, round_up))
+    };
+
+    let to_u64 = |amount: Option<u128>| amount.and_then(math::to_u64).ok_or(ErrorCode::MathOverflow);
+    Ok((to_u64(amount_a)?, to_u64(amount_b)?))
+}
+
+// Swap tick arrays from remaining accounts, checked to belong to `pool`. Their
+// order is checked by swap().
+pub fn load_tick_arrays<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    pool: &Pubkey,
+) -> Result<Vec<AccountLoader<'info, TickArray>>> {
+    require!(
+        !accounts.is_empty() && accounts.len() <= MAX_SWAP_TICK_ARRAYS,
+        ErrorCode::InvalidTickArray
+    );
+
+    let mut tick_arrays = Vec::with_capacity(accounts.len());
+    for account in accounts {
+        let tick_array = AccountLoader::<TickArray>::try_from(account)?;
+        require_keys_eq!(tick_array.load()?.pool, *pool, ErrorCode::InvalidTickArray);
+        tick_arrays.push(tick_array);
+    }
+    Ok(tick_arrays)
+}
+
+// Runs `f` on a position's (tick_lower, tick_upper), which may share a tick array
+pub fn with_position_ticks<R>(
+    lower_array: &AccountLoader<TickArray>,
+    upper_array: &AccountLoader<TickArray>,
+    pool: &Pubkey,
+    (tick_lower, tick_upper): (i32, i32),
+    tick_spacing: u16,
+    f: impl FnOnce(&mut Tick, &mut Tick) -> Result<R>,
+) -> Result<R> {
+    require_keys_eq!(lower_array.load()?.pool, *pool, ErrorCode::InvalidTickArray);
+    require_keys_eq!(upper_array.load()?.pool, *pool, ErrorCode::InvalidTickArray);
+
+    if lower_array.key() == upper_array.key() {
+        let mut tick_array = lower_array.load_mut()?;
+        let lower = tick_array.offset(tick_lower, tick_spacing)?;
+        let upper = tick_array.offset(tick_upper, tick_spacing)?;
+        // tick_lower < tick_upper, so the lower slot comes first
+        let (left, right) = tick_array.ticks.split_at_mut(upper);
+        f(&mut left[lower], &mut right[0])
+    } else {
+        let mut lower = lower_array.load_mut()?;
+        let mut upper = upper_array.load_mut()?;
+        f(
+            lower.tick_mut(tick_lower, tick_spacing)?,
+            upper.tick_mut(tick_upper, tick_spacing)?,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytemuck::Zeroable;
+    use proptest::prelude::*;
+
+    const ONE: u128 = 1 << 64;
+    const SPACING: u16 = 10;
+
+    fn pool_at_tick(tick: i32) -> ClmmPool {
+        ClmmPool {
+            tick_spacing: SPACING,
+            fee: 30,
+            sqrt_price_x64: sqrt_price_at_tick(tick).unwrap(),
+            tick_current: tick,
+            ..Default::default()
+        }
+    }
+
+    fn tick_array(start_tick_index: i32) -> Box<TickArray> {
+        let mut tick_array = Box::new(TickArray::zeroed());
+        tick_array.start_tick_index = start_tick_index;
+        tick_array
+    }
+
+    // Adds liquidity to a position whose ticks both live in `tick_array`
+    fn add_position(pool: &mut ClmmPool, tick_array: &mut TickArray, position: &mut Position, liquidity: i128) -> (u64, u64) {
+        let lower = tick_array.offset(position.tick_lower, SPACING).unwrap();
+        let upper = tick_array.offset(position.tick_upper, SPACING).unwrap();
+        let (left, right) = tick_array.ticks.split_at_mut(upper);
+        modify_position(pool, position, &mut left[lower], &mut right[0], liquidity).unwrap()
+    }
+
+    #[test]
+    fn sqrt_price_at_tick_bounds() {
+        assert_eq!(sqrt_price_at_tick(0).unwrap(), ONE);
+        assert_eq!(sqrt_price_at_tick(MIN_TICK).unwrap(), MIN_SQRT_PRICE_X64);
+        assert_eq!(sqrt_price_at_tick(MAX_TICK).unwrap(), MAX_SQRT_PRICE_X64);
+        assert!(sqrt_price_at_tick(MAX_TICK + 1).is_err());
+        assert!(sqrt_price_at_tick(MIN_TICK - 1).is_err());
+
+        // sqrt(1.0001^1000) = 1.05126846837676...
+        let price = sqrt_price_at_tick(1_000).unwrap() as f64 / ONE as f64;
+        assert!((price - 1.0512684683767).abs() < 1e-12);
+    }
+
+    #[test]
+    fn tick_array_start_rounds_down() {
+        let span = SPACING as i32 * TICK_ARRAY_SIZE as i32;
+        assert_eq!(tick_array_start(0, SPACING), 0);
+        assert_eq!(tick_array_start(span - 1, SPACING), 0);
+        assert_eq!(tick_array_start(-1, SPACING), -span);
+        assert_eq!(tick_array_start(-span, SPACING), -span);
+    }
+
+    #[test]
+    fn next_tick_searches_in_swap_direction() {
+        let mut tick_array = tick_array(0);
+        tick_array.tick_mut(100, SPACING).unwrap().initialized = 1;
+
+        assert_eq!(tick_array.next_tick(150, SPACING, true), Some((100, true)));
+        assert_eq!(tick_array.next_tick(100, SPACING, true), Some((100, true)));
+        assert_eq!(tick_array.next_tick(99, SPACING, true), Some((0, false)));
+        assert_eq!(tick_array.next_tick(-1, SPACING, true), None);
+
+        assert_eq!(tick_array.next_tick(99, SPACING, false), Some((100, true)));
+        assert_eq!(tick_array.next_tick(100, SPACING, false), Some((590, false)));
+        assert_eq!(tick_array.next_tick(590, SPACING, false), None);
+        assert_eq!(tick_array.next_tick(-5, SPACING, false), Some((100, true)));
+    }
+
+    #[test]
+    fn full_step_amounts_match_liquidity_math() {
+        // 1_000_000 liquidity between price 1 and tick 100, all of it B
+        let lower = ONE;
+        let upper = sqrt_price_at_tick(100).unwrap();
+        let amount_b = amount_b_delta(lower, upper, 1_000_000, true).unwrap();
+        assert_eq!(amount_b, 5_013); // 1e6 * (1.0001^50 - 1) = 5_012.3, rounded up
+
+        let step = compute_swap_step(upper, lower, 1_000_000, u64::MAX, 0).unwrap();
+        assert_eq!(step.sqrt_price_next_x64, lower);
+        assert_eq!(step.amount_out, 5_012);
+        assert_eq!(step.fee_amount, 0);
+    }
+
+    #[test]
+    fn swap_crosses_out_of_a_position() {
+        let mut pool = pool_at_tick(0);
+        let mut tick_arrays = vec![tick_array(0), tick_array(-600)];
+        let mut position = Position { tick_lower: -100, tick_upper: 100, ..Default::default() };
+
+        // The lower tick lives in the second array, so modify each tick where it is
+        {
+            let (first, second) = tick_arrays.split_at_mut(1);
+            let upper = first[0].tick_mut(100, SPACING).unwrap();
+            let lower = second[0].tick_mut(-100, SPACING).unwrap();
+            let (amount_a, amount_b) = modify_position(&mut pool, &mut position, lower, upper, 1_000_000).unwrap();
+            // 1e6 * (1 - 1.0001^-50) = 4_987.5 of each, rounded up
+            assert_eq!((amount_a, amount_b), (4_988, 4_988));
+        }
+        assert_eq!(pool.liquidity, 1_000_000);
+
+        // Sell A until the price leaves the range, the rest of the input is unusable
+        let limit = sqrt_price_at_tick(-200).unwrap();
+        let err = swap(&mut pool, &mut tick_arrays, true, 1_000_000, limit, 0).unwrap_err();
+        assert_eq!(err, error!(ErrorCode::SlippageExceeded));
+
+        // A swap that fits stays in range and pays the fee to the position
+        let mut pool = pool_at_tick(0);
+        let mut tick_arrays = vec![tick_array(0), tick_array(-600)];
+        let mut position = Position { tick_lower: -100, tick_upper: 100, ..Default::default() };
+        {
+            let (first, second) = tick_arrays.split_at_mut(1);
+            let upper = first[0].tick_mut(100, SPACING).unwrap();
+            let lower = second[0].tick_mut(-100, SPACING).unwrap();
+            modify_position(&mut pool, &mut position, lower, upper, 1_000_000).unwrap();
+        }
+        let result = swap(&mut pool, &mut tick_arrays, true, 4_000, limit, 0).unwrap();
+        assert_eq!(result.fee_amount, 12);
+        assert!(result.amount_out < 4_000 && result.amount_out > 3_950, "{result:?}");
+        assert!(pool.tick_current < 0 && pool.tick_current > -100);
+
+        let (first, second) = tick_arrays.split_at_mut(1);
+        let upper = first[0].tick_mut(100, SPACING).unwrap();
+        let lower = second[0].tick_mut(-100, SPACING).unwrap();
+        modify_position(&mut pool, &mut position, lower, upper, 0).unwrap();
+        // The position owns all the liquidity, so it earns the whole fee less rounding
+        assert!(position.fees_owed_a == 11 || position.fees_owed_a == 12);
+        assert_eq!((result.protocol_fee, pool.protocol_fees_a), (0, 0));
+    }
+
+    #[test]
+    fn swap_sets_aside_the_protocol_share() {
+        let mut pool = pool_at_tick(0);
+        let mut tick_arrays = vec![tick_array(0), tick_array(-600)];
+        let mut position = Position { tick_lower: -100, tick_upper: 100, ..Default::default() };
+        {
+            let (first, second) = tick_arrays.split_at_mut(1);
+            let upper = first[0].tick_mut(100, SPACING).unwrap();
+            let lower = second[0].tick_mut(-100, SPACING).unwrap();
+            modify_position(&mut pool, &mut position, lower, upper, 1_000_000).unwrap();
+        }
+
+        // Same trade as above, a quarter of the 12 fee goes to the protocol
+        let limit = sqrt_price_at_tick(-200).unwrap();
+        let result = swap(&mut pool, &mut tick_arrays, true, 4_000, limit, 2_500).unwrap();
+        assert_eq!((result.fee_amount, result.protocol_fee), (12, 3));
+        assert_eq!((pool.protocol_fees_a, pool.protocol_fees_b), (3, 0));
+
+        let (first, second) = tick_arrays.split_at_mut(1);
+        let upper = first[0].tick_mut(100, SPACING).unwrap();
+        let lower = second[0].tick_mut(-100, SPACING).unwrap();
+        modify_position(&mut pool, &mut position, lower, upper, 0).unwrap();
+        assert!(position.fees_owed_a == 8 || position.fees_owed_a == 9);
+    }
+
+    #[test]
+    fn swap_crossing_a_tick_switches_liquidity() {
+        let mut pool = pool_at_tick(5);
+        let mut tick_arrays = vec![tick_array(0)];
+        let mut wide = Position { tick_lower: 0, tick_upper: 500, ..Default::default() };
+        let mut narrow = Position { tick_lower: 0, tick_upper: 100, ..Default::default() };
+        add_position(&mut pool, &mut tick_arrays[0], &mut wide, 1_000_000);
+        add_position(&mut pool, &mut tick_arrays[0], &mut narrow, 3_000_000);
+        assert_eq!(pool.liquidity, 4_000_000);
+
+        // Buy A with B far enough to cross tick 100
+        let limit = sqrt_price_at_tick(400).unwrap();
+        swap(&mut pool, &mut tick_arrays, false, 30_000, limit, 0).unwrap();
+        assert!(pool.tick_current >= 100);
+        assert_eq!(pool.liquidity, 1_000_000);
+
+        // Back down across it again
+        let limit = sqrt_price_at_tick(1).unwrap();
+        swap(&mut pool, &mut tick_arrays, true, 30_000, limit, 0).unwrap();
+        assert!(pool.tick_current < 100);
+        assert_eq!(pool.liquidity, 4_000_000);
+    }
+
+    #[test]
+    fn removing_all_liquidity_clears_ticks() {
+        let mut pool = pool_at_tick(0);
+        let mut tick_array = tick_array(-300);
+        let mut position = Position { tick_lower: -100, tick_upper: 100, ..Default::default() };
+
+        let (in_a, in_b) = add_position(&mut pool, &mut tick_array, &mut position, 1_000_000);
+        let (out_a, out_b) = add_position(&mut pool, &mut tick_array, &mut position, -1_000_000);
+        // Rounding favours the pool both ways
+        assert!(out_a <= in_a && in_a - out_a <= 1);
+        assert!(out_b <= in_b && in_b - out_b <= 1);
+        assert_eq!(pool.liquidity, 0);
+        assert_eq!(*tick_array.tick_mut(-100, SPACING).unwrap(), Tick::default());
+        assert_eq!(*tick_array.tick_mut(100, SPACING).unwrap(), Tick::default());
+    }
+
+    proptest! {
+        #[test]
+        fn tick_at_sqrt_price_inverts_sqrt_price_at_tick(tick in MIN_TICK..MAX_TICK) {
+            let sqrt_price = sqrt_price_at_tick(tick).unwrap();
+            prop_assert_eq!(tick_at_sqrt_price(sqrt_price).unwrap(), tick);
+            prop_assert!(sqrt_price < sqrt_price_at_tick(tick + 1).unwrap());
+            if sqrt_price > MIN_SQRT_PRICE_X64 {
+                prop_assert_eq!(tick_at_sqrt_price(sqrt_price - 1).unwrap(), tick - 1);
+            }
+        }
+
+        #[test]
+        fn swap_step_never_overpays(
+            tick in -10_000i32..10_000,
+            target_offset in -5_000i32..5_000,
+            liquidity in 1u128..1_000_000_000_000_000,
+            amount in 1u64..1_000_000_000_000,
+            fee in 0u16..1_000,
+        ) {
+            let sqrt_price = sqrt_price_at_tick(tick).unwrap();
+            let target = sqrt_price_at_tick(tick + target_offset).unwrap();
+            let step = compute_swap_step(sqrt_price, target, liquidity, amount, fee).unwrap();
+            prop_assert!(step.amount_in as u128 + step.fee_amount as u128 <= amount as u128);
+
+            // The output is worth no more than the input at either end of the move
+            let a_to_b = sqrt_price >= target;
+            let (low, high) = if a_to_b { (step.sqrt_price_next_x64, sqrt_price) } else { (sqrt_price, step.sqrt_price_next_x64) };
+            prop_assert!(low >= target.min(sqrt_price) && high <= target.max(sqrt_price));
+            if a_to_b {
+                prop_assert!(step.amount_out as u128 <= amount_b_delta(low, high, liquidity, false).unwrap());
+            } else {
+                prop_assert!(step.amount_out as u128 <= amount_a_delta(low, high, liquidity, false).unwrap());
+            }
+        }
+    }
+}
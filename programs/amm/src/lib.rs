@@ -1,49 +1,278 @@
-use std::ops::Mul;
+// `#[program]` expands to `AccountInfo::realloc`, deprecated in the pinned solana-program
+#![allow(deprecated)]
 use anchor_lang::prelude::*;
 use anchor_spl::{
     metadata::{
         create_metadata_accounts_v3, mpl_token_metadata::types::DataV2, CreateMetadataAccountsV3,
         Metadata,
     },
-    associated_token::AssociatedToken,
-    token::{Mint, MintTo, Token, TokenAccount, Burn, Transfer}
+    associated_token::{self, AssociatedToken},
+    token::{spl_token::instruction::AuthorityType, MintTo, SetAuthority, Token, Burn},
+    token_interface::{Mint, TokenAccount, TokenInterface},
 };
 
+pub mod clmm;
+pub mod math;
+pub mod multi;
+pub mod oracle;
+pub mod route;
+pub mod stable;
+pub mod token_utils;
+pub mod weighted;
+
+use clmm::{ClmmPool, Position, TickArray};
+use multi::{MultiPool, PoolToken};
+use oracle::{CumulativePrices, Observations};
+
 declare_id!("HvM5J3JTPXViGexcxkDHKtBXvv8vwLMcZSrpqKimBFHr");
 
+// Fees are expressed in basis points: 30 = 0.30%, 10_000 = 100%
+pub const FEE_DENOMINATOR: u64 = 10_000;
+
+// Highest swap fee the admin can configure (10%). A fee at or near 100% would take
+// every swap input and pay nothing out.
+pub const MAX_FEE: u16 = 1_000;
+
+// LP permanently locked on pool creation (Uniswap v2's MINIMUM_LIQUIDITY), so the
+// LP share price cannot be inflated by creating a pool with dust
+pub const MINIMUM_LIQUIDITY: u64 = 1_000;
+
+// Bumped whenever the Pool layout changes, so old accounts can be migrated
+pub const POOL_VERSION: u8 = 5;
+
+// Pool::status bits, a set bit disables that action for the pool
+pub const POOL_SWAPS_DISABLED: u8 = 1 << 0;
+pub const POOL_DEPOSITS_DISABLED: u8 = 1 << 1;
+pub const POOL_WITHDRAWALS_DISABLED: u8 = 1 << 2;
+pub const POOL_STATUS_MASK: u8 = POOL_SWAPS_DISABLED | POOL_DEPOSITS_DISABLED | POOL_WITHDRAWALS_DISABLED;
+
 #[program]
 pub mod amm {
-    use anchor_lang::solana_program::native_token::LAMPORTS_PER_SOL;
-    use anchor_spl::token;
     use super::*;
 
-    pub fn initialize(ctx: Context<Initialize>, fee:u8) -> Result<()> {
+    pub fn initialize(ctx: Context<Initialize>, fee:u16, protocol_fee_share:u16) -> Result<()> {
+        check_fees(fee, protocol_fee_share)?;
+
         let amm_info = &mut ctx.accounts.amm;
         amm_info.user = ctx.accounts.signer.key();
         amm_info.fee = fee;
+        amm_info.protocol_fee_share = protocol_fee_share;
+        amm_info.fee_recipient = ctx.accounts.signer.key();
+        amm_info.guardian = ctx.accounts.signer.key();
+
+        emit!(AmmInitialized {
+            admin: amm_info.user,
+            fee,
+            protocol_fee_share,
+        });
+        Ok(())
+    }
+
+    pub fn set_fee(ctx: Context<UpdateAmm>, fee:u16, protocol_fee_share:u16) -> Result<()> {
+        check_fees(fee, protocol_fee_share)?;
+
+        let amm_info = &mut ctx.accounts.amm;
+        emit!(FeeUpdated {
+            old_fee: amm_info.fee,
+            new_fee: fee,
+            old_protocol_fee_share: amm_info.protocol_fee_share,
+            new_protocol_fee_share: protocol_fee_share,
+        });
+
+        amm_info.fee = fee;
+        amm_info.protocol_fee_share = protocol_fee_share;
+        Ok(())
+    }
+
+    // Step 1 of the admin handover, the new admin must call accept_admin
+    pub fn propose_admin(ctx: Context<UpdateAmm>, new_admin:Pubkey) -> Result<()> {
+        let amm_info = &mut ctx.accounts.amm;
+        amm_info.pending_user = new_admin;
+
+        emit!(AdminProposed {
+            admin: amm_info.user,
+            pending_admin: new_admin,
+        });
+        Ok(())
+    }
+
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let amm_info = &mut ctx.accounts.amm;
+        let old_admin = amm_info.user;
+        amm_info.user = ctx.accounts.pending_user.key();
+        amm_info.pending_user = Pubkey::default();
+
+        emit!(AdminTransferred {
+            old_admin,
+            new_admin: amm_info.user,
+        });
+        Ok(())
+    }
+
+    pub fn set_fee_recipient(ctx: Context<UpdateAmm>, fee_recipient:Pubkey) -> Result<()> {
+        let amm_info = &mut ctx.accounts.amm;
+        emit!(FeeRecipientUpdated {
+            old_fee_recipient: amm_info.fee_recipient,
+            new_fee_recipient: fee_recipient,
+        });
+
+        amm_info.fee_recipient = fee_recipient;
+        Ok(())
+    }
+
+    pub fn set_guardian(ctx: Context<UpdateAmm>, guardian:Pubkey) -> Result<()> {
+        let amm_info = &mut ctx.accounts.amm;
+        emit!(GuardianUpdated {
+            old_guardian: amm_info.guardian,
+            new_guardian: guardian,
+        });
+
+        amm_info.guardian = guardian;
+        Ok(())
+    }
+
+    // Global circuit breaker: while paused the program is withdraw-only.
+    // The guardian can only pause, unpausing needs the admin.
+    pub fn set_paused(ctx: Context<SetPaused>, paused:bool) -> Result<()> {
+        let authority = ctx.accounts.authority.key();
+        let amm_info = &mut ctx.accounts.amm;
+        let is_admin = authority == amm_info.user;
+        let is_guardian = authority == amm_info.guardian;
+        require!(is_admin || (is_guardian && paused), ErrorCode::Unauthorized);
+
+        amm_info.paused = paused;
+
+        emit!(PauseUpdated {
+            authority,
+            paused,
+        });
+        Ok(())
+    }
+
+    // Per-pool switches, see the POOL_*_DISABLED bits.
+    // The guardian can only disable swaps or deposits, so LPs can always exit
+    // unless the admin explicitly disables withdrawals too.
+    pub fn set_pool_status(ctx: Context<SetPoolStatus>, status:u8) -> Result<()> {
+        let authority = ctx.accounts.authority.key();
+        let pool_info = &mut ctx.accounts.pool;
+        let old_status = pool_info.status;
+        check_pool_status_change(&ctx.accounts.amm, &authority, old_status, status)?;
+
+        pool_info.status = status;
+
+        emit!(PoolStatusUpdated {
+            pool: pool_info.key(),
+            authority,
+            old_status,
+            new_status: status,
+        });
+        Ok(())
+    }
+
+    // Moves a StableSwap pool's amplification linearly from its current value to
+    // target_amp, reached at ramp_stop. Like Curve, a ramp takes at least
+    // MIN_RAMP_DURATION and changes A by at most MAX_AMP_CHANGE x, so LPs can exit
+    // before the curve shifts under them.
+    pub fn ramp_amp(ctx: Context<UpdatePoolCurve>, target_amp:u64, ramp_stop:i64) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let pool_info = &mut ctx.accounts.pool;
+        require!(pool_info.curve_type == CurveType::StableSwap, ErrorCode::InvalidCurveType);
+        require!((stable::MIN_AMP..=stable::MAX_AMP).contains(&target_amp), ErrorCode::InvalidAmp);
+        require!(now >= pool_info.amp_ramp_stop, ErrorCode::InvalidAmpRamp);
+        require!(ramp_stop >= now.checked_add(stable::MIN_RAMP_DURATION).ok_or(ErrorCode::MathOverflow)?, ErrorCode::InvalidAmpRamp);
+
+        let current_amp = pool_info.amp_at(now);
+        let max_amp = current_amp.checked_mul(stable::MAX_AMP_CHANGE).ok_or(ErrorCode::MathOverflow)?;
+        require!(target_amp <= max_amp && target_amp.checked_mul(stable::MAX_AMP_CHANGE).ok_or(ErrorCode::MathOverflow)? >= current_amp, ErrorCode::InvalidAmpRamp);
+
+        pool_info.amp_initial = current_amp;
+        pool_info.amp_target = target_amp;
+        pool_info.amp_ramp_start = now;
+        pool_info.amp_ramp_stop = ramp_stop;
+
+        emit!(AmpRampStarted {
+            pool: pool_info.key(),
+            amp_initial: current_amp,
+            amp_target: target_amp,
+            ramp_start: now,
+            ramp_stop,
+        });
+        Ok(())
+    }
+
+    // Freezes A at its current point on the ramp
+    pub fn stop_ramp_amp(ctx: Context<UpdatePoolCurve>) -> Result<()> {
+        let now = Clock::get()?.unix_timestamp;
+        let pool_info = &mut ctx.accounts.pool;
+        require!(pool_info.curve_type == CurveType::StableSwap, ErrorCode::InvalidCurveType);
+
+        let current_amp = pool_info.amp_at(now);
+        pool_info.amp_initial = current_amp;
+        pool_info.amp_target = current_amp;
+        pool_info.amp_ramp_start = now;
+        pool_info.amp_ramp_stop = now;
+
+        emit!(AmpRampStopped {
+            pool: pool_info.key(),
+            amp: current_amp,
+        });
         Ok(())
     }
     
-    pub fn create_pool(ctx:Context<CreatePool>, token_amount_a:u64, token_amount_b:u64)-> Result<()>{
+    // `amp` is the StableSwap amplification and must be 0 for constant product pools
+    pub fn create_pool(ctx:Context<CreatePool>, token_amount_a:u64, token_amount_b:u64, curve_type:CurveType, amp:u64, weight_a:u16)-> Result<()>{
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        // Only weighted pools have weights, mint_b gets what mint_a does not
+        require!((curve_type == CurveType::Weighted) == (weight_a != 0), ErrorCode::InvalidWeight);
+        let weight_b = if weight_a == 0 { 0 } else { (FEE_DENOMINATOR as u16).saturating_sub(weight_a) };
+        match curve_type {
+            CurveType::ConstantProduct => require!(amp == 0, ErrorCode::InvalidAmp),
+            CurveType::StableSwap => {
+                require!((stable::MIN_AMP..=stable::MAX_AMP).contains(&amp), ErrorCode::InvalidAmp);
+                // The invariant compares raw amounts, so one unit must be worth one unit
+                require!(ctx.accounts.mint_a.decimals == ctx.accounts.mint_b.decimals, ErrorCode::DecimalsMismatch);
+            }
+            CurveType::Weighted => {
+                require!(amp == 0, ErrorCode::InvalidAmp);
+                require!(weight_a >= weighted::MIN_WEIGHT && weight_b >= weighted::MIN_WEIGHT, ErrorCode::InvalidWeight);
+            }
+        }
         require!(ctx.accounts.user_token_account_a.amount >= token_amount_a, ErrorCode::InsufficientBalance);
         require!(ctx.accounts.user_token_account_b.amount >= token_amount_b, ErrorCode::InsufficientBalance);
+        token_utils::validate_mint(&ctx.accounts.mint_a)?;
+        token_utils::validate_mint(&ctx.accounts.mint_b)?;
 
-        // 1. TRANSFER TOKENS INTO VAULTS
-        let cpi_ctx_mint_a = Transfer{
-            from: ctx.accounts.user_token_account_a.to_account_info(),
-            to: ctx.accounts.pool_token_account_a.to_account_info(),
-            authority: ctx.accounts.signer.to_account_info(),
-        };
-
-        let cpi_ctx_mint_b = Transfer{
-            from: ctx.accounts.user_token_account_b.to_account_info(),
-            to: ctx.accounts.pool_token_account_b.to_account_info(),
-            authority: ctx.accounts.signer.to_account_info(),
-        };
+        // 1. TRANSFER TOKENS INTO VAULTS (user signs)
+        // Fee-on-transfer mints deliver less than was sent, so LP is priced on the vault balances
+        let received_a = token_utils::deposit_to_vault(
+            &ctx.accounts.token_program_a,
+            &ctx.accounts.mint_a,
+            &ctx.accounts.user_token_account_a,
+            &mut ctx.accounts.pool_token_account_a,
+            &ctx.accounts.signer,
+            token_amount_a
+        )?;
+        let received_b = token_utils::deposit_to_vault(
+            &ctx.accounts.token_program_b,
+            &ctx.accounts.mint_b,
+            &ctx.accounts.user_token_account_b,
+            &mut ctx.accounts.pool_token_account_b,
+            &ctx.accounts.signer,
+            token_amount_b
+        )?;
 
-        // These are standard transfers, user signs
-        anchor_spl::token::transfer(CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_ctx_mint_a), token_amount_a)?;
-        anchor_spl::token::transfer(CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_ctx_mint_b), token_amount_b)?;
+        // Calculate Initial LP (sqrt(x*y), D for StableSwap, prod(x_i^w_i) for Weighted),
+        // MINIMUM_LIQUIDITY of it is locked forever
+        let initial_lp = match curve_type {
+            CurveType::ConstantProduct => {
+                let multiply = received_a as u128 * received_b as u128; // Use u128 to prevent overflow
+                math::to_u64(math::sqrt(multiply))
+            }
+            CurveType::StableSwap => stable::compute_d(amp, received_a, received_b).and_then(math::to_u64),
+            CurveType::Weighted => weighted::compute_invariant(&[received_a, received_b], &[weight_a, weight_b]),
+        }.ok_or(ErrorCode::MathOverflow)?;
+        require!(initial_lp > MINIMUM_LIQUIDITY, ErrorCode::InsufficientInitialLiquidity);
+        let lp_amount = initial_lp - MINIMUM_LIQUIDITY;
 
         // 2. PREPARE POOL SEEDS (The Pool is the Authority)
         let mint_a = ctx.accounts.mint_a.key();
@@ -92,8 +321,44 @@ pub mod amm {
         let pool_info = &mut ctx.accounts.pool;
         pool_info.mint_a = ctx.accounts.mint_a.key();
         pool_info.mint_b = ctx.accounts.mint_b.key();
+        pool_info.vault_a = ctx.accounts.pool_token_account_a.key();
+        pool_info.vault_b = ctx.accounts.pool_token_account_b.key();
+        pool_info.lp_mint = ctx.accounts.lp_mint.key();
+        pool_info.bump = pool_bump;
+        pool_info.lp_mint_bump = ctx.bumps.lp_mint;
+        pool_info.created_at_slot = Clock::get()?.slot;
+        pool_info.creator = ctx.accounts.signer.key();
+        pool_info.version = POOL_VERSION;
+        pool_info.reserve_a = received_a;
+        pool_info.reserve_b = received_b;
+        pool_info.last_update_timestamp = Clock::get()?.unix_timestamp;
+        pool_info.curve_type = curve_type;
+        pool_info.amp_initial = amp;
+        pool_info.amp_target = amp;
+        pool_info.weight_a = weight_a;
+        pool_info.weight_b = weight_b;
+
+        let mut observations = ctx.accounts.observations.load_init()?;
+        observations.pool = pool_info.key();
+        observations.record(pool_info);
+        drop(observations);
 
         // 4. MINT LP TOKENS (Pool signs as authority)
+        // The locked share goes to the pool's own LP account, which no instruction can spend
+        let cpi_accounts_lock = MintTo{
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            to: ctx.accounts.locked_lp_token_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+
+        let cpi_context_lock = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            cpi_accounts_lock,
+            signer_seeds
+        );
+
+        anchor_spl::token::mint_to(cpi_context_lock, MINIMUM_LIQUIDITY)?;
+
         let cpi_accounts_mint = MintTo{
             mint: ctx.accounts.lp_mint.to_account_info(),
             to: ctx.accounts.user_lp_token_account.to_account_info(),
@@ -106,37 +371,79 @@ pub mod amm {
             signer_seeds // Pool signs
         );
 
-        // Calculate Initial LP (sqrt(x*y))
-        let multiply = token_amount_a as u128 * token_amount_b as u128; // Use u128 to prevent overflow
-        let lp_amount = (multiply as f64).sqrt() as u64; 
-
         anchor_spl::token::mint_to(cpi_context_mint, lp_amount)?;
 
-        msg!("Pool created for {} and {}", ctx.accounts.mint_a.key(), ctx.accounts.mint_b.key());
+        emit!(PoolCreated {
+            pool: ctx.accounts.pool.key(),
+            creator: ctx.accounts.signer.key(),
+            mint_a: ctx.accounts.mint_a.key(),
+            mint_b: ctx.accounts.mint_b.key(),
+            lp_mint: ctx.accounts.lp_mint.key(),
+            amount_a: received_a,
+            amount_b: received_b,
+            lp_amount,
+            reserve_a: ctx.accounts.pool.reserve_a,
+            reserve_b: ctx.accounts.pool.reserve_b,
+            lp_supply: initial_lp,
+            curve_type,
+            amp,
+            weight_a,
+            weight_b,
+        });
         Ok(())
     }
 
-    pub fn add_liquidity(ctx:Context<AddLiquidity>, token_amount_a:u64, token_amount_b:u64) -> Result<()>{
-        // 1. Transfer Tokens
-        let cpi_ctx_mint_a = Transfer{
-            from: ctx.accounts.user_token_account_a.to_account_info(),
-            to: ctx.accounts.pool_token_account_a.to_account_info(),
-            authority: ctx.accounts.signer.to_account_info(),
-        };
+    pub fn add_liquidity(ctx:Context<AddLiquidity>, max_amount_a:u64, max_amount_b:u64, min_lp_out:u64, deadline:Option<i64>) -> Result<()>{
+        check_deadline(deadline)?;
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(ctx.accounts.pool.status & POOL_DEPOSITS_DISABLED == 0, ErrorCode::DepositsDisabled);
 
-        let cpi_ctx_mint_b = Transfer{
-            from: ctx.accounts.user_token_account_b.to_account_info(),
-            to: ctx.accounts.pool_token_account_b.to_account_info(),
-            authority: ctx.accounts.signer.to_account_info(),
-        };
+        // 1. Work out how much of each token the pool can actually take at the current ratio.
+        // A proportional deposit keeps every curve's prices, weighted pools included, so
+        // LP is pro rata for all of them and no fee is due.
+        let total_supply = ctx.accounts.lp_mint.supply;
+        let (reserve_a, reserve_b) = ctx.accounts.pool.reserves_for(&ctx.accounts.mint_a.key());
 
-        anchor_spl::token::transfer(CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_ctx_mint_a), token_amount_a)?;
-        anchor_spl::token::transfer(CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_ctx_mint_b), token_amount_b)?;
+        // The maximums are what the user sends, the ratio is matched on what the vaults receive
+        let (expected_a, expected_b, _) = calculate_liquidity_deposit(
+            token_utils::amount_received(&ctx.accounts.mint_a, max_amount_a)?,
+            token_utils::amount_received(&ctx.accounts.mint_b, max_amount_b)?,
+            reserve_a,
+            reserve_b,
+            total_supply
+        ).ok_or(ErrorCode::MathOverflow)?;
 
-        // 2. Mint LP Tokens
-        let mint_a = ctx.accounts.mint_a.key();
-        let mint_b = ctx.accounts.mint_b.key();
-        let pool_bump = ctx.bumps.pool;
+        // 2. Transfer Tokens (only the amounts used, the excess never leaves the user)
+        let token_amount_a = token_utils::amount_to_send(&ctx.accounts.mint_a, expected_a)?;
+        let token_amount_b = token_utils::amount_to_send(&ctx.accounts.mint_b, expected_b)?;
+        let received_a = token_utils::deposit_to_vault(
+            &ctx.accounts.token_program_a,
+            &ctx.accounts.mint_a,
+            &ctx.accounts.user_token_account_a,
+            &mut ctx.accounts.pool_token_account_a,
+            &ctx.accounts.signer,
+            token_amount_a
+        )?;
+        let received_b = token_utils::deposit_to_vault(
+            &ctx.accounts.token_program_b,
+            &ctx.accounts.mint_b,
+            &ctx.accounts.user_token_account_b,
+            &mut ctx.accounts.pool_token_account_b,
+            &ctx.accounts.signer,
+            token_amount_b
+        )?;
+
+        // LP is priced on the measured vault balance changes, not on the quote
+        let lp_amount = calculate_lp_for_deposit(received_a, received_b, reserve_a, reserve_b, total_supply)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(lp_amount > 0, ErrorCode::InsufficientLiquidityMinted);
+        require!(lp_amount >= min_lp_out, ErrorCode::SlippageExceeded);
+
+        // 3. Mint LP Tokens
+        // Signer seeds always use the canonical order stored in the pool
+        let mint_a = ctx.accounts.pool.mint_a;
+        let mint_b = ctx.accounts.pool.mint_b;
+        let pool_bump = ctx.accounts.pool.bump;
         
         let seeds = &[
             b"pool",
@@ -154,23 +461,196 @@ pub mod amm {
 
         let cpi_context = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts_mint, signer_seeds);
 
-        // WARNING: Simplified Math. 
-        // In a real AMM, for add_liquidity (after creation), you calculate ratio based on existing supply, not sqrt(a*b)
-        let multiply = token_amount_a as u128 * token_amount_b as u128;
-        let lp_amount = (multiply as f64).sqrt() as u64;
-
         anchor_spl::token::mint_to(cpi_context, lp_amount)?;
 
+        // 4. Book the deposit into the recorded reserves
+        // Price accumulators first, they integrate the reserves being replaced
+        oracle::update_with_clock(&mut ctx.accounts.pool, &ctx.accounts.observations)?;
+        let mint_a_key = ctx.accounts.mint_a.key();
+        ctx.accounts.pool.set_reserves_for(
+            &mint_a_key,
+            reserve_a.checked_add(received_a).ok_or(ErrorCode::MathOverflow)?,
+            reserve_b.checked_add(received_b).ok_or(ErrorCode::MathOverflow)?
+        );
+
+        let (amount_a, amount_b) = ctx.accounts.pool.in_pair_order(&mint_a_key, received_a, received_b);
+        emit!(LiquidityAdded {
+            pool: ctx.accounts.pool.key(),
+            user: ctx.accounts.signer.key(),
+            amount_a,
+            amount_b,
+            lp_amount,
+            reserve_a: ctx.accounts.pool.reserve_a,
+            reserve_b: ctx.accounts.pool.reserve_b,
+            lp_supply: total_supply.checked_add(lp_amount).ok_or(ErrorCode::MathOverflow)?,
+        });
         Ok(())
     } 
 
-    pub fn remove_liquidity(ctx:Context<RemoveLiquidity>, lp_amount:u64) -> Result<()>{
+    // Zap: deposit only one side of the pair. The optimal fraction is swapped through
+    // the pool first so the remainder and the swap output match the pool ratio.
+    pub fn add_liquidity_single(ctx:Context<AddLiquiditySingle>, amount_in:u64, min_lp_out:u64, deadline:Option<i64>) -> Result<()>{
+        check_deadline(deadline)?;
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(ctx.accounts.pool.status & POOL_DEPOSITS_DISABLED == 0, ErrorCode::DepositsDisabled);
+        require!(ctx.accounts.pool.status & POOL_SWAPS_DISABLED == 0, ErrorCode::SwapsDisabled);
+
+        // 1. Reserves in (input, other) order
+        let is_in_a = ctx.accounts.input_mint.key() == ctx.accounts.pool.mint_a;
+        let total_supply = ctx.accounts.lp_mint.supply;
+        let input_mint_key = ctx.accounts.input_mint.key();
+        let (reserve_in, reserve_other) = ctx.accounts.pool.reserves_for(&input_mint_key);
+
+        // 2. Price the deposit on what the vault receives after any transfer fee
+        let fee = ctx.accounts.amm.fee;
+        let protocol_fee_share = ctx.accounts.amm.protocol_fee_share;
+        let received_in = token_utils::amount_received(&ctx.accounts.input_mint, amount_in)?;
+        let (received_total, lp_amount, protocol_fee_in, protocol_fee_other) = match ctx.accounts.pool.curve_type {
+            CurveType::ConstantProduct => {
+                // Swap the optimal fraction of the input through the curve
+                let swap_amount = calculate_optimal_swap_amount(received_in, reserve_in, fee)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                let swapped_out = calculate_amm_output(swap_amount, reserve_in, reserve_other, fee)
+                    .ok_or(ErrorCode::MathOverflow)?;
+                let protocol_fee = calculate_protocol_fee(swap_amount, fee, protocol_fee_share)
+                    .ok_or(ErrorCode::MathOverflow)?;
+
+                // 3. Deposit the rest against the post-swap reserves. Rounding dust of the
+                // swapped leg stays in the pool, only what is actually used is pulled from the user.
+                let reserve_in_after = reserve_in
+                    .checked_add(swap_amount)
+                    .and_then(|reserve| reserve.checked_sub(protocol_fee))
+                    .ok_or(ErrorCode::MathOverflow)?;
+                let reserve_other_after = reserve_other.checked_sub(swapped_out).ok_or(ErrorCode::MathOverflow)?;
+                let (deposit_in, _, lp_amount) = calculate_liquidity_deposit(
+                    received_in - swap_amount,
+                    swapped_out,
+                    reserve_in_after,
+                    reserve_other_after,
+                    total_supply
+                ).ok_or(ErrorCode::MathOverflow)?;
+
+                let received_total = swap_amount.checked_add(deposit_in).ok_or(ErrorCode::MathOverflow)?;
+                (received_total, lp_amount, protocol_fee, 0)
+            }
+            CurveType::StableSwap => {
+                // The invariant takes imbalanced deposits directly, no internal swap needed
+                let amp = ctx.accounts.pool.amp_at(Clock::get()?.unix_timestamp);
+                let (lp_amount, fee_in, fee_other) = stable::calculate_single_deposit(
+                    received_in,
+                    reserve_in,
+                    reserve_other,
+                    amp,
+                    fee,
+                    total_supply
+                ).ok_or(ErrorCode::MathOverflow)?;
+
+                let protocol_fee_in = calculate_protocol_share(fee_in, protocol_fee_share).ok_or(ErrorCode::MathOverflow)?;
+                let protocol_fee_other = calculate_protocol_share(fee_other, protocol_fee_share).ok_or(ErrorCode::MathOverflow)?;
+                (received_in, lp_amount, protocol_fee_in, protocol_fee_other)
+            }
+            CurveType::Weighted => {
+                // Balancer's single-token join, the non-proportional part pays the swap fee.
+                // Like a swap, at most weighted::MAX_IN_RATIO of the reserve goes in at once.
+                let (weight_in, weight_other) = ctx.accounts.pool.weights_for(&input_mint_key);
+                let (lp_amount, fees) = weighted::calculate_join(
+                    &[received_in, 0],
+                    &[reserve_in, reserve_other],
+                    &[weight_in, weight_other],
+                    fee,
+                    total_supply
+                ).ok_or(ErrorCode::MathOverflow)?;
+
+                let protocol_fee_in = calculate_protocol_share(fees[0], protocol_fee_share).ok_or(ErrorCode::MathOverflow)?;
+                (received_in, lp_amount, protocol_fee_in, 0)
+            }
+        };
+
+        require!(lp_amount > 0, ErrorCode::InsufficientLiquidityMinted);
+        require!(lp_amount >= min_lp_out, ErrorCode::SlippageExceeded);
+
+        // 4. Transfer the swapped and deposited input in one go
+        let total_in = token_utils::amount_to_send(&ctx.accounts.input_mint, received_total)?;
+        let vault_in = if is_in_a {
+            &mut ctx.accounts.pool_token_account_a
+        } else {
+            &mut ctx.accounts.pool_token_account_b
+        };
+        let actual_in = token_utils::deposit_to_vault(
+            &ctx.accounts.input_token_program,
+            &ctx.accounts.input_mint,
+            &ctx.accounts.user_token_account_in,
+            vault_in,
+            &ctx.accounts.signer,
+            total_in
+        )?;
+        require!(actual_in >= received_total, ErrorCode::SlippageExceeded);
+
+        // 5. Mint LP Tokens
+        let mint_a = ctx.accounts.pool.mint_a;
+        let mint_b = ctx.accounts.pool.mint_b;
+        let pool_bump = ctx.accounts.pool.bump;
+
+        let seeds = &[
+            b"pool",
+            mint_a.as_ref(),
+            mint_b.as_ref(),
+            &[pool_bump]
+        ];
+        let signer_seeds = &[&seeds[..]];
+
+        let cpi_accounts_mint = MintTo{
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            to: ctx.accounts.user_lp_token_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(), // Pool signs
+        };
+        let cpi_context = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts_mint, signer_seeds);
+        anchor_spl::token::mint_to(cpi_context, lp_amount)?;
+
+        // 6. Set aside the protocol's share of the deposit fees and book the rest.
+        // The swapped leg never leaves the pool, so the other reserve only loses its protocol fee.
+        // Price accumulators first, they integrate the reserves being replaced
+        oracle::update_with_clock(&mut ctx.accounts.pool, &ctx.accounts.observations)?;
+        let pool_info = &mut ctx.accounts.pool;
+        let (protocol_fee_a, protocol_fee_b) = pool_info.in_pair_order(&input_mint_key, protocol_fee_in, protocol_fee_other);
+        pool_info.protocol_fees_a = pool_info.protocol_fees_a.checked_add(protocol_fee_a).ok_or(ErrorCode::MathOverflow)?;
+        pool_info.protocol_fees_b = pool_info.protocol_fees_b.checked_add(protocol_fee_b).ok_or(ErrorCode::MathOverflow)?;
+        let new_reserve_in = reserve_in
+            .checked_add(actual_in)
+            .and_then(|reserve| reserve.checked_sub(protocol_fee_in))
+            .ok_or(ErrorCode::MathOverflow)?;
+        let new_reserve_other = reserve_other.checked_sub(protocol_fee_other).ok_or(ErrorCode::MathOverflow)?;
+        pool_info.set_reserves_for(&input_mint_key, new_reserve_in, new_reserve_other);
+
+        let (amount_a, amount_b) = pool_info.in_pair_order(&input_mint_key, actual_in, 0);
+        emit!(LiquidityAdded {
+            pool: pool_info.key(),
+            user: ctx.accounts.signer.key(),
+            amount_a,
+            amount_b,
+            lp_amount,
+            reserve_a: pool_info.reserve_a,
+            reserve_b: pool_info.reserve_b,
+            lp_supply: total_supply.checked_add(lp_amount).ok_or(ErrorCode::MathOverflow)?,
+        });
+        Ok(())
+    }
+
+    pub fn remove_liquidity(ctx:Context<RemoveLiquidity>, lp_amount:u64, min_amount_a:u64, min_amount_b:u64, deadline:Option<i64>) -> Result<()>{
+        check_deadline(deadline)?;
+        // Deliberately not gated on Amm::paused, a global pause is withdraw-only
+        require!(ctx.accounts.pool.status & POOL_WITHDRAWALS_DISABLED == 0, ErrorCode::WithdrawalsDisabled);
+
         let total_supply = ctx.accounts.lp_mint.supply;
-        let reserve_a = ctx.accounts.pool_token_account_a.amount;
-        let reserve_b = ctx.accounts.pool_token_account_b.amount;
+        let token_a_mint_key = ctx.accounts.token_a_mint.key();
+        let (reserve_a, reserve_b) = ctx.accounts.pool.reserves_for(&token_a_mint_key);
+
+        let (amount_a, amount_b) = calculate_withdraw_amounts(lp_amount, reserve_a, reserve_b, total_supply)?;
 
-        let amount_a = (reserve_a as u128).checked_mul(lp_amount as u128).unwrap().checked_div(total_supply as u128).unwrap() as u64;
-        let amount_b = (reserve_b as u128).checked_mul(lp_amount as u128).unwrap().checked_div(total_supply as u128).unwrap() as u64;
+        // Checked before anything moves, so a sandwiched ratio just fails the withdrawal.
+        // The minimums apply to what the user receives after any transfer fee.
+        require!(token_utils::amount_received(&ctx.accounts.token_a_mint, amount_a)? >= min_amount_a, ErrorCode::SlippageExceeded);
+        require!(token_utils::amount_received(&ctx.accounts.token_b_mint, amount_b)? >= min_amount_b, ErrorCode::SlippageExceeded);
   
         let cpi_burn = Burn{
             mint: ctx.accounts.lp_mint.to_account_info(),
@@ -180,449 +660,3242 @@ pub mod amm {
         let cpi_accounts = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_burn);
         anchor_spl::token::burn(cpi_accounts, lp_amount)?;
 
-        let mint_a_key = ctx.accounts.token_a_mint.key();
-        let mint_b_key = ctx.accounts.token_b_mint.key();
-        let bump = ctx.bumps.pool;
+        token_utils::transfer_from_pool(
+            &ctx.accounts.token_program_a,
+            &ctx.accounts.token_a_mint,
+            &ctx.accounts.pool_token_account_a,
+            &ctx.accounts.user_token_account_a,
+            &ctx.accounts.pool,
+            amount_a
+        )?;
 
-        let seeds = &[b"pool",mint_a_key.as_ref(), mint_b_key.as_ref(),&[bump]];
-        let signer_seeds = &[&seeds[..]];
+        token_utils::transfer_from_pool(
+            &ctx.accounts.token_program_b,
+            &ctx.accounts.token_b_mint,
+            &ctx.accounts.pool_token_account_b,
+            &ctx.accounts.user_token_account_b,
+            &ctx.accounts.pool,
+            amount_b
+        )?;
 
-        let cpi_trasfer_a = Transfer{
-            from: ctx.accounts.pool_token_account_a.to_account_info(),
-            to: ctx.accounts.user_token_account_a.to_account_info(),
-            authority:ctx.accounts.pool.to_account_info(),
-        };
+        // Price accumulators first, they integrate the reserves being replaced
+        oracle::update_with_clock(&mut ctx.accounts.pool, &ctx.accounts.observations)?;
+        ctx.accounts.pool.set_reserves_for(
+            &token_a_mint_key,
+            reserve_a.checked_sub(amount_a).ok_or(ErrorCode::MathOverflow)?,
+            reserve_b.checked_sub(amount_b).ok_or(ErrorCode::MathOverflow)?
+        );
+
+        let (event_amount_a, event_amount_b) = ctx.accounts.pool.in_pair_order(&token_a_mint_key, amount_a, amount_b);
+        emit!(LiquidityRemoved {
+            pool: ctx.accounts.pool.key(),
+            user: ctx.accounts.signer.key(),
+            amount_a: event_amount_a,
+            amount_b: event_amount_b,
+            lp_amount,
+            reserve_a: ctx.accounts.pool.reserve_a,
+            reserve_b: ctx.accounts.pool.reserve_b,
+            lp_supply: total_supply - lp_amount,
+        });
+        Ok(())
+    }
+
+    // Withdraws both legs pro rata, then swaps the unwanted leg back into the pool
+    // so the user is paid entirely in output_mint
+    pub fn remove_liquidity_one_token(ctx:Context<RemoveLiquidityOneToken>, lp_amount:u64, min_amount_out:u64, deadline:Option<i64>) -> Result<()>{
+        check_deadline(deadline)?;
+        // The internal swap trades against the curve, so it follows the swap switches too
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(ctx.accounts.pool.status & POOL_WITHDRAWALS_DISABLED == 0, ErrorCode::WithdrawalsDisabled);
+        require!(ctx.accounts.pool.status & POOL_SWAPS_DISABLED == 0, ErrorCode::SwapsDisabled);
+
+        // 1. Pro rata share of both reserves, in (output, other) order
+        let is_out_a = ctx.accounts.output_mint.key() == ctx.accounts.pool.mint_a;
+        let total_supply = ctx.accounts.lp_mint.supply;
+        let output_mint_key = ctx.accounts.output_mint.key();
+        let (reserve_out, reserve_other) = ctx.accounts.pool.reserves_for(&output_mint_key);
+
+        let (amount_out, amount_other) = calculate_withdraw_amounts(lp_amount, reserve_out, reserve_other, total_supply)?;
+
+        // 2. Swap the other leg through the curve, against the reserves left after the withdrawal
+        let remaining_other = reserve_other.checked_sub(amount_other).ok_or(ErrorCode::MathOverflow)?;
+        let remaining_out = reserve_out.checked_sub(amount_out).ok_or(ErrorCode::MathOverflow)?;
+        let other_mint_key = ctx.accounts.pool.paired_mint(&output_mint_key);
+        let swapped_out = calculate_swap_output(
+            &ctx.accounts.pool,
+            &other_mint_key,
+            amount_other,
+            remaining_other,
+            remaining_out,
+            ctx.accounts.amm.fee,
+            Clock::get()?.unix_timestamp
+        ).ok_or(ErrorCode::MathOverflow)?;
+        let total_out = amount_out.checked_add(swapped_out).ok_or(ErrorCode::MathOverflow)?;
 
-        let cpi_token_a = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_trasfer_a, signer_seeds);
-        anchor_spl::token::transfer(cpi_token_a, amount_a)?;
+        let protocol_fee = calculate_protocol_fee(
+            amount_other,
+            ctx.accounts.amm.fee,
+            ctx.accounts.amm.protocol_fee_share
+        ).ok_or(ErrorCode::MathOverflow)?;
+
+        // 3. Check Slippage before anything moves, on what the user receives after any transfer fee
+        require!(token_utils::amount_received(&ctx.accounts.output_mint, total_out)? >= min_amount_out, ErrorCode::SlippageExceeded);
+
+        // 4. Burn LP and pay out, the other leg never leaves the pool
+        let cpi_burn = Burn{
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            from: ctx.accounts.lp_mint_token_account.to_account_info(),
+            authority: ctx.accounts.signer.to_account_info(),
+        };
+        let cpi_accounts = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_burn);
+        anchor_spl::token::burn(cpi_accounts, lp_amount)?;
 
-        let cpi_trasfer_b = Transfer{
-            from: ctx.accounts.pool_token_account_a.to_account_info(),
-            to: ctx.accounts.user_token_account_a.to_account_info(),
-            authority:ctx.accounts.pool.to_account_info(),
+        let vault_out = if is_out_a {
+            &ctx.accounts.pool_token_account_a
+        } else {
+            &ctx.accounts.pool_token_account_b
         };
+        token_utils::transfer_from_pool(
+            &ctx.accounts.output_token_program,
+            &ctx.accounts.output_mint,
+            vault_out,
+            &ctx.accounts.user_token_account_out,
+            &ctx.accounts.pool,
+            total_out
+        )?;
+
+        // 5. Set aside the protocol's share of the internal swap fee and book the rest.
+        // The other leg went out and straight back in, less the protocol fee.
+        // Price accumulators first, they integrate the reserves being replaced
+        oracle::update_with_clock(&mut ctx.accounts.pool, &ctx.accounts.observations)?;
+        let pool_info = &mut ctx.accounts.pool;
+        if is_out_a {
+            pool_info.protocol_fees_b = pool_info.protocol_fees_b.checked_add(protocol_fee).ok_or(ErrorCode::MathOverflow)?;
+        } else {
+            pool_info.protocol_fees_a = pool_info.protocol_fees_a.checked_add(protocol_fee).ok_or(ErrorCode::MathOverflow)?;
+        }
+        pool_info.set_reserves_for(
+            &output_mint_key,
+            reserve_out.checked_sub(total_out).ok_or(ErrorCode::MathOverflow)?,
+            reserve_other.checked_sub(protocol_fee).ok_or(ErrorCode::MathOverflow)?
+        );
 
-        let cpi_token_b = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_trasfer_b, signer_seeds);
-        anchor_spl::token::transfer(cpi_token_b, amount_b)?;
+        let (amount_a, amount_b) = pool_info.in_pair_order(&output_mint_key, total_out, 0);
+        emit!(LiquidityRemoved {
+            pool: pool_info.key(),
+            user: ctx.accounts.signer.key(),
+            amount_a,
+            amount_b,
+            lp_amount,
+            reserve_a: pool_info.reserve_a,
+            reserve_b: pool_info.reserve_b,
+            lp_supply: total_supply - lp_amount,
+        });
         Ok(())
     }
 
     pub fn swap(
         ctx: Context<Swap>, 
         amount_in: u64, 
-        min_amount_out: u64
+        min_amount_out: u64,
+        deadline: Option<i64>
     ) -> Result<()> {
+        check_deadline(deadline)?;
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(ctx.accounts.pool.status & POOL_SWAPS_DISABLED == 0, ErrorCode::SwapsDisabled);
         
-        // 1. Determine Direction (A -> B or B -> A)
-        let is_a_to_b = ctx.accounts.input_mint.key() == ctx.accounts.pool.mint_a;
-        
-        // Setup variables based on direction
-        let (
-            input_token_account,  // User's Source
-            pool_receive_account, // Pool's Vault for Input
-            pool_pay_account,     // Pool's Vault for Output
-            user_receive_account  // User's Destination
-        ) = if is_a_to_b {
-            (
-                &ctx.accounts.user_token_account_a,
-                &ctx.accounts.pool_token_account_a,
-                &ctx.accounts.pool_token_account_b,
-                &ctx.accounts.user_token_account_b,
-            )
-        } else {
-            (
-                &ctx.accounts.user_token_account_b,
-                &ctx.accounts.pool_token_account_b,
-                &ctx.accounts.pool_token_account_a,
-                &ctx.accounts.user_token_account_a,
-            )
-        };
+        // 1. Reserves in (input, output) order, the accounts fix the direction.
+        // Read before any transfer, so x does not include dx yet
+        let (reserve_in, reserve_out) = ctx.accounts.pool.reserves_for(&ctx.accounts.input_mint.key());
 
         // 2. Transfer Input (User -> Pool)
-        // User signs this transaction, so we can transfer from their account
-        let cpi_accounts_in = Transfer {
-            from: input_token_account.to_account_info(),
-            to: pool_receive_account.to_account_info(),
-            authority: ctx.accounts.signer.to_account_info(),
-        };
-        let cpi_ctx_in = CpiContext::new(
-            ctx.accounts.token_program.to_account_info(),
-            cpi_accounts_in
-        );
-        anchor_spl::token::transfer(cpi_ctx_in, amount_in)?;
-
-        // 3. Calculate Output Amount (Constant Product Formula)
-        // Load reserves *after* the transfer? 
-        // Note: For safety, standard AMMs usually calculate based on pre-transfer balance, 
-        // or account for the amount_in we just added.
-        // Let's use the balances:
-        let reserve_in = pool_receive_account.amount; // This essentially includes the amount_in we just sent if we reload, but let's do the math carefully.
-        let reserve_out = pool_pay_account.amount;
-
-        // Formula: dy = (y * dx) / (x + dx)
-        // x = reserve_in (before deposit)
-        // y = reserve_out
-        // dx = amount_in
-        // Note: Since we ALREADY transferred amount_in, pool_receive_account.amount is (x + dx).
-        // So we subtract it back for the calculation "x".
-        
-        // Reload the account to get updated balance
-        let pool_receive_account_loaded = anchor_spl::token::accessor::amount(&pool_receive_account.to_account_info())?;
-        let actual_amount_in = amount_in; // Simplified. In prod, check difference in balance for deflationary tokens.
-        
-        let reserve_in_before = pool_receive_account_loaded - actual_amount_in;
+        // The curve only prices what the vault actually gained, fee-on-transfer
+        // tokens deliver less than amount_in
+        let actual_amount_in = ctx.accounts.transfer_in(amount_in)?;
 
-        let amount_out = calculate_amm_output(
+        // 3. Calculate Output Amount on the pool's curve
+        // Constant product: dy = (y * dx') / (x + dx'), dx' = dx less the swap fee
+        // The fee stays in the vault, so it accrues to LPs on remove_liquidity,
+        // except for the protocol's share which is set aside below
+        let amount_out = calculate_swap_output(
+            &ctx.accounts.pool,
+            &ctx.accounts.input_mint.key(),
             actual_amount_in,
-            reserve_in_before,
-            reserve_out
+            reserve_in,
+            reserve_out,
+            ctx.accounts.amm.fee,
+            Clock::get()?.unix_timestamp
         ).ok_or(ErrorCode::MathOverflow)?;
 
-        // 4. Check Slippage
-        require!(amount_out >= min_amount_out, ErrorCode::SlippageExceeded);
+        // 4. Check Slippage, on what the user receives after any transfer fee
+        let amount_received = token_utils::amount_received(&ctx.accounts.output_mint, amount_out)?;
+        require!(amount_received >= min_amount_out, ErrorCode::SlippageExceeded);
 
         // 5. Transfer Output (Pool -> User)
-        // Pool PDA signs this
-        let mint_a = ctx.accounts.pool.mint_a;
-        let mint_b = ctx.accounts.pool.mint_b;
-        let bump = ctx.bumps.pool;
-        
-        let seeds = &[
-            b"pool",
-            mint_a.as_ref(),
-            mint_b.as_ref(),
-            &[bump]
-        ];
-        let signer_seeds = &[&seeds[..]];
+        ctx.accounts.transfer_out(amount_out)?;
 
-        let cpi_accounts_out = Transfer {
-            from: pool_pay_account.to_account_info(),
-            to: user_receive_account.to_account_info(),
-            authority: ctx.accounts.pool.to_account_info(),
-        };
-        let cpi_ctx_out = CpiContext::new_with_signer(
-            ctx.accounts.token_program.to_account_info(),
-            cpi_accounts_out,
-            signer_seeds
-        );
-        anchor_spl::token::transfer(cpi_ctx_out, amount_out)?;
+        // 6. Set aside the protocol's share of the fee and book the trade
+        let protocol_fee = ctx.accounts.update_pool(actual_amount_in, amount_out)?;
 
-        msg!("Swapped {} tokens for {}", amount_in, amount_out);
+        ctx.accounts.emit_swapped(actual_amount_in, amount_out, protocol_fee);
         Ok(())
     }
-}
 
-// Helper function for Constant Product Math
-// Output = (Input * Reserve_Out) / (Reserve_In + Input)
-fn calculate_amm_output(amount_in: u64, reserve_in: u64, reserve_out: u64) -> Option<u64> {
-    let amount_in = amount_in as u128;
-    let reserve_in = reserve_in as u128;
-    let reserve_out = reserve_out as u128;
+    // Same as swap, but the user fixes the output and caps the input
+    pub fn swap_exact_out(
+        ctx: Context<Swap>,
+        amount_out: u64,
+        max_amount_in: u64,
+        deadline: Option<i64>
+    ) -> Result<()> {
+        check_deadline(deadline)?;
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(ctx.accounts.pool.status & POOL_SWAPS_DISABLED == 0, ErrorCode::SwapsDisabled);
 
-    let numerator = amount_in.checked_mul(reserve_out)?;
-    let denominator = reserve_in.checked_add(amount_in)?;
+        // 1. Reserves in (input, output) order, the accounts fix the direction
+        let (reserve_in, reserve_out) = ctx.accounts.pool.reserves_for(&ctx.accounts.input_mint.key());
 
-    let amount_out = numerator.checked_div(denominator)?;
-    
-    Some(amount_out as u64)
-}
+        // 2. Calculate Required Input (inverse of the pool's curve, rounded up)
+        // amount_out is what the user receives, the vault also covers any transfer fee on it
+        let amount_out_sent = token_utils::amount_to_send(&ctx.accounts.output_mint, amount_out)?;
+        require!(amount_out_sent < reserve_out, ErrorCode::InsufficientLiquidity);
 
-#[derive(Accounts)]
-pub struct Initialize<'info>{
-    #[account(mut)]
-    pub signer: Signer<'info>,
-    #[account(
-        init, // Changed to init (init_if_needed is rare for global configs)
-        payer = signer,
-        space = 8 + Amm::INIT_SPACE,
-        seeds = [b"AMM"],
-        bump
-    )]
-    pub amm: Account<'info, Amm>,
-    pub token_program: Program<'info, Token>,
-    pub system_program: Program<'info, System>,
-}
+        let amount_in_received = calculate_swap_input(
+            &ctx.accounts.pool,
+            &ctx.accounts.input_mint.key(),
+            amount_out_sent,
+            reserve_in,
+            reserve_out,
+            ctx.accounts.amm.fee,
+            Clock::get()?.unix_timestamp
+        ).ok_or(ErrorCode::MathOverflow)?;
+        let amount_in = token_utils::amount_to_send(&ctx.accounts.input_mint, amount_in_received)?;
 
-#[derive(Accounts)]
-pub struct CreatePool<'info>{
-    #[account(mut)]
-    pub signer: Signer<'info>,
+        // 3. Check Slippage
+        require!(amount_in <= max_amount_in, ErrorCode::SlippageExceeded);
 
-    pub mint_a: Account<'info,Mint>,
-    pub mint_b: Account<'info,Mint>,
+        // 4. Transfer Input (User -> Pool) and Output (Pool -> User)
+        // The quote assumed the mint's configured transfer fee, the vault balance is what counts
+        let actual_amount_in = ctx.accounts.transfer_in(amount_in)?;
+        require!(actual_amount_in >= amount_in_received, ErrorCode::SlippageExceeded);
+        ctx.accounts.transfer_out(amount_out_sent)?;
 
-    #[account(
-        mut,
-        associated_token::mint = mint_a,
-        associated_token::authority = signer
-    )]
-    pub user_token_account_a: Account<'info, TokenAccount>,
+        // 5. Set aside the protocol's share of the fee and book the trade
+        let protocol_fee = ctx.accounts.update_pool(actual_amount_in, amount_out_sent)?;
 
-    #[account(
-        mut,
-        associated_token::mint = mint_b,
-        associated_token::authority = signer,
-    )]
-    pub user_token_account_b: Account<'info,TokenAccount>,
+        ctx.accounts.emit_swapped(actual_amount_in, amount_out_sent, protocol_fee);
+        Ok(())
+    }
 
-    #[account(
-        init,
-        payer = signer,
-        space = 8 + Pool::INIT_SPACE,
-        seeds = [b"pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
-        bump 
-    )]
-    pub pool: Account<'info, Pool>,
+    // Swaps along a path of pools in one instruction, the hop accounts come in as
+    // remaining_accounts (see route.rs). Intermediate tokens go straight from one
+    // pool's vault to the next, only the final output is checked against min_amount_out.
+    pub fn swap_route<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SwapRoute<'info>>,
+        amount_in: u64,
+        min_amount_out: u64,
+        deadline: Option<i64>
+    ) -> Result<()> {
+        check_deadline(deadline)?;
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
 
-    #[account(
-        init,
-        payer = signer,
-        associated_token::mint = mint_a,
-        associated_token::authority = pool,
-    )]
-    pub pool_token_account_a: Account<'info, TokenAccount>,
+        let mut hops = route::load_hops(
+            ctx.remaining_accounts,
+            ctx.accounts.input_mint.key(),
+            ctx.accounts.output_mint.key()
+        )?;
+        for hop in &hops {
+            require!(hop.pool.status & POOL_SWAPS_DISABLED == 0, ErrorCode::SwapsDisabled);
+        }
 
-    #[account(
-        init,
-        payer = signer,
-        associated_token::mint = mint_b,
-        associated_token::authority = pool,
-    )]
-    pub pool_token_account_b: Account<'info,TokenAccount>,
+        // 1. Transfer Input (User -> first pool), priced on what the vault actually gained
+        let mut hop_amount_in = token_utils::deposit_to_vault(
+            &ctx.accounts.input_token_program,
+            &ctx.accounts.input_mint,
+            &ctx.accounts.user_token_account_in,
+            &mut hops[0].vault_in,
+            &ctx.accounts.signer,
+            amount_in
+        )?;
 
-    #[account(
-        init,
-        payer = signer, 
-        seeds = [b"lp_mint", mint_a.key().as_ref(), mint_b.key().as_ref()],
-        bump,
-        mint::decimals = 9,
-        mint::authority = pool, // <--- CORRECT AUTHORITY
-        mint::freeze_authority = pool, // <--- CORRECT AUTHORITY
+        // 2. Each hop pays into the next pool's vault, the last one pays the user
+        let now = Clock::get()?.unix_timestamp;
+        let balance_before = ctx.accounts.user_token_account_out.amount;
+        for index in 0..hops.len() {
+            let (hop, next_hops) = hops[index..].split_first_mut().ok_or(ErrorCode::InvalidRoute)?;
+
+            let (reserve_in, reserve_out) = hop.pool.reserves_for(&hop.mint_in());
+            let hop_amount_out = calculate_swap_output(
+                &hop.pool,
+                &hop.mint_in(),
+                hop_amount_in,
+                reserve_in,
+                reserve_out,
+                ctx.accounts.amm.fee,
+                now
+            ).ok_or(ErrorCode::MathOverflow)?;
+
+            let protocol_fee = book_swap(
+                &mut hop.pool,
+                &hop.observations,
+                &ctx.accounts.amm,
+                hop.is_a_to_b,
+                hop_amount_in,
+                hop_amount_out
+            )?;
+            emit!(Swapped {
+                pool: hop.pool.key(),
+                user: ctx.accounts.signer.key(),
+                input_mint: hop.mint_in(),
+                amount_in: hop_amount_in,
+                amount_out: hop_amount_out,
+                protocol_fee,
+                reserve_a: hop.pool.reserve_a,
+                reserve_b: hop.pool.reserve_b,
+            });
+
+            match next_hops.first_mut() {
+                Some(next_hop) => {
+                    let vault_balance_before = next_hop.vault_in.amount;
+                    token_utils::transfer_from_pool(
+                        &hop.token_program_out,
+                        &hop.mint_out,
+                        &hop.vault_out,
+                        &next_hop.vault_in,
+                        &hop.pool,
+                        hop_amount_out
+                    )?;
+                    next_hop.vault_in.reload()?;
+                    hop_amount_in = next_hop.vault_in.amount
+                        .checked_sub(vault_balance_before)
+                        .ok_or(ErrorCode::MathOverflow)?;
+                }
+                None => {
+                    token_utils::transfer_from_pool(
+                        &hop.token_program_out,
+                        &hop.mint_out,
+                        &hop.vault_out,
+                        &ctx.accounts.user_token_account_out,
+                        &hop.pool,
+                        hop_amount_out
+                    )?;
+                }
+            }
+
+            // Remaining accounts are not written back by Anchor
+            hop.pool.exit(&crate::ID)?;
+        }
+
+        // 3. Check Slippage on what actually reached the user
+        ctx.accounts.user_token_account_out.reload()?;
+        let amount_out = ctx.accounts.user_token_account_out.amount
+            .checked_sub(balance_before)
+            .ok_or(ErrorCode::MathOverflow)?;
+        require!(amount_out >= min_amount_out, ErrorCode::SlippageExceeded);
+        Ok(())
+    }
+
+    pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let amount_a = ctx.accounts.pool.protocol_fees_a;
+        let amount_b = ctx.accounts.pool.protocol_fees_b;
+
+        if amount_a > 0 {
+            token_utils::transfer_from_pool(
+                &ctx.accounts.token_program_a,
+                &ctx.accounts.mint_a,
+                &ctx.accounts.pool_token_account_a,
+                &ctx.accounts.treasury_token_account_a,
+                &ctx.accounts.pool,
+                amount_a
+            )?;
+        }
+
+        if amount_b > 0 {
+            token_utils::transfer_from_pool(
+                &ctx.accounts.token_program_b,
+                &ctx.accounts.mint_b,
+                &ctx.accounts.pool_token_account_b,
+                &ctx.accounts.treasury_token_account_b,
+                &ctx.accounts.pool,
+                amount_b
+            )?;
+        }
+
+        let pool_info = &mut ctx.accounts.pool;
+        pool_info.protocol_fees_a = 0;
+        pool_info.protocol_fees_b = 0;
+
+        emit!(ProtocolFeesCollected {
+            pool: pool_info.key(),
+            fee_recipient: ctx.accounts.amm.fee_recipient,
+            amount_a,
+            amount_b,
+        });
+        Ok(())
+    }
+
+    // Uniswap v2's sync: sets the recorded reserves to the vault balances (less
+    // protocol fees), so tokens sent straight to a vault are donated to the LPs
+    pub fn sync(ctx: Context<SyncReserves>) -> Result<()> {
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+
+        // Price accumulators first, they integrate the reserves being replaced
+        oracle::update_with_clock(&mut ctx.accounts.pool, &ctx.accounts.observations)?;
+        let pool_info = &mut ctx.accounts.pool;
+        pool_info.reserve_a = ctx.accounts.pool_token_account_a.amount
+            .checked_sub(pool_info.protocol_fees_a)
+            .ok_or(ErrorCode::MathOverflow)?;
+        pool_info.reserve_b = ctx.accounts.pool_token_account_b.amount
+            .checked_sub(pool_info.protocol_fees_b)
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        emit!(ReservesSynced {
+            pool: pool_info.key(),
+            reserve_a: pool_info.reserve_a,
+            reserve_b: pool_info.reserve_b,
+        });
+        Ok(())
+    }
+
+    // Uniswap v2's skim: the other way round, sends whatever the vaults hold beyond
+    // the recorded reserves and protocol fees out, leaving the price untouched
+    pub fn skim(ctx: Context<Skim>) -> Result<()> {
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+
+        let pool_info = &ctx.accounts.pool;
+        let excess_a = ctx.accounts.pool_token_account_a.amount
+            .checked_sub(pool_info.reserve_a)
+            .and_then(|excess| excess.checked_sub(pool_info.protocol_fees_a))
+            .ok_or(ErrorCode::MathOverflow)?;
+        let excess_b = ctx.accounts.pool_token_account_b.amount
+            .checked_sub(pool_info.reserve_b)
+            .and_then(|excess| excess.checked_sub(pool_info.protocol_fees_b))
+            .ok_or(ErrorCode::MathOverflow)?;
+
+        if excess_a > 0 {
+            token_utils::transfer_from_pool(
+                &ctx.accounts.token_program_a,
+                &ctx.accounts.mint_a,
+                &ctx.accounts.pool_token_account_a,
+                &ctx.accounts.recipient_token_account_a,
+                &ctx.accounts.pool,
+                excess_a
+            )?;
+        }
+
+        if excess_b > 0 {
+            token_utils::transfer_from_pool(
+                &ctx.accounts.token_program_b,
+                &ctx.accounts.mint_b,
+                &ctx.accounts.pool_token_account_b,
+                &ctx.accounts.recipient_token_account_b,
+                &ctx.accounts.pool,
+                excess_b
+            )?;
+        }
+
+        emit!(Skimmed {
+            pool: ctx.accounts.pool.key(),
+            amount_a: excess_a,
+            amount_b: excess_b,
+        });
+        Ok(())
+    }
+
+    // Read-only, returns the price accumulators as they were `seconds_ago` seconds
+    // back for each entry. TWAP between two entries is the accumulator difference
+    // divided by the time difference, see oracle.rs.
+    pub fn observe(ctx: Context<Observe>, seconds_agos: Vec<u32>) -> Result<Vec<CumulativePrices>> {
+        require!(seconds_agos.len() <= oracle::MAX_OBSERVE_POINTS, ErrorCode::InvalidObservationWindow);
+
+        let now = Clock::get()?.unix_timestamp;
+        let observations = ctx.accounts.observations.load()?;
+        seconds_agos
+            .iter()
+            .map(|seconds_ago| oracle::cumulatives_at(&ctx.accounts.pool, &observations, now, now - *seconds_ago as i64))
+            .collect()
+    }
+
+    // Concentrated liquidity pool for the pair, see clmm.rs. Starts empty at
+    // sqrt_price_x64, liquidity comes in through open_position + increase_liquidity.
+    // Several pools per pair can exist, one per tick_spacing.
+    pub fn create_clmm_pool(ctx: Context<CreateClmmPool>, tick_spacing: u16, fee: u16, sqrt_price_x64: u128) -> Result<()> {
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!((1..=clmm::MAX_TICK_SPACING).contains(&tick_spacing), ErrorCode::InvalidTickSpacing);
+        // Same cap as Amm::fee, the swap step divides by (10_000 - fee)
+        require!(fee <= MAX_FEE, ErrorCode::InvalidFee);
+        token_utils::validate_mint(&ctx.accounts.mint_a)?;
+        token_utils::validate_mint(&ctx.accounts.mint_b)?;
+
+        let pool_info = &mut ctx.accounts.pool;
+        pool_info.version = clmm::CLMM_POOL_VERSION;
+        pool_info.mint_a = ctx.accounts.mint_a.key();
+        pool_info.mint_b = ctx.accounts.mint_b.key();
+        pool_info.vault_a = ctx.accounts.pool_token_account_a.key();
+        pool_info.vault_b = ctx.accounts.pool_token_account_b.key();
+        pool_info.bump = ctx.bumps.pool;
+        pool_info.tick_spacing = tick_spacing;
+        pool_info.fee = fee;
+        pool_info.creator = ctx.accounts.signer.key();
+        pool_info.tick_current = clmm::tick_at_sqrt_price(sqrt_price_x64)?;
+        pool_info.sqrt_price_x64 = sqrt_price_x64;
+
+        emit!(ClmmPoolCreated {
+            pool: pool_info.key(),
+            creator: pool_info.creator,
+            mint_a: pool_info.mint_a,
+            mint_b: pool_info.mint_b,
+            tick_spacing,
+            fee,
+            sqrt_price_x64,
+            tick_current: pool_info.tick_current,
+        });
+        Ok(())
+    }
+
+    // set_pool_status for CLMM pools, same authority rules
+    pub fn set_clmm_pool_status(ctx: Context<SetClmmPoolStatus>, status: u8) -> Result<()> {
+        let authority = ctx.accounts.authority.key();
+        let pool_info = &mut ctx.accounts.pool;
+        let old_status = pool_info.status;
+        check_pool_status_change(&ctx.accounts.amm, &authority, old_status, status)?;
+
+        pool_info.status = status;
+
+        emit!(PoolStatusUpdated {
+            pool: pool_info.key(),
+            authority,
+            old_status,
+            new_status: status,
+        });
+        Ok(())
+    }
+
+    // collect_protocol_fees for CLMM pools
+    pub fn collect_clmm_protocol_fees(ctx: Context<CollectClmmProtocolFees>) -> Result<()> {
+        let amount_a = ctx.accounts.pool.protocol_fees_a;
+        let amount_b = ctx.accounts.pool.protocol_fees_b;
+
+        if amount_a > 0 {
+            token_utils::transfer_from_clmm_pool(
+                &ctx.accounts.token_program_a,
+                &ctx.accounts.mint_a,
+                &ctx.accounts.pool_token_account_a,
+                &ctx.accounts.treasury_token_account_a,
+                &ctx.accounts.pool,
+                amount_a
+            )?;
+        }
+
+        if amount_b > 0 {
+            token_utils::transfer_from_clmm_pool(
+                &ctx.accounts.token_program_b,
+                &ctx.accounts.mint_b,
+                &ctx.accounts.pool_token_account_b,
+                &ctx.accounts.treasury_token_account_b,
+                &ctx.accounts.pool,
+                amount_b
+            )?;
+        }
+
+        let pool_info = &mut ctx.accounts.pool;
+        pool_info.protocol_fees_a = 0;
+        pool_info.protocol_fees_b = 0;
+
+        emit!(ProtocolFeesCollected {
+            pool: pool_info.key(),
+            fee_recipient: ctx.accounts.amm.fee_recipient,
+            amount_a,
+            amount_b,
+        });
+        Ok(())
+    }
+
+    // Permissionless, whoever first needs ticks in the range pays the rent
+    pub fn initialize_tick_array(ctx: Context<InitializeTickArray>, start_tick_index: i32) -> Result<()> {
+        let tick_spacing = ctx.accounts.pool.tick_spacing;
+        require!(
+            start_tick_index == clmm::tick_array_start(start_tick_index, tick_spacing)
+                && start_tick_index >= clmm::tick_array_start(clmm::MIN_TICK, tick_spacing)
+                && start_tick_index <= clmm::MAX_TICK,
+            ErrorCode::InvalidTickArray
+        );
+
+        let mut tick_array = ctx.accounts.tick_array.load_init()?;
+        tick_array.pool = ctx.accounts.pool.key();
+        tick_array.start_tick_index = start_tick_index;
+        Ok(())
+    }
+
+    // Opens an empty position over [tick_lower, tick_upper) and mints its NFT to
+    // the signer. Mint authority is dropped right after, so the supply stays 1.
+    pub fn open_position(ctx: Context<OpenPosition>, tick_lower: i32, tick_upper: i32) -> Result<()> {
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        let tick_spacing = ctx.accounts.pool.tick_spacing;
+        clmm::check_tick(tick_lower, tick_spacing)?;
+        clmm::check_tick(tick_upper, tick_spacing)?;
+        require!(tick_lower < tick_upper, ErrorCode::InvalidTick);
+
+        let position = &mut ctx.accounts.position;
+        position.pool = ctx.accounts.pool.key();
+        position.position_mint = ctx.accounts.position_mint.key();
+        position.tick_lower = tick_lower;
+        position.tick_upper = tick_upper;
+        position.bump = ctx.bumps.position;
+
+        let pool_info = &ctx.accounts.pool;
+        let tick_spacing_bytes = pool_info.tick_spacing.to_le_bytes();
+        let seeds = &[
+            b"clmm_pool",
+            pool_info.mint_a.as_ref(),
+            pool_info.mint_b.as_ref(),
+            &tick_spacing_bytes,
+            &[pool_info.bump]
+        ];
+        let signer_seeds = &[&seeds[..]];
+
+        let cpi_accounts_mint = MintTo{
+            mint: ctx.accounts.position_mint.to_account_info(),
+            to: ctx.accounts.position_token_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_context_mint = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            cpi_accounts_mint,
+            signer_seeds
+        );
+        anchor_spl::token::mint_to(cpi_context_mint, 1)?;
+
+        let cpi_accounts_authority = SetAuthority{
+            current_authority: ctx.accounts.pool.to_account_info(),
+            account_or_mint: ctx.accounts.position_mint.to_account_info(),
+        };
+        let cpi_context_authority = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            cpi_accounts_authority,
+            signer_seeds
+        );
+        anchor_spl::token::set_authority(cpi_context_authority, AuthorityType::MintTokens, None)?;
+
+        emit!(PositionOpened {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            position_mint: ctx.accounts.position_mint.key(),
+            owner: ctx.accounts.signer.key(),
+            tick_lower,
+            tick_upper,
+        });
+        Ok(())
+    }
+
+    // Adds `liquidity` to the position, the user pays the amounts it is worth at
+    // the current price (rounded up). The maximums cap what the user sends.
+    pub fn increase_liquidity(
+        ctx: Context<ModifyPosition>,
+        liquidity: u128,
+        amount_a_max: u64,
+        amount_b_max: u64,
+        deadline: Option<i64>
+    ) -> Result<()> {
+        check_deadline(deadline)?;
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(ctx.accounts.pool.status & POOL_DEPOSITS_DISABLED == 0, ErrorCode::DepositsDisabled);
+        require!(liquidity > 0, ErrorCode::ZeroAmount);
+        let liquidity_delta = i128::try_from(liquidity).map_err(|_| ErrorCode::MathOverflow)?;
+
+        let (amount_a, amount_b) = ctx.accounts.modify_position(liquidity_delta)?;
+
+        let amount_a_sent = token_utils::amount_to_send(&ctx.accounts.token_mint_a, amount_a)?;
+        let amount_b_sent = token_utils::amount_to_send(&ctx.accounts.token_mint_b, amount_b)?;
+        require!(amount_a_sent <= amount_a_max, ErrorCode::SlippageExceeded);
+        require!(amount_b_sent <= amount_b_max, ErrorCode::SlippageExceeded);
+
+        // The vaults must gain the full amounts, whatever the mints' transfer fees did
+        if amount_a > 0 {
+            let received_a = token_utils::deposit_to_vault(
+                &ctx.accounts.token_program_a,
+                &ctx.accounts.token_mint_a,
+                &ctx.accounts.user_token_account_a,
+                &mut ctx.accounts.pool_token_account_a,
+                &ctx.accounts.signer,
+                amount_a_sent
+            )?;
+            require!(received_a >= amount_a, ErrorCode::SlippageExceeded);
+        }
+        if amount_b > 0 {
+            let received_b = token_utils::deposit_to_vault(
+                &ctx.accounts.token_program_b,
+                &ctx.accounts.token_mint_b,
+                &ctx.accounts.user_token_account_b,
+                &mut ctx.accounts.pool_token_account_b,
+                &ctx.accounts.signer,
+                amount_b_sent
+            )?;
+            require!(received_b >= amount_b, ErrorCode::SlippageExceeded);
+        }
+
+        emit!(ClmmLiquidityAdded {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            user: ctx.accounts.signer.key(),
+            liquidity,
+            amount_a,
+            amount_b,
+            pool_liquidity: ctx.accounts.pool.liquidity,
+        });
+        Ok(())
+    }
+
+    // Removes `liquidity` from the position and pays out what it is worth at the
+    // current price (rounded down). Fees stay on the position until collect_fees.
+    pub fn decrease_liquidity(
+        ctx: Context<ModifyPosition>,
+        liquidity: u128,
+        amount_a_min: u64,
+        amount_b_min: u64,
+        deadline: Option<i64>
+    ) -> Result<()> {
+        check_deadline(deadline)?;
+        // Deliberately not gated on Amm::paused, a global pause is withdraw-only
+        require!(ctx.accounts.pool.status & POOL_WITHDRAWALS_DISABLED == 0, ErrorCode::WithdrawalsDisabled);
+        require!(liquidity > 0, ErrorCode::ZeroAmount);
+        require!(liquidity <= ctx.accounts.position.liquidity, ErrorCode::InsufficientBalance);
+        let liquidity_delta = i128::try_from(liquidity).map_err(|_| ErrorCode::MathOverflow)?;
+
+        let (amount_a, amount_b) = ctx.accounts.modify_position(-liquidity_delta)?;
+
+        // The minimums apply to what the user receives after any transfer fee
+        require!(token_utils::amount_received(&ctx.accounts.token_mint_a, amount_a)? >= amount_a_min, ErrorCode::SlippageExceeded);
+        require!(token_utils::amount_received(&ctx.accounts.token_mint_b, amount_b)? >= amount_b_min, ErrorCode::SlippageExceeded);
+
+        ctx.accounts.transfer_out(amount_a, amount_b)?;
+
+        emit!(ClmmLiquidityRemoved {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            user: ctx.accounts.signer.key(),
+            liquidity,
+            amount_a,
+            amount_b,
+            pool_liquidity: ctx.accounts.pool.liquidity,
+        });
+        Ok(())
+    }
+
+    // Pays out the swap fees the position has earned so far
+    pub fn collect_fees(ctx: Context<ModifyPosition>) -> Result<()> {
+        // Brings fees_owed up to date without changing the liquidity
+        ctx.accounts.modify_position(0)?;
+
+        let position = &mut ctx.accounts.position;
+        let (amount_a, amount_b) = (position.fees_owed_a, position.fees_owed_b);
+        position.fees_owed_a = 0;
+        position.fees_owed_b = 0;
+
+        ctx.accounts.transfer_out(amount_a, amount_b)?;
+
+        emit!(ClmmFeesCollected {
+            pool: ctx.accounts.pool.key(),
+            position: ctx.accounts.position.key(),
+            user: ctx.accounts.signer.key(),
+            amount_a,
+            amount_b,
+        });
+        Ok(())
+    }
+
+    // Exact-input swap on a CLMM pool. The tick arrays the price may move through
+    // come in as remaining_accounts: first the one holding the current tick, then
+    // the next ones in swap direction (at most clmm::MAX_SWAP_TICK_ARRAYS).
+    // sqrt_price_limit_x64 = 0 means no limit. The whole input must be swapped.
+    pub fn clmm_swap<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ClmmSwap<'info>>,
+        amount_in: u64,
+        min_amount_out: u64,
+        sqrt_price_limit_x64: u128,
+        deadline: Option<i64>
+    ) -> Result<()> {
+        check_deadline(deadline)?;
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(ctx.accounts.pool.status & POOL_SWAPS_DISABLED == 0, ErrorCode::SwapsDisabled);
+        require!(amount_in > 0, ErrorCode::ZeroAmount);
+
+        let a_to_b = ctx.accounts.input_mint.key() == ctx.accounts.pool.mint_a;
+        let sqrt_price_limit_x64 = match (sqrt_price_limit_x64, a_to_b) {
+            (0, true) => clmm::MIN_SQRT_PRICE_X64 + 1,
+            (0, false) => clmm::MAX_SQRT_PRICE_X64 - 1,
+            (limit, _) => limit,
+        };
+
+        // 1. Transfer Input (User -> Pool), priced on what the vault actually gained
+        let actual_amount_in = token_utils::deposit_to_vault(
+            &ctx.accounts.input_token_program,
+            &ctx.accounts.input_mint,
+            &ctx.accounts.user_token_account_in,
+            &mut ctx.accounts.pool_token_account_in,
+            &ctx.accounts.signer,
+            amount_in
+        )?;
+
+        // 2. Walk the curve, crossing ticks as needed
+        let tick_array_loaders = clmm::load_tick_arrays(ctx.remaining_accounts, &ctx.accounts.pool.key())?;
+        let mut tick_arrays = tick_array_loaders
+            .iter()
+            .map(|tick_array| tick_array.load_mut())
+            .collect::<Result<Vec<_>>>()?;
+        let result = clmm::swap(
+            &mut ctx.accounts.pool,
+            &mut tick_arrays,
+            a_to_b,
+            actual_amount_in,
+            sqrt_price_limit_x64,
+            ctx.accounts.amm.protocol_fee_share
+        )?;
+        drop(tick_arrays);
+
+        // 3. Check Slippage, on what the user receives after any transfer fee
+        let amount_received = token_utils::amount_received(&ctx.accounts.output_mint, result.amount_out)?;
+        require!(amount_received >= min_amount_out, ErrorCode::SlippageExceeded);
+
+        // 4. Transfer Output (Pool -> User)
+        token_utils::transfer_from_clmm_pool(
+            &ctx.accounts.output_token_program,
+            &ctx.accounts.output_mint,
+            &ctx.accounts.pool_token_account_out,
+            &ctx.accounts.user_token_account_out,
+            &ctx.accounts.pool,
+            result.amount_out
+        )?;
+
+        let pool_info = &ctx.accounts.pool;
+        emit!(ClmmSwapped {
+            pool: pool_info.key(),
+            user: ctx.accounts.signer.key(),
+            input_mint: ctx.accounts.input_mint.key(),
+            amount_in: actual_amount_in,
+            amount_out: result.amount_out,
+            fee_amount: result.fee_amount,
+            protocol_fee: result.protocol_fee,
+            sqrt_price_x64: pool_info.sqrt_price_x64,
+            tick_current: pool_info.tick_current,
+            liquidity: pool_info.liquidity,
+        });
+        Ok(())
+    }
+
+    // Multi-asset pool of 2..=multi::MAX_POOL_TOKENS tokens on the weighted invariant.
+    // The remaining accounts name the tokens (see multi.rs), sorted by mint, and
+    // `weights` / `amounts` follow the same order. The vaults are created here.
+    pub fn create_multi_pool<'info>(
+        ctx: Context<'_, '_, 'info, 'info, CreateMultiPool<'info>>,
+        pool_id: [u8; 32],
+        weights: Vec<u16>,
+        amounts: Vec<u64>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(
+            ctx.remaining_accounts.len() == weights.len() * multi::ACCOUNTS_PER_TOKEN && amounts.len() == weights.len(),
+            ErrorCode::InvalidTokenCount
+        );
+        let mints: Vec<Pubkey> = ctx.remaining_accounts
+            .chunks(multi::ACCOUNTS_PER_TOKEN)
+            .map(|token_infos| token_infos[0].key())
+            .collect();
+        multi::validate_tokens(&mints, &weights)?;
+        require!(pool_id == multi::pool_id(&mints, &weights), ErrorCode::InvalidPoolId);
+
+        // 1. CREATE THE VAULTS (the pool's ATA for each mint)
+        let mut tokens = Vec::with_capacity(mints.len());
+        for (token_infos, weight) in ctx.remaining_accounts.chunks(multi::ACCOUNTS_PER_TOKEN).zip(&weights) {
+            let cpi_accounts = associated_token::Create {
+                payer: ctx.accounts.signer.to_account_info(),
+                associated_token: token_infos[1].clone(),
+                authority: ctx.accounts.pool.to_account_info(),
+                mint: token_infos[0].clone(),
+                system_program: ctx.accounts.system_program.to_account_info(),
+                token_program: token_infos[3].clone(),
+            };
+            associated_token::create(CpiContext::new(ctx.accounts.associated_token_program.to_account_info(), cpi_accounts))?;
+            tokens.push(PoolToken { mint: token_infos[0].key(), vault: token_infos[1].key(), weight: *weight, ..Default::default() });
+        }
+
+        let pool_info = &mut ctx.accounts.pool;
+        pool_info.version = multi::MULTI_POOL_VERSION;
+        pool_info.pool_id = pool_id;
+        pool_info.bump = ctx.bumps.pool;
+        pool_info.lp_mint = ctx.accounts.lp_mint.key();
+        pool_info.lp_mint_bump = ctx.bumps.lp_mint;
+        pool_info.creator = ctx.accounts.signer.key();
+        pool_info.tokens = tokens;
+
+        // 2. TRANSFER TOKENS INTO VAULTS (user signs), LP is priced on what they received
+        let mut token_accounts = multi::load_token_accounts(ctx.remaining_accounts, &ctx.accounts.pool, &ctx.accounts.signer.key())?;
+        let mut received = Vec::with_capacity(token_accounts.len());
+        for (token, amount) in token_accounts.iter_mut().zip(&amounts) {
+            token_utils::validate_mint(&token.mint)?;
+            require!(token.user_token_account.amount >= *amount, ErrorCode::InsufficientBalance);
+            received.push(token_utils::deposit_to_vault(
+                &token.token_program,
+                &token.mint,
+                &token.user_token_account,
+                &mut token.vault,
+                &ctx.accounts.signer,
+                *amount
+            )?);
+        }
+        for (token, amount) in ctx.accounts.pool.tokens.iter_mut().zip(&received) {
+            token.reserve = *amount;
+        }
+
+        // Initial LP is prod(x_i^w_i), MINIMUM_LIQUIDITY of it is locked forever
+        let initial_lp = weighted::compute_invariant(&received, &weights).ok_or(ErrorCode::MathOverflow)?;
+        require!(initial_lp > MINIMUM_LIQUIDITY, ErrorCode::InsufficientInitialLiquidity);
+        let lp_amount = initial_lp - MINIMUM_LIQUIDITY;
+
+        // 3. MINT LP TOKENS (Pool signs as authority)
+        let pool_bump = [ctx.accounts.pool.bump];
+        let seeds: &[&[u8]] = &[b"multi_pool", pool_id.as_ref(), &pool_bump];
+        let signer_seeds = &[seeds];
+
+        let cpi_accounts_lock = MintTo{
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            to: ctx.accounts.locked_lp_token_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_context_lock = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts_lock, signer_seeds);
+        anchor_spl::token::mint_to(cpi_context_lock, MINIMUM_LIQUIDITY)?;
+
+        let cpi_accounts_mint = MintTo{
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            to: ctx.accounts.user_lp_token_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(),
+        };
+        let cpi_context_mint = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts_mint, signer_seeds);
+        anchor_spl::token::mint_to(cpi_context_mint, lp_amount)?;
+
+        emit!(MultiPoolCreated {
+            pool: ctx.accounts.pool.key(),
+            creator: ctx.accounts.signer.key(),
+            lp_mint: ctx.accounts.lp_mint.key(),
+            mints,
+            weights,
+            amounts: received,
+            lp_amount,
+            lp_supply: initial_lp,
+        });
+        Ok(())
+    }
+
+    // Proportional deposit into every token of a multi-asset pool, the maximums follow
+    // the pool's token order. Like add_liquidity, no fee is due.
+    pub fn add_multi_liquidity<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ModifyMultiLiquidity<'info>>,
+        max_amounts: Vec<u64>,
+        min_lp_out: u64,
+        deadline: Option<i64>
+    ) -> Result<()> {
+        check_deadline(deadline)?;
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(ctx.accounts.pool.status & POOL_DEPOSITS_DISABLED == 0, ErrorCode::DepositsDisabled);
+        require!(max_amounts.len() == ctx.accounts.pool.tokens.len(), ErrorCode::InvalidTokenCount);
+
+        let mut token_accounts = multi::load_token_accounts(ctx.remaining_accounts, &ctx.accounts.pool, &ctx.accounts.signer.key())?;
+        let total_supply = ctx.accounts.lp_mint.supply;
+        let reserves = ctx.accounts.pool.reserves();
+
+        // 1. The maximums are what the user sends, the ratio is matched on what the vaults receive
+        let max_received = token_accounts
+            .iter()
+            .zip(&max_amounts)
+            .map(|(token, max_amount)| token_utils::amount_received(&token.mint, *max_amount))
+            .collect::<Result<Vec<u64>>>()?;
+        let (_, expected) = multi::calculate_deposit(&max_received, &reserves, total_supply).ok_or(ErrorCode::MathOverflow)?;
+
+        // 2. Transfer Tokens (only the amounts used)
+        let mut received = Vec::with_capacity(token_accounts.len());
+        for (token, expected_amount) in token_accounts.iter_mut().zip(&expected) {
+            let token_amount = token_utils::amount_to_send(&token.mint, *expected_amount)?;
+            received.push(token_utils::deposit_to_vault(
+                &token.token_program,
+                &token.mint,
+                &token.user_token_account,
+                &mut token.vault,
+                &ctx.accounts.signer,
+                token_amount
+            )?);
+        }
+
+        // LP is priced on the measured vault balance changes, not on the quote
+        let (lp_amount, _) = multi::calculate_deposit(&received, &reserves, total_supply).ok_or(ErrorCode::MathOverflow)?;
+        require!(lp_amount > 0, ErrorCode::InsufficientLiquidityMinted);
+        require!(lp_amount >= min_lp_out, ErrorCode::SlippageExceeded);
+
+        // 3. Mint LP Tokens
+        let pool_bump = [ctx.accounts.pool.bump];
+        let seeds: &[&[u8]] = &[b"multi_pool", ctx.accounts.pool.pool_id.as_ref(), &pool_bump];
+        let signer_seeds = &[seeds];
+        let cpi_accounts_mint = MintTo{
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            to: ctx.accounts.user_lp_token_account.to_account_info(),
+            authority: ctx.accounts.pool.to_account_info(), // Pool signs
+        };
+        let cpi_context = CpiContext::new_with_signer(ctx.accounts.token_program.to_account_info(), cpi_accounts_mint, signer_seeds);
+        anchor_spl::token::mint_to(cpi_context, lp_amount)?;
+
+        // 4. Book the deposit into the recorded reserves
+        for (token, amount) in ctx.accounts.pool.tokens.iter_mut().zip(&received) {
+            token.reserve = token.reserve.checked_add(*amount).ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        emit!(MultiLiquidityAdded {
+            pool: ctx.accounts.pool.key(),
+            user: ctx.accounts.signer.key(),
+            amounts: received,
+            lp_amount,
+            reserves: ctx.accounts.pool.reserves(),
+            lp_supply: total_supply.checked_add(lp_amount).ok_or(ErrorCode::MathOverflow)?,
+        });
+        Ok(())
+    }
+
+    // Pro rata withdrawal of every token of a multi-asset pool, the minimums follow
+    // the pool's token order
+    pub fn remove_multi_liquidity<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ModifyMultiLiquidity<'info>>,
+        lp_amount: u64,
+        min_amounts: Vec<u64>,
+        deadline: Option<i64>
+    ) -> Result<()> {
+        check_deadline(deadline)?;
+        // Deliberately not gated on Amm::paused, a global pause is withdraw-only
+        require!(ctx.accounts.pool.status & POOL_WITHDRAWALS_DISABLED == 0, ErrorCode::WithdrawalsDisabled);
+        require!(min_amounts.len() == ctx.accounts.pool.tokens.len(), ErrorCode::InvalidTokenCount);
+
+        let token_accounts = multi::load_token_accounts(ctx.remaining_accounts, &ctx.accounts.pool, &ctx.accounts.signer.key())?;
+        let total_supply = ctx.accounts.lp_mint.supply;
+        let amounts = multi::calculate_withdraw(lp_amount, &ctx.accounts.pool.reserves(), total_supply)?;
+
+        // Checked before anything moves, on what the user receives after any transfer fee
+        for ((token, amount), min_amount) in token_accounts.iter().zip(&amounts).zip(&min_amounts) {
+            require!(token_utils::amount_received(&token.mint, *amount)? >= *min_amount, ErrorCode::SlippageExceeded);
+        }
+
+        let cpi_burn = Burn{
+            mint: ctx.accounts.lp_mint.to_account_info(),
+            from: ctx.accounts.user_lp_token_account.to_account_info(),
+            authority: ctx.accounts.signer.to_account_info(),
+        };
+        let cpi_accounts = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_burn);
+        anchor_spl::token::burn(cpi_accounts, lp_amount)?;
+
+        for (token, amount) in token_accounts.iter().zip(&amounts) {
+            token_utils::transfer_from_multi_pool(
+                &token.token_program,
+                &token.mint,
+                &token.vault,
+                &token.user_token_account,
+                &ctx.accounts.pool,
+                *amount
+            )?;
+        }
+
+        for (token, amount) in ctx.accounts.pool.tokens.iter_mut().zip(&amounts) {
+            token.reserve = token.reserve.checked_sub(*amount).ok_or(ErrorCode::MathOverflow)?;
+        }
+
+        emit!(MultiLiquidityRemoved {
+            pool: ctx.accounts.pool.key(),
+            user: ctx.accounts.signer.key(),
+            amounts,
+            lp_amount,
+            reserves: ctx.accounts.pool.reserves(),
+            lp_supply: total_supply - lp_amount,
+        });
+        Ok(())
+    }
+
+    // set_pool_status for multi-asset pools, same authority rules
+    pub fn set_multi_pool_status(ctx: Context<SetMultiPoolStatus>, status: u8) -> Result<()> {
+        let authority = ctx.accounts.authority.key();
+        let pool_info = &mut ctx.accounts.pool;
+        let old_status = pool_info.status;
+        check_pool_status_change(&ctx.accounts.amm, &authority, old_status, status)?;
+
+        pool_info.status = status;
+
+        emit!(PoolStatusUpdated {
+            pool: pool_info.key(),
+            authority,
+            old_status,
+            new_status: status,
+        });
+        Ok(())
+    }
+
+    // collect_protocol_fees for multi-asset pools. The remaining accounts are the
+    // usual per-token accounts (see multi.rs) with the fee recipient's token accounts.
+    pub fn collect_multi_protocol_fees<'info>(ctx: Context<'_, '_, 'info, 'info, CollectMultiProtocolFees<'info>>) -> Result<()> {
+        let token_accounts = multi::load_token_accounts(ctx.remaining_accounts, &ctx.accounts.pool, &ctx.accounts.amm.fee_recipient)?;
+        let amounts: Vec<u64> = ctx.accounts.pool.tokens.iter().map(|token| token.protocol_fees).collect();
+
+        for (token, amount) in token_accounts.iter().zip(&amounts) {
+            if *amount > 0 {
+                token_utils::transfer_from_multi_pool(
+                    &token.token_program,
+                    &token.mint,
+                    &token.vault,
+                    &token.user_token_account,
+                    &ctx.accounts.pool,
+                    *amount
+                )?;
+            }
+        }
+
+        for token in ctx.accounts.pool.tokens.iter_mut() {
+            token.protocol_fees = 0;
+        }
+
+        emit!(MultiProtocolFeesCollected {
+            pool: ctx.accounts.pool.key(),
+            fee_recipient: ctx.accounts.amm.fee_recipient,
+            amounts,
+        });
+        Ok(())
+    }
+
+    // Swaps between any two tokens of a multi-asset pool, priced on their weights
+    pub fn multi_swap(
+        ctx: Context<MultiSwap>,
+        amount_in: u64,
+        min_amount_out: u64,
+        deadline: Option<i64>
+    ) -> Result<()> {
+        check_deadline(deadline)?;
+        require!(!ctx.accounts.amm.paused, ErrorCode::ProgramPaused);
+        require!(ctx.accounts.pool.status & POOL_SWAPS_DISABLED == 0, ErrorCode::SwapsDisabled);
+
+        // The pool constraint has already checked both mints are in the pool
+        let index_in = ctx.accounts.pool.token_index(&ctx.accounts.input_mint.key()).ok_or(ErrorCode::InvalidMint)?;
+        let index_out = ctx.accounts.pool.token_index(&ctx.accounts.output_mint.key()).ok_or(ErrorCode::InvalidMint)?;
+        let token_in = ctx.accounts.pool.tokens[index_in];
+        let token_out = ctx.accounts.pool.tokens[index_out];
+
+        // 1. Transfer Input (User -> Pool), priced on what the vault actually gained
+        let actual_amount_in = token_utils::deposit_to_vault(
+            &ctx.accounts.input_token_program,
+            &ctx.accounts.input_mint,
+            &ctx.accounts.user_token_account_in,
+            &mut ctx.accounts.pool_token_account_in,
+            &ctx.accounts.signer,
+            amount_in
+        )?;
+
+        // 2. Calculate Output Amount, the fee stays in the vault like in swap
+        let amount_out = weighted::calculate_output(
+            actual_amount_in,
+            token_in.reserve,
+            token_out.reserve,
+            token_in.weight,
+            token_out.weight,
+            ctx.accounts.amm.fee
+        ).ok_or(ErrorCode::MathOverflow)?;
+
+        // 3. Check Slippage, on what the user receives after any transfer fee
+        let amount_received = token_utils::amount_received(&ctx.accounts.output_mint, amount_out)?;
+        require!(amount_received >= min_amount_out, ErrorCode::SlippageExceeded);
+
+        // 4. Transfer Output (Pool -> User)
+        token_utils::transfer_from_multi_pool(
+            &ctx.accounts.output_token_program,
+            &ctx.accounts.output_mint,
+            &ctx.accounts.pool_token_account_out,
+            &ctx.accounts.user_token_account_out,
+            &ctx.accounts.pool,
+            amount_out
+        )?;
+
+        // 5. Set aside the protocol's share of the fee and book the trade
+        let protocol_fee = calculate_protocol_fee(
+            actual_amount_in,
+            ctx.accounts.amm.fee,
+            ctx.accounts.amm.protocol_fee_share
+        ).ok_or(ErrorCode::MathOverflow)?;
+        let pool_info = &mut ctx.accounts.pool;
+        pool_info.book_swap(index_in, index_out, actual_amount_in, amount_out, protocol_fee)?;
+
+        emit!(MultiSwapped {
+            pool: pool_info.key(),
+            user: ctx.accounts.signer.key(),
+            input_mint: token_in.mint,
+            output_mint: token_out.mint,
+            amount_in: actual_amount_in,
+            amount_out,
+            protocol_fee,
+            reserve_in: pool_info.tokens[index_in].reserve,
+            reserve_out: pool_info.tokens[index_out].reserve,
+        });
+        Ok(())
+    }
+}
+
+// The swap fee is capped at MAX_FEE, the protocol may take up to all of it
+fn check_fees(fee: u16, protocol_fee_share: u16) -> Result<()> {
+    require!(fee <= MAX_FEE, ErrorCode::InvalidFee);
+    require!(protocol_fee_share as u64 <= FEE_DENOMINATOR, ErrorCode::InvalidFee);
+    Ok(())
+}
+
+// Who may move a pool from old_status to status: the admin anything, the guardian
+// only disabling swaps or deposits. Shared by every pool type's status instruction.
+fn check_pool_status_change(amm: &Amm, authority: &Pubkey, old_status: u8, status: u8) -> Result<()> {
+    require!(status & !POOL_STATUS_MASK == 0, ErrorCode::InvalidPoolStatus);
+    if *authority != amm.user {
+        require!(*authority == amm.guardian, ErrorCode::Unauthorized);
+        let only_disables = status & old_status == old_status;
+        let touches_withdrawals = (status ^ old_status) & POOL_WITHDRAWALS_DISABLED != 0;
+        require!(only_disables && !touches_withdrawals, ErrorCode::Unauthorized);
+    }
+    Ok(())
+}
+
+// Rejects a transaction that lands after the user's deadline (unix timestamp),
+// so a signed swap or deposit cannot be held back and executed later
+fn check_deadline(deadline: Option<i64>) -> Result<()> {
+    if let Some(deadline) = deadline {
+        require!(Clock::get()?.unix_timestamp <= deadline, ErrorCode::TransactionExpired);
+    }
+    Ok(())
+}
+
+// Helper function for Withdrawals
+// Each reserve is paid out in proportion to lp_amount / total_supply, rounded down.
+// Burning the whole supply pays out both reserves exactly.
+fn calculate_withdraw_amounts(lp_amount: u64, reserve_a: u64, reserve_b: u64, total_supply: u64) -> Result<(u64, u64)> {
+    require!(total_supply > 0, ErrorCode::ZeroLpSupply);
+    require!(lp_amount > 0, ErrorCode::ZeroAmount);
+    require!(lp_amount <= total_supply, ErrorCode::InsufficientBalance);
+
+    let share = |reserve: u64| -> Result<u64> {
+        let amount = math::mul_div_floor(reserve as u128, lp_amount as u128, total_supply as u128)
+            .and_then(math::to_u64)
+            .ok_or(ErrorCode::MathOverflow)?;
+        Ok(amount)
+    };
+    let (amount_a, amount_b) = (share(reserve_a)?, share(reserve_b)?);
+
+    // Dust burns would destroy LP for nothing
+    require!(amount_a > 0 || amount_b > 0, ErrorCode::ZeroAmount);
+    Ok((amount_a, amount_b))
+}
+
+// Helper function for Proportional Deposits
+// Picks the largest (amount_a, amount_b) within the caller's maximums that keeps the
+// pool ratio, then mints min(a * supply / reserve_a, b * supply / reserve_b) LP.
+// The supply never drops below MINIMUM_LIQUIDITY, so there is no empty-pool case.
+fn calculate_liquidity_deposit(
+    max_amount_a: u64,
+    max_amount_b: u64,
+    reserve_a: u64,
+    reserve_b: u64,
+    total_supply: u64,
+) -> Option<(u64, u64, u64)> {
+    // Same as Uniswap v2's router: try to use all of A, fall back to all of B
+    let amount_b_optimal = quote(max_amount_a, reserve_a, reserve_b)?;
+    let (amount_a, amount_b) = if amount_b_optimal <= max_amount_b {
+        (max_amount_a, amount_b_optimal)
+    } else {
+        let amount_a_optimal = quote(max_amount_b, reserve_b, reserve_a)?;
+        if amount_a_optimal > max_amount_a {
+            return None;
+        }
+        (amount_a_optimal, max_amount_b)
+    };
+
+    let lp_amount = calculate_lp_for_deposit(amount_a, amount_b, reserve_a, reserve_b, total_supply)?;
+    Some((amount_a, amount_b, lp_amount))
+}
+
+// LP = min(a * supply / reserve_a, b * supply / reserve_b)
+// Rounds down so a deposit can never claim more than it brought in
+fn calculate_lp_for_deposit(amount_a: u64, amount_b: u64, reserve_a: u64, reserve_b: u64, total_supply: u64) -> Option<u64> {
+    let supply = total_supply as u128;
+    let lp_from_a = math::mul_div_floor(amount_a as u128, supply, reserve_a as u128)?;
+    let lp_from_b = math::mul_div_floor(amount_b as u128, supply, reserve_b as u128)?;
+    math::to_u64(lp_from_a.min(lp_from_b))
+}
+
+// amount_b = amount_a * reserve_b / reserve_a
+fn quote(amount_a: u64, reserve_a: u64, reserve_b: u64) -> Option<u64> {
+    math::to_u64(math::mul_div_floor(amount_a as u128, reserve_b as u128, reserve_a as u128)?)
+}
+
+// Helper function for Single-Sided Deposits
+// Amount of the input to swap so the leftover and the swap output are in the
+// post-swap pool ratio. With f = fee, r = reserve_in and a = amount_in:
+//   s = (sqrt(r^2 (2 - f)^2 + 4 (1 - f) a r) - r (2 - f)) / (2 (1 - f))
+// Scaled by F = 10_000 and g = F - fee_bps to stay in integers:
+//   s = (sqrt(r (r (F + g)^2 + 4 g F a)) - r (F + g)) / (2 g)
+fn calculate_optimal_swap_amount(amount_in: u64, reserve_in: u64, fee_bps: u16) -> Option<u64> {
+    use math::U256;
+
+    let denominator = FEE_DENOMINATOR;
+    let fee_multiplier = denominator.checked_sub(fee_bps as u64)?;
+    if fee_multiplier == 0 {
+        return None;
+    }
+
+    let reserve = U256::from(reserve_in);
+    let sum = U256::from(denominator + fee_multiplier);
+    let inner = reserve * sum * sum + U256::from(4 * fee_multiplier * denominator) * U256::from(amount_in);
+    let root = (reserve * inner).integer_sqrt();
+
+    let swap_amount = root.checked_sub(reserve * sum)? / U256::from(2 * fee_multiplier);
+    let swap_amount = math::to_u64(math::u256_to_u128(swap_amount)?)?;
+    Some(swap_amount.min(amount_in))
+}
+
+// Helper function for the Protocol Fee Switch
+// Swap_Fee = Input - Input_After_Fee (same rounding as calculate_amm_output)
+// Protocol_Fee = Swap_Fee * Protocol_Share_Bps / 10_000, the rest stays with LPs
+fn calculate_protocol_fee(amount_in: u64, fee_bps: u16, protocol_fee_share: u16) -> Option<u64> {
+    let amount_in_after_fee = amount_after_fee(amount_in, fee_bps)?;
+    let swap_fee = (amount_in as u128).checked_sub(amount_in_after_fee)?;
+    calculate_protocol_share(math::to_u64(swap_fee)?, protocol_fee_share)
+}
+
+// Fee * Protocol_Share_Bps / 10_000, rounded down in favour of the LPs
+fn calculate_protocol_share(fee_amount: u64, protocol_fee_share: u16) -> Option<u64> {
+    let protocol_fee = math::mul_div_floor(fee_amount as u128, protocol_fee_share as u128, FEE_DENOMINATOR as u128)?;
+    math::to_u64(protocol_fee)
+}
+
+// Input * (10_000 - Fee_Bps) / 10_000, rounded down so the fee is rounded up
+fn amount_after_fee(amount_in: u64, fee_bps: u16) -> Option<u128> {
+    let fee_multiplier = FEE_DENOMINATOR.checked_sub(fee_bps as u64)?;
+    math::mul_div_floor(amount_in as u128, fee_multiplier as u128, FEE_DENOMINATOR as u128)
+}
+
+// Helper function for Exact Output Swaps (inverse of calculate_amm_output)
+// Input_After_Fee = ceil(Reserve_In * Output / (Reserve_Out - Output))
+// Input = ceil(Input_After_Fee * 10_000 / (10_000 - Fee_Bps))
+// Both steps round up, so calculate_amm_output(Input) >= Output always holds
+fn calculate_amm_input(amount_out: u64, reserve_in: u64, reserve_out: u64, fee_bps: u16) -> Option<u64> {
+    let remaining_out = reserve_out.checked_sub(amount_out)?;
+    if remaining_out == 0 {
+        return None;
+    }
+
+    let amount_in_after_fee = math::mul_div_ceil(reserve_in as u128, amount_out as u128, remaining_out as u128)?;
+    let fee_multiplier = FEE_DENOMINATOR.checked_sub(fee_bps as u64)?;
+    let amount_in = math::mul_div_ceil(amount_in_after_fee, FEE_DENOMINATOR as u128, fee_multiplier as u128)?;
+    math::to_u64(amount_in)
+}
+
+// Helper function for Constant Product Math
+// Input_After_Fee = Input * (10_000 - Fee_Bps) / 10_000
+// Output = (Input_After_Fee * Reserve_Out) / (Reserve_In + Input_After_Fee)
+fn calculate_amm_output(amount_in: u64, reserve_in: u64, reserve_out: u64, fee_bps: u16) -> Option<u64> {
+    // Round the fee up (input after fee down) and the output down so the pool never undercharges
+    let amount_in = amount_after_fee(amount_in, fee_bps)?;
+    let denominator = (reserve_in as u128).checked_add(amount_in)?;
+
+    let amount_out = math::mul_div_floor(amount_in, reserve_out as u128, denominator)?;
+    math::to_u64(amount_out)
+}
+
+// Helper function for Curve Dispatch
+// Output of a swap selling mint_in on the pool's curve at time `now` (A may be ramping)
+fn calculate_swap_output(pool: &Pool, mint_in: &Pubkey, amount_in: u64, reserve_in: u64, reserve_out: u64, fee_bps: u16, now: i64) -> Option<u64> {
+    match pool.curve_type {
+        CurveType::ConstantProduct => calculate_amm_output(amount_in, reserve_in, reserve_out, fee_bps),
+        CurveType::StableSwap => stable::calculate_output(amount_in, reserve_in, reserve_out, pool.amp_at(now), fee_bps),
+        CurveType::Weighted => {
+            let (weight_in, weight_out) = pool.weights_for(mint_in);
+            weighted::calculate_output(amount_in, reserve_in, reserve_out, weight_in, weight_out, fee_bps)
+        }
+    }
+}
+
+// Input needed for `amount_out` on the pool's curve, see calculate_swap_output
+fn calculate_swap_input(pool: &Pool, mint_in: &Pubkey, amount_out: u64, reserve_in: u64, reserve_out: u64, fee_bps: u16, now: i64) -> Option<u64> {
+    match pool.curve_type {
+        CurveType::ConstantProduct => calculate_amm_input(amount_out, reserve_in, reserve_out, fee_bps),
+        CurveType::StableSwap => stable::calculate_input(amount_out, reserve_in, reserve_out, pool.amp_at(now), fee_bps),
+        CurveType::Weighted => {
+            let (weight_in, weight_out) = pool.weights_for(mint_in);
+            weighted::calculate_input(amount_out, reserve_in, reserve_out, weight_in, weight_out, fee_bps)
+        }
+    }
+}
+
+#[derive(Accounts)]
+pub struct Initialize<'info>{
+    #[account(mut)]
+    pub signer: Signer<'info>,
+    #[account(
+        init, // Changed to init (init_if_needed is rare for global configs)
+        payer = signer,
+        space = 8 + Amm::INIT_SPACE,
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateAmm<'info>{
+    pub user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"AMM"],
+        bump,
+        has_one = user @ ErrorCode::Unauthorized
+    )]
+    pub amm: Account<'info, Amm>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info>{
+    pub pending_user: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"AMM"],
+        bump,
+        has_one = pending_user @ ErrorCode::Unauthorized
+    )]
+    pub amm: Account<'info, Amm>,
+}
+
+#[derive(Accounts)]
+pub struct SetPaused<'info>{
+    pub authority: Signer<'info>,
+    #[account(
+        mut,
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+}
+
+#[derive(Accounts)]
+pub struct SetPoolStatus<'info>{
+    pub authority: Signer<'info>,
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct SetClmmPoolStatus<'info>{
+    pub authority: Signer<'info>,
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+    #[account(
+        mut,
+        seeds = [b"clmm_pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), pool.tick_spacing.to_le_bytes().as_ref()],
+        bump = pool.bump,
+    )]
+    pub pool: Box<Account<'info, ClmmPool>>,
+}
+
+#[derive(Accounts)]
+pub struct UpdatePoolCurve<'info>{
+    pub user: Signer<'info>,
+    #[account(
+        seeds = [b"AMM"],
+        bump,
+        has_one = user @ ErrorCode::Unauthorized
+    )]
+    pub amm: Account<'info, Amm>,
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+}
+
+#[derive(Accounts)]
+pub struct CreatePool<'info>{
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    // Either token program, see token_utils::validate_mint for the extensions allowed
+    #[account(mint::token_program = token_program_a)]
+    pub mint_a: Box<InterfaceAccount<'info, Mint>>,
+    // Pools are keyed by the sorted pair, so A/B and B/A resolve to the same pool
+    #[account(
+        mint::token_program = token_program_b,
+        constraint = mint_a.key() != mint_b.key() @ ErrorCode::IdenticalMints,
+        constraint = mint_a.key() < mint_b.key() @ ErrorCode::InvalidMintOrder,
+    )]
+    pub mint_b: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint_a,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program_a,
+    )]
+    pub user_token_account_a: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint_b,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program_b,
+    )]
+    pub user_token_account_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init,
+        payer = signer,
+        space = 8 + Pool::INIT_SPACE,
+        seeds = [b"pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
+        bump 
+    )]
+    pub pool: Account<'info, Pool>,
+
+    // Price history for TWAP consumers, see oracle.rs
+    #[account(
+        init,
+        payer = signer,
+        space = 8 + std::mem::size_of::<Observations>(),
+        seeds = [b"observations", pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+
+    #[account(
+        init,
+        payer = signer,
+        associated_token::mint = mint_a,
+        associated_token::authority = pool,
+        associated_token::token_program = token_program_a,
+    )]
+    pub pool_token_account_a: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init,
+        payer = signer,
+        associated_token::mint = mint_b,
+        associated_token::authority = pool,
+        associated_token::token_program = token_program_b,
+    )]
+    pub pool_token_account_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init,
+        payer = signer, 
+        seeds = [b"lp_mint", mint_a.key().as_ref(), mint_b.key().as_ref()],
+        bump,
+        mint::decimals = 9,
+        mint::authority = pool, // <--- CORRECT AUTHORITY
+        mint::freeze_authority = pool, // <--- CORRECT AUTHORITY
+    )]
+    pub lp_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        seeds = [
+            b"metadata",
+            metadata_program.key().as_ref(),
+            lp_mint.key().as_ref()
+        ],
+        bump,
+        seeds::program = metadata_program.key()
+    )]
+    /// CHECK: Metaplex check
+    pub metadata_account: UncheckedAccount<'info>,
+
+    #[account(
+        init,
+        payer = signer,
+        associated_token::mint = lp_mint,
+        associated_token::authority = signer,
+    )]
+    pub user_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    // Holds MINIMUM_LIQUIDITY forever, the pool never signs a transfer out of it
+    #[account(
+        init,
+        payer = signer,
+        associated_token::mint = lp_mint,
+        associated_token::authority = pool,
+    )]
+    pub locked_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program_a: Interface<'info, TokenInterface>,
+    pub token_program_b: Interface<'info, TokenInterface>,
+    // LP tokens always live in the legacy token program
+    pub token_program:Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub metadata_program: Program<'info, Metadata>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct AddLiquidity<'info>{
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    #[account(mut, mint::token_program = token_program_a)]
+    pub mint_a: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(mut, mint::token_program = token_program_b)]
+    pub mint_b: Box<InterfaceAccount<'info, Mint>>,
+
+    // mint_a / mint_b may be passed in either order, the pool is always the canonical one
+    #[account(
+        mut, // Must be mutable to mint tokens (if storing state)
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+        has_one = lp_mint,
+        constraint = pool.is_pair(&mint_a.key(), &mint_b.key()) @ ErrorCode::InvalidMint
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"observations", pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint_a,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program_a,
+    )]
+    pub user_token_account_a: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        associated_token::mint = mint_b,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program_b,
+    )]
+    pub user_token_account_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut, // No init_if_needed, it must exist
+        address = pool.vault_for(&mint_a.key()) @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_a: Box<InterfaceAccount<'info, TokenAccount>>,
+    
+    #[account(mut)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        address = pool.vault_for(&mint_b.key()) @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        associated_token::mint = lp_mint,
+        associated_token::authority = signer,
+    )]
+    pub user_lp_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program_a: Interface<'info, TokenInterface>,
+    pub token_program_b: Interface<'info, TokenInterface>,
+    // LP tokens always live in the legacy token program
+    pub token_program:Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveLiquidity<'info>{
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(mut)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = lp_mint,
+        associated_token::authority = signer,
+    )]
+    pub lp_mint_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    ///unchecked account
+    #[account(mut, mint::token_program = token_program_a)]
+    pub token_a_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    ///unchecked account
+    #[account(mut, mint::token_program = token_program_b)]
+    pub token_b_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    // token_a_mint / token_b_mint may be passed in either order
+    #[account(
+        mut, 
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+        has_one = lp_mint,
+        constraint = pool.is_pair(&token_a_mint.key(), &token_b_mint.key()) @ ErrorCode::InvalidMint
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"observations", pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+
+    #[account(
+        mut,
+        address = pool.vault_for(&token_a_mint.key()) @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_for(&token_b_mint.key()) @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+
+    #[account(
+        mut,
+        associated_token::mint = token_a_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program_a,
+    )]
+    pub user_token_account_a: InterfaceAccount<'info, TokenAccount>,
+    
+    #[account(
+        mut,
+        associated_token::mint = token_b_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program_b,
+    )]
+    pub user_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program_a: Interface<'info, TokenInterface>,
+    pub token_program_b: Interface<'info, TokenInterface>,
+    // LP tokens always live in the legacy token program
+    pub token_program: Program<'info,Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info,System>,
+}
+
+#[derive(Accounts)]
+pub struct AddLiquiditySingle<'info>{
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+        has_one = lp_mint,
+        constraint = input_mint.key() == pool.mint_a || input_mint.key() == pool.mint_b @ ErrorCode::InvalidMint
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"observations", pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+
+    #[account(mut)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    // The token the user is depositing, must be one of the pool's mints
+    #[account(mint::token_program = input_token_program)]
+    pub input_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        address = pool.vault_a @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = input_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = input_token_program,
+    )]
+    pub user_token_account_in: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = lp_mint,
+        associated_token::authority = signer,
+    )]
+    pub user_lp_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    pub input_token_program: Interface<'info, TokenInterface>,
+    // LP tokens always live in the legacy token program
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveLiquidityOneToken<'info>{
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+        has_one = lp_mint,
+        constraint = output_mint.key() == pool.mint_a || output_mint.key() == pool.mint_b @ ErrorCode::InvalidMint
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"observations", pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+
+    #[account(mut)]
+    pub lp_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        associated_token::mint = lp_mint,
+        associated_token::authority = signer,
+    )]
+    pub lp_mint_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    // The token the user wants to receive, must be one of the pool's mints
+    #[account(mint::token_program = output_token_program)]
+    pub output_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        mut,
+        address = pool.vault_a @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = output_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = output_token_program,
+    )]
+    pub user_token_account_out: InterfaceAccount<'info, TokenAccount>,
+
+    pub output_token_program: Interface<'info, TokenInterface>,
+    // LP tokens always live in the legacy token program
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct Swap<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    // input_mint -> output_mint sets the direction, both must be this pool's mints
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+        constraint = pool.is_pair(&input_mint.key(), &output_mint.key()) @ ErrorCode::InvalidMint
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"observations", pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+
+    // The token the user is selling
+    #[account(mint::token_program = input_token_program)]
+    pub input_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    // The token the user is buying
+    #[account(mint::token_program = output_token_program)]
+    pub output_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        address = pool.vault_for(&input_mint.key()) @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_in: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        address = pool.vault_for(&output_mint.key()) @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_out: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        associated_token::mint = input_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = input_token_program,
+    )]
+    pub user_token_account_in: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        associated_token::mint = output_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = output_token_program,
+    )]
+    pub user_token_account_out: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub input_token_program: Interface<'info, TokenInterface>,
+    pub output_token_program: Interface<'info, TokenInterface>,
+}
+
+// Books a trade into the recorded reserves, with the protocol's share of the
+// swap fee moved out of the LP reserves. Returns that share.
+fn book_swap(
+    pool_info: &mut Pool,
+    observations: &AccountLoader<Observations>,
+    amm: &Amm,
+    is_a_to_b: bool,
+    amount_in: u64,
+    amount_out: u64
+) -> Result<u64> {
+    let protocol_fee = calculate_protocol_fee(
+        amount_in,
+        amm.fee,
+        amm.protocol_fee_share
+    ).ok_or(ErrorCode::MathOverflow)?;
+
+    // Price accumulators first, they integrate the reserves being replaced
+    oracle::update_with_clock(pool_info, observations)?;
+
+    let (reserve_in, reserve_out) = if is_a_to_b {
+        (pool_info.reserve_a, pool_info.reserve_b)
+    } else {
+        (pool_info.reserve_b, pool_info.reserve_a)
+    };
+    let new_reserve_in = reserve_in
+        .checked_add(amount_in)
+        .and_then(|reserve| reserve.checked_sub(protocol_fee))
+        .ok_or(ErrorCode::MathOverflow)?;
+    let new_reserve_out = reserve_out.checked_sub(amount_out).ok_or(ErrorCode::MathOverflow)?;
+
+    if is_a_to_b {
+        pool_info.protocol_fees_a = pool_info.protocol_fees_a.checked_add(protocol_fee).ok_or(ErrorCode::MathOverflow)?;
+        pool_info.reserve_a = new_reserve_in;
+        pool_info.reserve_b = new_reserve_out;
+    } else {
+        pool_info.protocol_fees_b = pool_info.protocol_fees_b.checked_add(protocol_fee).ok_or(ErrorCode::MathOverflow)?;
+        pool_info.reserve_b = new_reserve_in;
+        pool_info.reserve_a = new_reserve_out;
+    }
+    Ok(protocol_fee)
+}
+
+impl Swap<'_> {
+    // Direction in pool order, input_mint is checked to be one of the pair
+    fn is_a_to_b(&self) -> bool {
+        self.input_mint.key() == self.pool.mint_a
+    }
+
+    // User -> Pool, the user signs. Returns what the vault actually received.
+    fn transfer_in(&mut self, amount: u64) -> Result<u64> {
+        token_utils::deposit_to_vault(
+            &self.input_token_program,
+            &self.input_mint,
+            &self.user_token_account_in,
+            &mut self.pool_token_account_in,
+            &self.signer,
+            amount
+        )
+    }
+
+    // Pool -> User, the pool PDA signs
+    fn transfer_out(&self, amount: u64) -> Result<()> {
+        token_utils::transfer_from_pool(
+            &self.output_token_program,
+            &self.output_mint,
+            &self.pool_token_account_out,
+            &self.user_token_account_out,
+            &self.pool,
+            amount
+        )
+    }
+
+    fn update_pool(&mut self, amount_in: u64, amount_out: u64) -> Result<u64> {
+        let is_a_to_b = self.is_a_to_b();
+        book_swap(&mut self.pool, &self.observations, &self.amm, is_a_to_b, amount_in, amount_out)
+    }
+
+    // Amounts are what the vault received and sent, reserves are after the trade
+    fn emit_swapped(&self, amount_in: u64, amount_out: u64, protocol_fee: u64) {
+        emit!(Swapped {
+            pool: self.pool.key(),
+            user: self.signer.key(),
+            input_mint: self.input_mint.key(),
+            amount_in,
+            amount_out,
+            protocol_fee,
+            reserve_a: self.pool.reserve_a,
+            reserve_b: self.pool.reserve_b,
+        });
+    }
+}
+
+#[derive(Accounts)]
+pub struct SwapRoute<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    #[account(mint::token_program = input_token_program)]
+    pub input_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(mint::token_program = output_token_program)]
+    pub output_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        associated_token::mint = input_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = input_token_program,
+    )]
+    pub user_token_account_in: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        associated_token::mint = output_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = output_token_program,
+    )]
+    pub user_token_account_out: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub input_token_program: Interface<'info, TokenInterface>,
+    pub output_token_program: Interface<'info, TokenInterface>,
+    // Followed by route::ACCOUNTS_PER_HOP remaining accounts per hop
+}
+
+#[derive(Accounts)]
+pub struct CollectProtocolFees<'info> {
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump,
+        has_one = user @ ErrorCode::Unauthorized
+    )]
+    pub amm: Account<'info, Amm>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        address = pool.mint_a @ ErrorCode::InvalidMint,
+        mint::token_program = token_program_a,
+    )]
+    pub mint_a: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        address = pool.mint_b @ ErrorCode::InvalidMint,
+        mint::token_program = token_program_b,
+    )]
+    pub mint_b: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        address = pool.vault_a @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_a,
+        token::authority = amm.fee_recipient,
+        token::token_program = token_program_a,
+    )]
+    pub treasury_token_account_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_b,
+        token::authority = amm.fee_recipient,
+        token::token_program = token_program_b,
+    )]
+    pub treasury_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program_a: Interface<'info, TokenInterface>,
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct CollectClmmProtocolFees<'info> {
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump,
+        has_one = user @ ErrorCode::Unauthorized
+    )]
+    pub amm: Account<'info, Amm>,
+
+    #[account(
+        mut,
+        seeds = [b"clmm_pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), pool.tick_spacing.to_le_bytes().as_ref()],
+        bump = pool.bump,
+    )]
+    pub pool: Box<Account<'info, ClmmPool>>,
+
+    #[account(
+        address = pool.mint_a @ ErrorCode::InvalidMint,
+        mint::token_program = token_program_a,
+    )]
+    pub mint_a: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        address = pool.mint_b @ ErrorCode::InvalidMint,
+        mint::token_program = token_program_b,
+    )]
+    pub mint_b: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        address = pool.vault_a @ ErrorCode::InvalidVault,
     )]
-    pub lp_mint: Account<'info, Mint>,
+    pub pool_token_account_a: Box<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(
         mut,
-        seeds = [
-            b"metadata",
-            metadata_program.key().as_ref(),
-            lp_mint.key().as_ref()
-        ],
-        bump,
-        seeds::program = metadata_program.key()
+        address = pool.vault_b @ ErrorCode::InvalidVault,
     )]
-    /// CHECK: Metaplex check
-    pub metadata_account: UncheckedAccount<'info>,
+    pub pool_token_account_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_a,
+        token::authority = amm.fee_recipient,
+        token::token_program = token_program_a,
+    )]
+    pub treasury_token_account_a: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_b,
+        token::authority = amm.fee_recipient,
+        token::token_program = token_program_b,
+    )]
+    pub treasury_token_account_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program_a: Interface<'info, TokenInterface>,
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct SyncReserves<'info> {
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    #[account(
+        mut,
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        mut,
+        seeds = [b"observations", pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+
+    #[account(address = pool.vault_a @ ErrorCode::InvalidVault)]
+    pub pool_token_account_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.vault_b @ ErrorCode::InvalidVault)]
+    pub pool_token_account_b: InterfaceAccount<'info, TokenAccount>,
+}
+
+#[derive(Accounts)]
+pub struct Skim<'info> {
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    #[account(
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        address = pool.mint_a @ ErrorCode::InvalidMint,
+        mint::token_program = token_program_a,
+    )]
+    pub mint_a: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        address = pool.mint_b @ ErrorCode::InvalidMint,
+        mint::token_program = token_program_b,
+    )]
+    pub mint_b: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        address = pool.vault_a @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        address = pool.vault_b @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+    // Any token accounts of the right mints, skim is permissionless like in Uniswap v2
+    #[account(
+        mut,
+        token::mint = pool.mint_a,
+        token::token_program = token_program_a,
+    )]
+    pub recipient_token_account_a: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        token::mint = pool.mint_b,
+        token::token_program = token_program_b,
+    )]
+    pub recipient_token_account_b: InterfaceAccount<'info, TokenAccount>,
+
+    pub token_program_a: Interface<'info, TokenInterface>,
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct Observe<'info> {
+    #[account(
+        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
+        bump = pool.bump,
+    )]
+    pub pool: Account<'info, Pool>,
+
+    #[account(
+        seeds = [b"observations", pool.key().as_ref()],
+        bump
+    )]
+    pub observations: AccountLoader<'info, Observations>,
+}
+
+#[derive(Accounts)]
+#[instruction(tick_spacing: u16)]
+pub struct CreateClmmPool<'info>{
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    #[account(mint::token_program = token_program_a)]
+    pub mint_a: Box<InterfaceAccount<'info, Mint>>,
+    // Sorted like Pool, so each (pair, tick_spacing) has exactly one pool
+    #[account(
+        mint::token_program = token_program_b,
+        constraint = mint_a.key() != mint_b.key() @ ErrorCode::IdenticalMints,
+        constraint = mint_a.key() < mint_b.key() @ ErrorCode::InvalidMintOrder,
+    )]
+    pub mint_b: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
         init,
         payer = signer,
-        associated_token::mint = lp_mint,
-        associated_token::authority = signer,
+        space = 8 + ClmmPool::INIT_SPACE,
+        seeds = [b"clmm_pool", mint_a.key().as_ref(), mint_b.key().as_ref(), tick_spacing.to_le_bytes().as_ref()],
+        bump
     )]
-    pub user_lp_token_account: Account<'info, TokenAccount>,
+    pub pool: Box<Account<'info, ClmmPool>>,
 
-    pub token_program:Program<'info, Token>,
+    #[account(
+        init,
+        payer = signer,
+        associated_token::mint = mint_a,
+        associated_token::authority = pool,
+        associated_token::token_program = token_program_a,
+    )]
+    pub pool_token_account_a: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        init,
+        payer = signer,
+        associated_token::mint = mint_b,
+        associated_token::authority = pool,
+        associated_token::token_program = token_program_b,
+    )]
+    pub pool_token_account_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program_a: Interface<'info, TokenInterface>,
+    pub token_program_b: Interface<'info, TokenInterface>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
-    pub metadata_program: Program<'info, Metadata>,
-    pub rent: Sysvar<'info, Rent>,
 }
 
 #[derive(Accounts)]
-pub struct AddLiquidity<'info>{
+#[instruction(start_tick_index: i32)]
+pub struct InitializeTickArray<'info>{
     #[account(mut)]
     pub signer: Signer<'info>,
 
-    #[account(mut)]
-    pub mint_a: Account<'info,Mint>,
+    pub pool: Account<'info, ClmmPool>,
+
+    #[account(
+        init,
+        payer = signer,
+        space = 8 + std::mem::size_of::<TickArray>(),
+        seeds = [b"tick_array", pool.key().as_ref(), start_tick_index.to_le_bytes().as_ref()],
+        bump
+    )]
+    pub tick_array: AccountLoader<'info, TickArray>,
+
+    pub system_program: Program<'info, System>,
+}
 
+#[derive(Accounts)]
+pub struct OpenPosition<'info>{
     #[account(mut)]
-    pub mint_b: Account<'info,Mint>,
+    pub signer: Signer<'info>,
 
     #[account(
-        mut, // Must be mutable to mint tokens (if storing state)
-        seeds = [b"pool", mint_a.key().as_ref(), mint_b.key().as_ref()],
-        bump  
+        seeds = [b"AMM"],
+        bump
     )]
-    pub pool: Account<'info, Pool>,
+    pub amm: Account<'info, Amm>,
+
+    #[account(
+        seeds = [b"clmm_pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), pool.tick_spacing.to_le_bytes().as_ref()],
+        bump = pool.bump,
+    )]
+    pub pool: Box<Account<'info, ClmmPool>>,
+
+    #[account(
+        init,
+        payer = signer,
+        space = 8 + Position::INIT_SPACE,
+        seeds = [b"position", position_mint.key().as_ref()],
+        bump
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    // Fresh keypair, the NFT that represents the position
+    #[account(
+        init,
+        payer = signer,
+        mint::decimals = 0,
+        mint::authority = pool,
+    )]
+    pub position_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        init,
+        payer = signer,
+        associated_token::mint = position_mint,
+        associated_token::authority = signer,
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    // Position NFTs always live in the legacy token program
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+// Shared by increase_liquidity, decrease_liquidity and collect_fees. Whoever holds
+// the position NFT may modify the position.
+#[derive(Accounts)]
+pub struct ModifyPosition<'info>{
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
 
     #[account(
         mut,
-        associated_token::mint = mint_a,
-        associated_token::authority = signer
+        seeds = [b"clmm_pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), pool.tick_spacing.to_le_bytes().as_ref()],
+        bump = pool.bump,
     )]
-    pub user_token_account_a: Account<'info, TokenAccount>,
+    pub pool: Box<Account<'info, ClmmPool>>,
 
     #[account(
         mut,
-        associated_token::mint = mint_b,
+        seeds = [b"position", position.position_mint.as_ref()],
+        bump = position.bump,
+        has_one = pool,
+    )]
+    pub position: Box<Account<'info, Position>>,
+
+    #[account(
+        token::authority = signer,
+        constraint = position_token_account.mint == position.position_mint @ ErrorCode::Unauthorized,
+        constraint = position_token_account.amount == 1 @ ErrorCode::Unauthorized,
+    )]
+    pub position_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    // Arrays holding tick_lower and tick_upper, the same account if both are in one
+    #[account(mut)]
+    pub tick_array_lower: AccountLoader<'info, TickArray>,
+    #[account(mut)]
+    pub tick_array_upper: AccountLoader<'info, TickArray>,
+
+    #[account(address = pool.mint_a @ ErrorCode::InvalidMint, mint::token_program = token_program_a)]
+    pub token_mint_a: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(address = pool.mint_b @ ErrorCode::InvalidMint, mint::token_program = token_program_b)]
+    pub token_mint_b: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(mut, address = pool.vault_a @ ErrorCode::InvalidVault)]
+    pub pool_token_account_a: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut, address = pool.vault_b @ ErrorCode::InvalidVault)]
+    pub pool_token_account_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        associated_token::mint = token_mint_a,
         associated_token::authority = signer,
+        associated_token::token_program = token_program_a,
     )]
-    pub user_token_account_b: Account<'info,TokenAccount>,
+    pub user_token_account_a: Box<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(
-        mut, // No init_if_needed, it must exist
-        associated_token::mint = mint_a,
-        associated_token::authority = pool,
+        mut,
+        associated_token::mint = token_mint_b,
+        associated_token::authority = signer,
+        associated_token::token_program = token_program_b,
     )]
-    pub pool_token_account_a: Account<'info, TokenAccount>,
-    
+    pub user_token_account_b: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub token_program_a: Interface<'info, TokenInterface>,
+    pub token_program_b: Interface<'info, TokenInterface>,
+}
+
+impl ModifyPosition<'_> {
+    // Applies a liquidity change (0 just accrues fees), returns the token amounts
+    fn modify_position(&mut self, liquidity_delta: i128) -> Result<(u64, u64)> {
+        let pool_key = self.pool.key();
+        let tick_spacing = self.pool.tick_spacing;
+        let ticks = (self.position.tick_lower, self.position.tick_upper);
+        let pool_info = &mut self.pool;
+        let position = &mut self.position;
+        clmm::with_position_ticks(
+            &self.tick_array_lower,
+            &self.tick_array_upper,
+            &pool_key,
+            ticks,
+            tick_spacing,
+            |lower, upper| clmm::modify_position(pool_info, position, lower, upper, liquidity_delta)
+        )
+    }
+
+    // Pool -> User for both tokens, the pool PDA signs
+    fn transfer_out(&self, amount_a: u64, amount_b: u64) -> Result<()> {
+        if amount_a > 0 {
+            token_utils::transfer_from_clmm_pool(
+                &self.token_program_a,
+                &self.token_mint_a,
+                &self.pool_token_account_a,
+                &self.user_token_account_a,
+                &self.pool,
+                amount_a
+            )?;
+        }
+        if amount_b > 0 {
+            token_utils::transfer_from_clmm_pool(
+                &self.token_program_b,
+                &self.token_mint_b,
+                &self.pool_token_account_b,
+                &self.user_token_account_b,
+                &self.pool,
+                amount_b
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Accounts)]
+pub struct ClmmSwap<'info> {
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    // input_mint -> output_mint sets the direction, both must be this pool's mints
     #[account(
         mut,
-        seeds = [b"lp_mint", mint_a.key().as_ref(), mint_b.key().as_ref()],
+        seeds = [b"clmm_pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), pool.tick_spacing.to_le_bytes().as_ref()],
+        bump = pool.bump,
+        constraint = pool.is_pair(&input_mint.key(), &output_mint.key()) @ ErrorCode::InvalidMint
+    )]
+    pub pool: Box<Account<'info, ClmmPool>>,
+
+    #[account(mint::token_program = input_token_program)]
+    pub input_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(mint::token_program = output_token_program)]
+    pub output_mint: Box<InterfaceAccount<'info, Mint>>,
+
+    #[account(
+        mut,
+        address = pool.vault_for(&input_mint.key()) @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_in: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        address = pool.vault_for(&output_mint.key()) @ ErrorCode::InvalidVault,
+    )]
+    pub pool_token_account_out: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        associated_token::mint = input_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = input_token_program,
+    )]
+    pub user_token_account_in: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        associated_token::mint = output_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = output_token_program,
+    )]
+    pub user_token_account_out: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    pub input_token_program: Interface<'info, TokenInterface>,
+    pub output_token_program: Interface<'info, TokenInterface>,
+    // Followed by 1..=clmm::MAX_SWAP_TICK_ARRAYS tick arrays (mut) as remaining accounts
+}
+
+#[derive(Accounts)]
+#[instruction(pool_id: [u8; 32])]
+pub struct CreateMultiPool<'info>{
+    #[account(mut)]
+    pub signer: Signer<'info>,
+
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    // pool_id commits to the sorted mints and weights, see multi::pool_id
+    #[account(
+        init,
+        payer = signer,
+        space = 8 + MultiPool::INIT_SPACE,
+        seeds = [b"multi_pool", pool_id.as_ref()],
+        bump
+    )]
+    pub pool: Box<Account<'info, MultiPool>>,
+
+    #[account(
+        init,
+        payer = signer,
+        seeds = [b"multi_lp_mint", pool.key().as_ref()],
         bump,
+        mint::decimals = 9,
+        mint::authority = pool,
+        mint::freeze_authority = pool,
     )]
-    pub lp_mint: Account<'info, Mint>,
+    pub lp_mint: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
-        mut,
-        associated_token::mint = mint_b,
-        associated_token::authority = pool,
+        init,
+        payer = signer,
+        associated_token::mint = lp_mint,
+        associated_token::authority = signer,
     )]
-    pub pool_token_account_b: Account<'info,TokenAccount>,
+    pub user_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
 
+    // Holds MINIMUM_LIQUIDITY forever, the pool never signs a transfer out of it
     #[account(
-        mut,
+        init,
+        payer = signer,
         associated_token::mint = lp_mint,
-        associated_token::authority = signer,
+        associated_token::authority = pool,
     )]
-    pub user_lp_token_account: Account<'info, TokenAccount>,
+    pub locked_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    pub token_program:Program<'info, Token>,
+    // LP tokens always live in the legacy token program
+    pub token_program: Program<'info, Token>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
     pub rent: Sysvar<'info, Rent>,
+    // Followed by multi::ACCOUNTS_PER_TOKEN accounts per token as remaining accounts,
+    // the vaults are the pool's (not yet created) ATAs
 }
 
 #[derive(Accounts)]
-pub struct RemoveLiquidity<'info>{
+pub struct ModifyMultiLiquidity<'info>{
     #[account(mut)]
     pub signer: Signer<'info>,
 
-    ///unchecked account
-    #[account(mut)]
-    pub lp_mint: Account<'info, Mint>,
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
 
     #[account(
         mut,
-        associated_token::mint = lp_mint,
-        associated_token::authority = signer,
+        seeds = [b"multi_pool", pool.pool_id.as_ref()],
+        bump = pool.bump,
+        has_one = lp_mint,
     )]
-    pub lp_mint_token_account: Account<'info, TokenAccount>,
+    pub pool: Box<Account<'info, MultiPool>>,
 
-    ///unchecked account
-    #[account(mut)]
-    pub token_a_mint: Account<'info, Mint>,
-
-    ///unchecked account
     #[account(mut)]
-    pub token_b_mint: Account<'info, Mint>,
+    pub lp_mint: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
-        mut, 
-        seeds = [b"pool", token_a_mint.key().as_ref(), token_b_mint.key().as_ref()],
-        bump
+        mut,
+        associated_token::mint = lp_mint,
+        associated_token::authority = signer,
     )]
-    pub pool: Account<'info, Pool>,
+    pub user_lp_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+
+    // LP tokens always live in the legacy token program
+    pub token_program: Program<'info, Token>,
+    // Followed by multi::ACCOUNTS_PER_TOKEN accounts per pool token as remaining accounts
+}
 
+#[derive(Accounts)]
+pub struct SetMultiPoolStatus<'info>{
+    pub authority: Signer<'info>,
     #[account(
-        mut,
-        associated_token::mint = token_a_mint,
-        associated_token::authority = pool,
+        seeds = [b"AMM"],
+        bump
     )]
-    pub pool_token_account_a: Account<'info, TokenAccount>,
-
+    pub amm: Account<'info, Amm>,
     #[account(
         mut,
-        associated_token::mint = token_b_mint,
-        associated_token::authority = pool,
+        seeds = [b"multi_pool", pool.pool_id.as_ref()],
+        bump = pool.bump,
     )]
-    pub pool_token_account_b: Account<'info, TokenAccount>,
+    pub pool: Box<Account<'info, MultiPool>>,
+}
 
+#[derive(Accounts)]
+pub struct CollectMultiProtocolFees<'info> {
+    pub user: Signer<'info>,
 
     #[account(
-        mut,
-        associated_token::mint = token_a_mint,
-        associated_token::authority = signer,
+        seeds = [b"AMM"],
+        bump,
+        has_one = user @ ErrorCode::Unauthorized
     )]
-    pub user_token_account_a: Account<'info, TokenAccount>,
-    
+    pub amm: Account<'info, Amm>,
+
     #[account(
         mut,
-        associated_token::mint = token_b_mint,
-        associated_token::authority = signer,
+        seeds = [b"multi_pool", pool.pool_id.as_ref()],
+        bump = pool.bump,
     )]
-    pub user_token_account_b: Account<'info, TokenAccount>,
-
-    pub token_program: Program<'info,Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info,System>,
+    pub pool: Box<Account<'info, MultiPool>>,
+    // Followed by multi::ACCOUNTS_PER_TOKEN accounts per pool token as remaining accounts,
+    // with token accounts owned by Amm::fee_recipient as the destinations
 }
 
 #[derive(Accounts)]
-pub struct Swap<'info> {
+pub struct MultiSwap<'info> {
     #[account(mut)]
     pub signer: Signer<'info>,
 
+    #[account(
+        seeds = [b"AMM"],
+        bump
+    )]
+    pub amm: Account<'info, Amm>,
+
+    // input_mint -> output_mint sets the direction, both must be tokens of this pool
     #[account(
         mut,
-        seeds = [b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref()],
-        bump,
+        seeds = [b"multi_pool", pool.pool_id.as_ref()],
+        bump = pool.bump,
+        constraint = pool.is_pair(&input_mint.key(), &output_mint.key()) @ ErrorCode::InvalidMint
     )]
-    pub pool: Account<'info, Pool>,
+    pub pool: Box<Account<'info, MultiPool>>,
+
+    #[account(mint::token_program = input_token_program)]
+    pub input_mint: Box<InterfaceAccount<'info, Mint>>,
 
-    // This tells us WHICH token the user is selling
-    pub input_mint: Account<'info, Mint>,
+    #[account(mint::token_program = output_token_program)]
+    pub output_mint: Box<InterfaceAccount<'info, Mint>>,
 
     #[account(
         mut,
-        associated_token::mint = pool.mint_a,
-        associated_token::authority = pool
+        address = pool.vault_for(&input_mint.key()) @ ErrorCode::InvalidVault,
     )]
-    pub pool_token_account_a: Account<'info, TokenAccount>,
+    pub pool_token_account_in: Box<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(
         mut,
-        associated_token::mint = pool.mint_b,
-        associated_token::authority = pool
+        address = pool.vault_for(&output_mint.key()) @ ErrorCode::InvalidVault,
     )]
-    pub pool_token_account_b: Account<'info, TokenAccount>,
+    pub pool_token_account_out: Box<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(
         mut,
-        associated_token::mint = pool.mint_a,
-        associated_token::authority = signer
+        associated_token::mint = input_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = input_token_program,
     )]
-    pub user_token_account_a: Account<'info, TokenAccount>,
+    pub user_token_account_in: Box<InterfaceAccount<'info, TokenAccount>>,
 
     #[account(
         mut,
-        associated_token::mint = pool.mint_b,
-        associated_token::authority = signer
+        associated_token::mint = output_mint,
+        associated_token::authority = signer,
+        associated_token::token_program = output_token_program,
     )]
-    pub user_token_account_b: Account<'info, TokenAccount>,
+    pub user_token_account_out: Box<InterfaceAccount<'info, TokenAccount>>,
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+    pub input_token_program: Interface<'info, TokenInterface>,
+    pub output_token_program: Interface<'info, TokenInterface>,
 }
 
 #[account]
-#[derive(InitSpace)]
+#[derive(InitSpace, Default)]
 pub struct Amm{
     pub user: Pubkey,
-    pub fee:u8,
+    pub fee:u16, // basis points, see FEE_DENOMINATOR
     pub lp_mint:Pubkey,
+    pub protocol_fee_share:u16, // basis points of `fee` kept by the protocol
+    pub pending_user: Pubkey, // proposed admin, Pubkey::default() when none
+    pub fee_recipient: Pubkey, // owner of the treasury accounts for protocol fees
+    pub guardian: Pubkey, // may pause, but not unpause
+    pub paused: bool, // withdraw-only mode for every pool
 }
 
 #[account]
-#[derive(InitSpace)]
+#[derive(InitSpace, Default)]
  pub struct Pool{
+    pub version: u8, // POOL_VERSION at the time the account was written
     pub mint_a: Pubkey,
     pub mint_b: Pubkey,
+    pub vault_a: Pubkey,
+    pub vault_b: Pubkey,
+    pub lp_mint: Pubkey,
+    pub bump: u8,
+    pub lp_mint_bump: u8,
+    pub created_at_slot: u64,
+    pub creator: Pubkey,
+    // Protocol fees sitting in the vaults, excluded from the LP reserves
+    pub protocol_fees_a: u64,
+    pub protocol_fees_b: u64,
+    pub status: u8, // POOL_*_DISABLED bits
+    // Reserves the curve trades against. Vault balance = reserve + protocol fees,
+    // anything above that is a donation until someone calls sync or skim.
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    // TWAP accumulators, UQ64.64 price times seconds, see oracle.rs
+    pub price_a_cumulative: u128,
+    pub price_b_cumulative: u128,
+    pub last_update_timestamp: i64,
+    pub curve_type: CurveType,
+    // StableSwap amplification, ramps linearly from amp_initial at amp_ramp_start
+    // to amp_target at amp_ramp_stop (see amp_at). Zero for constant product pools.
+    pub amp_initial: u64,
+    pub amp_target: u64,
+    pub amp_ramp_start: i64,
+    pub amp_ramp_stop: i64,
+    // Weighted pool token weights in basis points, summing to FEE_DENOMINATOR.
+    // Zero for the other curves.
+    pub weight_a: u16,
+    pub weight_b: u16,
  }
 
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq, InitSpace)]
+pub enum CurveType {
+    #[default]
+    ConstantProduct, // x * y = k
+    StableSwap, // Curve's invariant, see stable.rs
+    Weighted, // Balancer's prod(x_i ^ w_i), see weighted.rs
+}
+
+impl Pool {
+    // True if the two mints are this pool's pair, in either order
+    pub fn is_pair(&self, mint_x: &Pubkey, mint_y: &Pubkey) -> bool {
+        (*mint_x == self.mint_a && *mint_y == self.mint_b)
+            || (*mint_x == self.mint_b && *mint_y == self.mint_a)
+    }
+
+    // Vault holding mint_x, assumes mint_x is one of the pair (see is_pair)
+    pub fn vault_for(&self, mint_x: &Pubkey) -> Pubkey {
+        if *mint_x == self.mint_a {
+            self.vault_a
+        } else {
+            self.vault_b
+        }
+    }
+
+    // Adds price * seconds since the last update, using the reserves that held
+    // over that time. Wraps on overflow like Uniswap v2, only differences matter.
+    pub fn update_price_accumulators(&mut self, now: i64) {
+        let elapsed = now.saturating_sub(self.last_update_timestamp);
+        if elapsed <= 0 {
+            return;
+        }
+
+        if self.reserve_a != 0 && self.reserve_b != 0 {
+            let elapsed = elapsed as u128;
+            let (price_a, price_b) = self.spot_prices_q64(now);
+            self.price_a_cumulative = self.price_a_cumulative.wrapping_add(price_a.wrapping_mul(elapsed));
+            self.price_b_cumulative = self.price_b_cumulative.wrapping_add(price_b.wrapping_mul(elapsed));
+        }
+        self.last_update_timestamp = now;
+    }
+
+    // Recorded reserves as (reserve for mint_x, reserve for the other mint)
+    pub fn reserves_for(&self, mint_x: &Pubkey) -> (u64, u64) {
+        if *mint_x == self.mint_a {
+            (self.reserve_a, self.reserve_b)
+        } else {
+            (self.reserve_b, self.reserve_a)
+        }
+    }
+
+    // Inverse of reserves_for
+    pub fn set_reserves_for(&mut self, mint_x: &Pubkey, reserve_x: u64, reserve_other: u64) {
+        (self.reserve_a, self.reserve_b) = self.in_pair_order(mint_x, reserve_x, reserve_other);
+    }
+
+    // The pair's other mint, assumes mint_x is one of the pair
+    pub fn paired_mint(&self, mint_x: &Pubkey) -> Pubkey {
+        if *mint_x == self.mint_a {
+            self.mint_b
+        } else {
+            self.mint_a
+        }
+    }
+
+    // Weighted pool weights as (weight for mint_x, weight for the other mint)
+    pub fn weights_for(&self, mint_x: &Pubkey) -> (u16, u16) {
+        if *mint_x == self.mint_a {
+            (self.weight_a, self.weight_b)
+        } else {
+            (self.weight_b, self.weight_a)
+        }
+    }
+
+    // Reorders (value for mint_x, value for the other mint) into (value for mint_a, value for mint_b)
+    pub fn in_pair_order(&self, mint_x: &Pubkey, value_x: u64, value_other: u64) -> (u64, u64) {
+        if *mint_x == self.mint_a {
+            (value_x, value_other)
+        } else {
+            (value_other, value_x)
+        }
+    }
+
+    // Marginal prices (A in B, B in A) on the pool's own curve, UQ64.64, for the oracle
+    pub fn spot_prices_q64(&self, now: i64) -> (u128, u128) {
+        let constant_product = (
+            oracle::price_q64(self.reserve_a, self.reserve_b),
+            oracle::price_q64(self.reserve_b, self.reserve_a),
+        );
+        match self.curve_type {
+            CurveType::ConstantProduct => constant_product,
+            CurveType::StableSwap => {
+                let amp = self.amp_at(now);
+                // D only fails to converge where swaps fail too, keep the oracle running
+                stable::spot_price_q64(amp, self.reserve_a, self.reserve_b)
+                    .zip(stable::spot_price_q64(amp, self.reserve_b, self.reserve_a))
+                    .unwrap_or(constant_product)
+            }
+            CurveType::Weighted => {
+                weighted::spot_price_q64(self.reserve_a, self.reserve_b, self.weight_a, self.weight_b)
+                    .zip(weighted::spot_price_q64(self.reserve_b, self.reserve_a, self.weight_b, self.weight_a))
+                    .unwrap_or(constant_product)
+            }
+        }
+    }
+
+    // Amplification at `now`, interpolated while a ramp is in progress
+    pub fn amp_at(&self, now: i64) -> u64 {
+        if now >= self.amp_ramp_stop || self.amp_ramp_stop <= self.amp_ramp_start {
+            return self.amp_target;
+        }
+
+        let elapsed = now.saturating_sub(self.amp_ramp_start).max(0) as u128;
+        let duration = (self.amp_ramp_stop - self.amp_ramp_start) as u128;
+        let (initial, target) = (self.amp_initial as u128, self.amp_target as u128);
+        // Between amp_initial and amp_target, so it always fits back into u64
+        let amp = if target > initial {
+            initial + (target - initial) * elapsed / duration
+        } else {
+            initial - (initial - target) * elapsed / duration
+        };
+        amp as u64
+    }
+}
+
+// Amounts in events are always in pool order (mint_a, mint_b) and are what the
+// vaults actually received or sent, reserves and LP supply are after the instruction
+
+#[event]
+pub struct AmmInitialized {
+    pub admin: Pubkey,
+    pub fee: u16,
+    pub protocol_fee_share: u16,
+}
+
+#[event]
+pub struct PoolCreated {
+    pub pool: Pubkey,
+    pub creator: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub lp_mint: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub lp_amount: u64, // minted to the creator, MINIMUM_LIQUIDITY is locked on top
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub lp_supply: u64,
+    pub curve_type: CurveType,
+    pub amp: u64,
+    pub weight_a: u16,
+    pub weight_b: u16,
+}
+
+#[event]
+pub struct LiquidityAdded {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub lp_amount: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub lp_supply: u64,
+}
+
+#[event]
+pub struct LiquidityRemoved {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub lp_amount: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+    pub lp_supply: u64,
+}
+
+#[event]
+pub struct Swapped {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub input_mint: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub protocol_fee: u64,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+#[event]
+pub struct ProtocolFeesCollected {
+    pub pool: Pubkey,
+    pub fee_recipient: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct ReservesSynced {
+    pub pool: Pubkey,
+    pub reserve_a: u64,
+    pub reserve_b: u64,
+}
+
+#[event]
+pub struct Skimmed {
+    pub pool: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct FeeUpdated {
+    pub old_fee: u16,
+    pub new_fee: u16,
+    pub old_protocol_fee_share: u16,
+    pub new_protocol_fee_share: u16,
+}
+
+#[event]
+pub struct AdminProposed {
+    pub admin: Pubkey,
+    pub pending_admin: Pubkey,
+}
+
+#[event]
+pub struct AdminTransferred {
+    pub old_admin: Pubkey,
+    pub new_admin: Pubkey,
+}
+
+#[event]
+pub struct FeeRecipientUpdated {
+    pub old_fee_recipient: Pubkey,
+    pub new_fee_recipient: Pubkey,
+}
+
+#[event]
+pub struct GuardianUpdated {
+    pub old_guardian: Pubkey,
+    pub new_guardian: Pubkey,
+}
+
+#[event]
+pub struct PauseUpdated {
+    pub authority: Pubkey,
+    pub paused: bool,
+}
+
+#[event]
+pub struct PoolStatusUpdated {
+    pub pool: Pubkey,
+    pub authority: Pubkey,
+    pub old_status: u8,
+    pub new_status: u8,
+}
+
+#[event]
+pub struct AmpRampStarted {
+    pub pool: Pubkey,
+    pub amp_initial: u64,
+    pub amp_target: u64,
+    pub ramp_start: i64,
+    pub ramp_stop: i64,
+}
+
+#[event]
+pub struct AmpRampStopped {
+    pub pool: Pubkey,
+    pub amp: u64,
+}
+
+#[event]
+pub struct ClmmPoolCreated {
+    pub pool: Pubkey,
+    pub creator: Pubkey,
+    pub mint_a: Pubkey,
+    pub mint_b: Pubkey,
+    pub tick_spacing: u16,
+    pub fee: u16,
+    pub sqrt_price_x64: u128,
+    pub tick_current: i32,
+}
+
+#[event]
+pub struct PositionOpened {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub position_mint: Pubkey,
+    pub owner: Pubkey,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+}
+
+#[event]
+pub struct ClmmLiquidityAdded {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub user: Pubkey,
+    pub liquidity: u128,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub pool_liquidity: u128, // active liquidity after the change
+}
+
+#[event]
+pub struct ClmmLiquidityRemoved {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub user: Pubkey,
+    pub liquidity: u128,
+    pub amount_a: u64,
+    pub amount_b: u64,
+    pub pool_liquidity: u128, // active liquidity after the change
+}
+
+#[event]
+pub struct ClmmFeesCollected {
+    pub pool: Pubkey,
+    pub position: Pubkey,
+    pub user: Pubkey,
+    pub amount_a: u64,
+    pub amount_b: u64,
+}
+
+#[event]
+pub struct ClmmSwapped {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub input_mint: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub fee_amount: u64,
+    pub protocol_fee: u64, // part of fee_amount
+    // Pool state after the trade
+    pub sqrt_price_x64: u128,
+    pub tick_current: i32,
+    pub liquidity: u128,
+}
+
+#[event]
+pub struct MultiPoolCreated {
+    pub pool: Pubkey,
+    pub creator: Pubkey,
+    pub lp_mint: Pubkey,
+    pub mints: Vec<Pubkey>,
+    pub weights: Vec<u16>,
+    pub amounts: Vec<u64>,
+    pub lp_amount: u64,
+    pub lp_supply: u64,
+}
+
+#[event]
+pub struct MultiLiquidityAdded {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub amounts: Vec<u64>, // in the pool's token order
+    pub lp_amount: u64,
+    pub reserves: Vec<u64>,
+    pub lp_supply: u64,
+}
+
+#[event]
+pub struct MultiLiquidityRemoved {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub amounts: Vec<u64>, // in the pool's token order
+    pub lp_amount: u64,
+    pub reserves: Vec<u64>,
+    pub lp_supply: u64,
+}
+
+#[event]
+pub struct MultiProtocolFeesCollected {
+    pub pool: Pubkey,
+    pub fee_recipient: Pubkey,
+    pub amounts: Vec<u64>, // in the pool's token order
+}
+
+#[event]
+pub struct MultiSwapped {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub input_mint: Pubkey,
+    pub output_mint: Pubkey,
+    pub amount_in: u64,
+    pub amount_out: u64,
+    pub protocol_fee: u64,
+    pub reserve_in: u64,
+    pub reserve_out: u64,
+}
+
  #[error_code]
  pub enum ErrorCode {
      #[msg("user account does not have balance")]
@@ -631,4 +3904,399 @@ pub struct Amm{
     SlippageExceeded,
     #[msg("Math Overflow")]
     MathOverflow,
- }
\ No newline at end of file
+    #[msg("Deposit too small to mint any LP tokens")]
+    InsufficientLiquidityMinted,
+    #[msg("Fee above MAX_FEE, or protocol fee share above 10000 basis points")]
+    InvalidFee,
+    #[msg("Signer is not allowed to perform this action")]
+    Unauthorized,
+    #[msg("Program is paused, only withdrawals are allowed")]
+    ProgramPaused,
+    #[msg("Swaps are disabled for this pool")]
+    SwapsDisabled,
+    #[msg("Deposits are disabled for this pool")]
+    DepositsDisabled,
+    #[msg("Withdrawals are disabled for this pool")]
+    WithdrawalsDisabled,
+    #[msg("Unknown pool status bits")]
+    InvalidPoolStatus,
+    #[msg("Initial deposit must mint more than the minimum locked liquidity")]
+    InsufficientInitialLiquidity,
+    #[msg("Pool mints must be different")]
+    IdenticalMints,
+    #[msg("Pool mints must be sorted, mint_a < mint_b")]
+    InvalidMintOrder,
+    #[msg("Mint does not belong to this pool")]
+    InvalidMint,
+    #[msg("Token account is not the pool's vault")]
+    InvalidVault,
+    #[msg("Pool does not hold enough liquidity for this trade")]
+    InsufficientLiquidity,
+    #[msg("Transaction deadline has passed")]
+    TransactionExpired,
+    #[msg("Mint has a Token-2022 extension the pool does not support")]
+    UnsupportedMintExtension,
+    #[msg("Requested time is older than the oldest price observation")]
+    ObservationTooOld,
+    #[msg("Observation times must be in the past and at most MAX_OBSERVE_POINTS long")]
+    InvalidObservationWindow,
+    #[msg("Route accounts do not form a valid path from input_mint to output_mint")]
+    InvalidRoute,
+    #[msg("Amplification is out of range, or set for a constant product pool")]
+    InvalidAmp,
+    #[msg("Amplification ramp is too short, too steep, or overlaps a running ramp")]
+    InvalidAmpRamp,
+    #[msg("Instruction does not apply to this pool's curve type")]
+    InvalidCurveType,
+    #[msg("StableSwap pools need both mints to have the same decimals")]
+    DecimalsMismatch,
+    #[msg("LP supply is zero, there is nothing to withdraw")]
+    ZeroLpSupply,
+    #[msg("Amount must be greater than zero")]
+    ZeroAmount,
+    #[msg("Weights are only for weighted pools, each at least MIN_WEIGHT")]
+    InvalidWeight,
+    #[msg("Tick spacing must be between 1 and MAX_TICK_SPACING")]
+    InvalidTickSpacing,
+    #[msg("Tick is out of range, not on the tick spacing, or the range is empty")]
+    InvalidTick,
+    #[msg("Sqrt price is out of range, or the price limit is on the wrong side")]
+    InvalidSqrtPrice,
+    #[msg("Tick array does not belong to the pool or is not the one expected")]
+    InvalidTickArray,
+    #[msg("Multi-asset pools hold 2 to MAX_POOL_TOKENS tokens, with one set of accounts and amounts each")]
+    InvalidTokenCount,
+    #[msg("Pool id does not match the pool's mints and weights")]
+    InvalidPoolId,
+ }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amm_output_without_fee_is_plain_constant_product() {
+        // 1000 * 2000 / (1000 + 1000)
+        assert_eq!(calculate_amm_output(1_000, 1_000, 2_000, 0), Some(1_000));
+        assert_eq!(calculate_amm_output(0, 1_000, 2_000, 0), Some(0));
+    }
+
+    #[test]
+    fn amm_output_charges_fee_on_input() {
+        // 30 bps: effective input 997 -> 997 * 1_000_000 / (1_000_000 + 997) = 996
+        assert_eq!(calculate_amm_output(1_000, 1_000_000, 1_000_000, 30), Some(996));
+        // Same trade with no fee pays out one more unit
+        assert_eq!(calculate_amm_output(1_000, 1_000_000, 1_000_000, 0), Some(999));
+    }
+
+    #[test]
+    fn amm_output_fee_boundaries() {
+        // MAX_FEE (10%): 900 of the 1_000 reach the curve, 900 * 1_000_000 / 1_000_900 = 899
+        assert_eq!(calculate_amm_output(1_000, 1_000_000, 1_000_000, MAX_FEE), Some(899));
+        // 1 bp below it, 9_001 of 10_000 reach the curve instead of 9_000
+        assert_eq!(calculate_amm_output(10_000, 1_000_000, 1_000_000, MAX_FEE - 1), Some(8_920));
+        // 1 bp above it can never be configured
+        assert_eq!(check_fees(MAX_FEE + 1, 0).unwrap_err(), error!(ErrorCode::InvalidFee));
+    }
+
+    #[test]
+    fn amm_input_inverts_amm_output() {
+        // No fee: 1000 * 2000 / (1000 + 1000) = 1000, so 1000 out needs 1000 in
+        assert_eq!(calculate_amm_input(1_000, 1_000, 2_000, 0), Some(1_000));
+        // 30 bps: 996 out of a 1M/1M pool needs exactly the 1_000 from the exact-in test
+        assert_eq!(calculate_amm_input(996, 1_000_000, 1_000_000, 30), Some(1_000));
+        assert_eq!(calculate_amm_output(1_000, 1_000_000, 1_000_000, 30), Some(996));
+    }
+
+    #[test]
+    fn amm_input_rejects_draining_and_full_fee() {
+        assert_eq!(calculate_amm_input(1_000, 1_000, 1_000, 30), None);
+        assert_eq!(calculate_amm_input(1_001, 1_000, 1_000, 30), None);
+        assert_eq!(calculate_amm_input(1, 1_000, 1_000, 10_000), None);
+        assert_eq!(calculate_amm_input(0, 1_000, 1_000, 30), Some(0));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn amm_input_always_covers_output(
+            reserve_in in 1u64..=u64::MAX / 2,
+            reserve_out in 2u64..=u64::MAX / 2,
+            out_fraction in 1u64..10_000,
+            fee in 0u16..10_000,
+        ) {
+            let amount_out = ((reserve_out as u128 * out_fraction as u128) / 10_000) as u64;
+            if let Some(amount_in) = calculate_amm_input(amount_out, reserve_in, reserve_out, fee) {
+                let out = calculate_amm_output(amount_in, reserve_in, reserve_out, fee).unwrap();
+                proptest::prop_assert!(out >= amount_out);
+            }
+        }
+    }
+
+    #[test]
+    fn optimal_swap_matches_uniswap_reference() {
+        // Zapping 1% of the reserve at 0.30% fee swaps just under half of it
+        // (the real-valued formula gives 4995.05)
+        let swap = calculate_optimal_swap_amount(10_000, 1_000_000, 30).unwrap();
+        assert_eq!(swap, 4_995);
+        // Without a fee the classic formula is exact: sqrt(r^2 + a r) - r
+        assert_eq!(calculate_optimal_swap_amount(3_000_000, 1_000_000, 0), Some(1_000_000));
+        assert_eq!(calculate_optimal_swap_amount(0, 1_000_000, 30), Some(0));
+        assert_eq!(calculate_optimal_swap_amount(1_000, 1_000_000, 10_000), None);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn optimal_swap_leaves_matching_ratio(
+            reserve_in in 1_000u64..=u64::MAX / 4,
+            reserve_out in 1_000u64..=u64::MAX / 4,
+            amount_in in 1_000u64..=u64::MAX / 4,
+            fee in 0u16..=1_000,
+        ) {
+            let swap = calculate_optimal_swap_amount(amount_in, reserve_in, fee).unwrap();
+            proptest::prop_assert!(swap <= amount_in);
+
+            let out = calculate_amm_output(swap, reserve_in, reserve_out, fee).unwrap();
+            let new_in = reserve_in as u128 + swap as u128;
+            let new_out = (reserve_out - out) as u128;
+            // leftover * new_out ~= out * new_in, i.e. the deposit ratio matches the pool,
+            // up to rounding in swap and out (absolute for small pools, relative for large)
+            let leftover = (amount_in - swap) as u128;
+            let lhs = leftover * new_out;
+            let rhs = out as u128 * new_in;
+            let tolerance = lhs.max(rhs) / 1_000_000_000 + (leftover + new_in + new_out) * 2;
+            proptest::prop_assert!(lhs.abs_diff(rhs) <= tolerance);
+        }
+    }
+
+    #[test]
+    fn guardian_may_only_disable_swaps_and_deposits() {
+        let amm = Amm { user: Pubkey::new_unique(), guardian: Pubkey::new_unique(), ..Default::default() };
+        let stranger = Pubkey::new_unique();
+        let all = POOL_STATUS_MASK;
+
+        assert!(check_pool_status_change(&amm, &amm.user, all, 0).is_ok());
+        assert!(check_pool_status_change(&amm, &amm.guardian, 0, POOL_SWAPS_DISABLED | POOL_DEPOSITS_DISABLED).is_ok());
+        assert_eq!(
+            check_pool_status_change(&amm, &amm.guardian, POOL_SWAPS_DISABLED, 0).unwrap_err(),
+            error!(ErrorCode::Unauthorized)
+        );
+        assert_eq!(
+            check_pool_status_change(&amm, &amm.guardian, 0, POOL_WITHDRAWALS_DISABLED).unwrap_err(),
+            error!(ErrorCode::Unauthorized)
+        );
+        assert_eq!(check_pool_status_change(&amm, &stranger, 0, 0).unwrap_err(), error!(ErrorCode::Unauthorized));
+        assert_eq!(check_pool_status_change(&amm, &amm.user, 0, 1 << 7).unwrap_err(), error!(ErrorCode::InvalidPoolStatus));
+    }
+
+    #[test]
+    fn fees_are_capped() {
+        assert!(check_fees(0, 0).is_ok());
+        assert!(check_fees(30, 2_000).is_ok());
+        assert!(check_fees(MAX_FEE, FEE_DENOMINATOR as u16).is_ok());
+        assert_eq!(check_fees(MAX_FEE + 1, 0).unwrap_err(), error!(ErrorCode::InvalidFee));
+        assert_eq!(check_fees(FEE_DENOMINATOR as u16, 0).unwrap_err(), error!(ErrorCode::InvalidFee));
+        assert_eq!(check_fees(30, FEE_DENOMINATOR as u16 + 1).unwrap_err(), error!(ErrorCode::InvalidFee));
+    }
+
+    #[test]
+    fn protocol_fee_is_a_share_of_swap_fee() {
+        // 30 bps on 1_000_000 = 3_000 fee, 1/6 of it (1_667 bps) goes to the protocol
+        assert_eq!(calculate_protocol_fee(1_000_000, 30, 1_667), Some(500));
+        assert_eq!(calculate_protocol_fee(1_000_000, 30, 0), Some(0));
+        assert_eq!(calculate_protocol_fee(1_000_000, 30, 10_000), Some(3_000));
+        assert_eq!(calculate_protocol_fee(1_000_000, 0, 10_000), Some(0));
+        // The fee is rounded up, so even dust swaps pay one unit
+        assert_eq!(calculate_protocol_fee(1, 30, 10_000), Some(1));
+    }
+
+    #[test]
+    fn pool_pair_matches_either_order() {
+        let mint_a = Pubkey::new_unique();
+        let mint_b = Pubkey::new_unique();
+        let pool = Pool {
+            mint_a,
+            mint_b,
+            vault_a: Pubkey::new_unique(),
+            vault_b: Pubkey::new_unique(),
+            reserve_a: 10,
+            reserve_b: 20,
+            ..Default::default()
+        };
+
+        assert!(pool.is_pair(&mint_a, &mint_b));
+        assert!(pool.is_pair(&mint_b, &mint_a));
+        assert!(!pool.is_pair(&mint_a, &mint_a));
+        assert!(!pool.is_pair(&mint_a, &Pubkey::new_unique()));
+        assert_eq!(pool.vault_for(&mint_a), pool.vault_a);
+        assert_eq!(pool.vault_for(&mint_b), pool.vault_b);
+        assert_eq!(pool.reserves_for(&mint_a), (10, 20));
+        assert_eq!(pool.reserves_for(&mint_b), (20, 10));
+    }
+
+    #[test]
+    fn set_reserves_for_inverts_reserves_for() {
+        let mint_a = Pubkey::new_unique();
+        let mint_b = Pubkey::new_unique();
+        let mut pool = Pool { mint_a, mint_b, ..Default::default() };
+
+        pool.set_reserves_for(&mint_b, 7, 3);
+        assert_eq!((pool.reserve_a, pool.reserve_b), (3, 7));
+        assert_eq!(pool.reserves_for(&mint_b), (7, 3));
+
+        pool.set_reserves_for(&mint_a, 5, 9);
+        assert_eq!((pool.reserve_a, pool.reserve_b), (5, 9));
+    }
+
+    #[test]
+    fn burning_all_lp_returns_both_reserves() {
+        assert_eq!(calculate_withdraw_amounts(1_000, 5_000, 7, 1_000).unwrap(), (5_000, 7));
+        assert_eq!(calculate_withdraw_amounts(u64::MAX, u64::MAX, 1, u64::MAX).unwrap(), (u64::MAX, 1));
+        // Half the supply, half of each reserve, rounded down
+        assert_eq!(calculate_withdraw_amounts(500, 5_001, 7, 1_000).unwrap(), (2_500, 3));
+    }
+
+    #[test]
+    fn withdraw_rejects_zero_and_excess() {
+        assert_eq!(calculate_withdraw_amounts(1, 100, 100, 0).unwrap_err(), error!(ErrorCode::ZeroLpSupply));
+        assert_eq!(calculate_withdraw_amounts(0, 100, 100, 10).unwrap_err(), error!(ErrorCode::ZeroAmount));
+        assert_eq!(calculate_withdraw_amounts(11, 100, 100, 10).unwrap_err(), error!(ErrorCode::InsufficientBalance));
+        // Rounds to nothing on both legs
+        assert_eq!(calculate_withdraw_amounts(1, 5, 5, 10).unwrap_err(), error!(ErrorCode::ZeroAmount));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn withdrawals_are_pro_rata_and_drain_exactly(
+            reserve_a in 1u64..=u64::MAX,
+            reserve_b in 1u64..=u64::MAX,
+            total_supply in 2u64..=u64::MAX,
+            burn_fraction in 1u64..10_000,
+        ) {
+            // One LP burns part of the supply, the rest burns everything left
+            let lp_first = ((total_supply as u128 * burn_fraction as u128) / 10_000).max(1) as u64;
+            let Ok((first_a, first_b)) = calculate_withdraw_amounts(lp_first, reserve_a, reserve_b, total_supply) else {
+                return Ok(());
+            };
+            // Never more than the LP's share of either reserve
+            proptest::prop_assert!(first_a as u128 * total_supply as u128 <= reserve_a as u128 * lp_first as u128);
+            proptest::prop_assert!(first_b as u128 * total_supply as u128 <= reserve_b as u128 * lp_first as u128);
+
+            let (rest_a, rest_b) = (reserve_a - first_a, reserve_b - first_b);
+            let supply_left = total_supply - lp_first;
+            let (last_a, last_b) = calculate_withdraw_amounts(supply_left, rest_a, rest_b, supply_left).unwrap();
+            proptest::prop_assert_eq!((first_a + last_a, first_b + last_b), (reserve_a, reserve_b));
+        }
+    }
+
+    #[test]
+    fn amp_ramps_linearly_in_both_directions() {
+        let mut pool = Pool {
+            curve_type: CurveType::StableSwap,
+            amp_initial: 100,
+            amp_target: 1_000,
+            amp_ramp_start: 1_000,
+            amp_ramp_stop: 2_000,
+            ..Default::default()
+        };
+        assert_eq!(pool.amp_at(500), 100);
+        assert_eq!(pool.amp_at(1_500), 550);
+        assert_eq!(pool.amp_at(2_000), 1_000);
+        assert_eq!(pool.amp_at(5_000), 1_000);
+
+        (pool.amp_initial, pool.amp_target) = (1_000, 100);
+        assert_eq!(pool.amp_at(1_250), 775);
+        assert_eq!(pool.amp_at(2_000), 100);
+    }
+
+    #[test]
+    fn swaps_dispatch_on_curve_type() {
+        let mut pool = Pool { curve_type: CurveType::ConstantProduct, ..Default::default() };
+        let mint_in = Pubkey::default();
+        let constant_product = calculate_swap_output(&pool, &mint_in, 10_000, 1_000_000, 1_000_000, 30, 0).unwrap();
+        assert_eq!(Some(constant_product), calculate_amm_output(10_000, 1_000_000, 1_000_000, 30));
+
+        pool.curve_type = CurveType::StableSwap;
+        (pool.amp_initial, pool.amp_target) = (200, 200);
+        let stable = calculate_swap_output(&pool, &mint_in, 10_000, 1_000_000, 1_000_000, 30, 0).unwrap();
+        assert!(stable > constant_product);
+
+        let amount_in = calculate_swap_input(&pool, &mint_in, stable, 1_000_000, 1_000_000, 30, 0).unwrap();
+        assert!(calculate_swap_output(&pool, &mint_in, amount_in, 1_000_000, 1_000_000, 30, 0).unwrap() >= stable);
+
+        // Selling the 80% token of an 80/20 pool with equal reserves moves its price the least
+        pool.curve_type = CurveType::Weighted;
+        pool.mint_a = mint_in;
+        pool.mint_b = Pubkey::new_unique();
+        (pool.weight_a, pool.weight_b) = (8_000, 2_000);
+        let heavy_in = calculate_swap_output(&pool, &pool.mint_a, 10_000, 1_000_000, 1_000_000, 30, 0).unwrap();
+        let light_in = calculate_swap_output(&pool, &pool.mint_b, 10_000, 1_000_000, 1_000_000, 30, 0).unwrap();
+        assert!(heavy_in > constant_product && constant_product > light_in);
+    }
+
+    #[test]
+    fn amm_output_handles_max_values() {
+        let out = calculate_amm_output(u64::MAX, u64::MAX, u64::MAX, 30).unwrap();
+        assert!(out < u64::MAX / 2);
+        assert!(calculate_amm_output(u64::MAX, u64::MAX, u64::MAX, 0).is_some());
+        assert_eq!(calculate_amm_output(u64::MAX, 0, u64::MAX, 0), Some(u64::MAX));
+    }
+
+    #[test]
+    fn amm_output_never_drains_reserve() {
+        for fee in [0u16, 1, 30, 100, 5_000, 10_000] {
+            let out = calculate_amm_output(u64::MAX, 1, 1_000, fee).unwrap();
+            assert!(out < 1_000);
+        }
+    }
+
+    #[test]
+    fn deposit_uses_all_of_a_when_b_covers_the_ratio() {
+        // 100 A needs 200 B at 1_000:2_000, within the 500 B allowed
+        assert_eq!(calculate_liquidity_deposit(100, 500, 1_000, 2_000, 1_000), Some((100, 200, 100)));
+    }
+
+    #[test]
+    fn deposit_falls_back_to_all_of_b() {
+        // 100 A would need 200 B, only 50 is allowed, so 50 B takes 25 A
+        assert_eq!(calculate_liquidity_deposit(100, 50, 1_000, 2_000, 1_000), Some((25, 50, 25)));
+    }
+
+    #[test]
+    fn deposit_rejects_empty_reserves() {
+        assert_eq!(calculate_liquidity_deposit(100, 100, 0, 1_000, 1_000), None);
+        assert_eq!(calculate_liquidity_deposit(100, 100, 1_000, 0, 1_000), None);
+    }
+
+    #[test]
+    fn deposit_lp_rounds_down() {
+        // 7 * 1 / 3 = 2.33
+        assert_eq!(calculate_liquidity_deposit(1, 1, 3, 3, 7), Some((1, 1, 2)));
+        // Dust that is worth less than one LP mints none
+        assert_eq!(calculate_liquidity_deposit(1, 1, 1_000, 1_000, 999), Some((1, 1, 0)));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn deposit_then_full_withdrawal_never_gains(
+            reserve_a in 1u64..1_000_000_000_000,
+            reserve_b in 1u64..1_000_000_000_000,
+            total_supply in 1u64..1_000_000_000_000,
+            max_amount_a in 0u64..1_000_000_000_000,
+            max_amount_b in 0u64..1_000_000_000_000,
+        ) {
+            // The fallback's `amount_a_optimal > max_amount_a` guard never fires: quote
+            // rounds down, so it would need max_b * reserve_a / reserve_b >= max_a while
+            // max_a * reserve_b / reserve_a > max_b
+            let (amount_a, amount_b, lp_amount) =
+                calculate_liquidity_deposit(max_amount_a, max_amount_b, reserve_a, reserve_b, total_supply).unwrap();
+            proptest::prop_assert!(amount_a <= max_amount_a && amount_b <= max_amount_b);
+
+            // Burning the new LP pays out its floored share of the grown reserves
+            let supply_after = (total_supply + lp_amount) as u128;
+            let out_a = lp_amount as u128 * (reserve_a + amount_a) as u128 / supply_after;
+            let out_b = lp_amount as u128 * (reserve_b + amount_b) as u128 / supply_after;
+            proptest::prop_assert!(out_a <= amount_a as u128, "withdrew {} A after depositing {}", out_a, amount_a);
+            proptest::prop_assert!(out_b <= amount_b as u128, "withdrew {} B after depositing {}", out_b, amount_b);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+// Integer math shared by the LP and swap calculations.
+// Everything is done in u128 so u64 * u64 products never overflow, and every
+// division states which way it rounds so the pool is never the one that loses dust.
+
+// For the few formulas whose intermediates exceed u128 (e.g. the zap's optimal swap)
+#[allow(clippy::assign_op_pattern, clippy::manual_div_ceil)]
+mod wide {
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+}
+pub use wide::U256;
+
+// Floor of the square root, Newton's method starting from a power of two above the root
+pub fn sqrt(value: u128) -> u128 {
+    if value < 2 {
+        return value;
+    }
+
+    let bits = 128 - value.leading_zeros();
+    let mut x = 1u128 << bits.div_ceil(2);
+    loop {
+        let next = (x + value / x) >> 1;
+        if next >= x {
+            return x;
+        }
+        x = next;
+    }
+}
+
+pub fn checked_mul(a: u128, b: u128) -> Option<u128> {
+    a.checked_mul(b)
+}
+
+pub fn checked_div(a: u128, b: u128) -> Option<u128> {
+    a.checked_div(b)
+}
+
+// (a * b) / denominator, rounded down
+pub fn mul_div_floor(a: u128, b: u128, denominator: u128) -> Option<u128> {
+    checked_div(checked_mul(a, b)?, denominator)
+}
+
+// (a * b) / denominator, rounded up
+pub fn mul_div_ceil(a: u128, b: u128, denominator: u128) -> Option<u128> {
+    let product = checked_mul(a, b)?;
+    let quotient = checked_div(product, denominator)?;
+    if product % denominator == 0 {
+        Some(quotient)
+    } else {
+        quotient.checked_add(1)
+    }
+}
+
+pub fn to_u64(value: u128) -> Option<u64> {
+    u64::try_from(value).ok()
+}
+
+pub fn u256_to_u128(value: U256) -> Option<u128> {
+    if value.bits() > 128 {
+        None
+    } else {
+        Some(value.as_u128())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigUint;
+    use proptest::prelude::*;
+
+    fn big(value: u128) -> BigUint {
+        BigUint::from(value)
+    }
+
+    #[test]
+    fn sqrt_small_and_edge_values() {
+        assert_eq!(sqrt(0), 0);
+        assert_eq!(sqrt(1), 1);
+        assert_eq!(sqrt(2), 1);
+        assert_eq!(sqrt(3), 1);
+        assert_eq!(sqrt(4), 2);
+        assert_eq!(sqrt(99), 9);
+        assert_eq!(sqrt(100), 10);
+        assert_eq!(sqrt(u64::MAX as u128 * u64::MAX as u128), u64::MAX as u128);
+        assert_eq!(sqrt(u128::MAX), u64::MAX as u128);
+    }
+
+    #[test]
+    fn sqrt_is_exact_above_f64_precision() {
+        // (2^53 + 1)^2 is where the old f64 path started rounding
+        let root = (1u128 << 53) + 1;
+        assert_eq!(sqrt(root * root), root);
+        assert_eq!(sqrt(root * root - 1), root - 1);
+    }
+
+    #[test]
+    fn u256_sqrt_and_narrowing() {
+        let max = U256::from(u128::MAX);
+        assert_eq!(u256_to_u128((max * max).integer_sqrt()), Some(u128::MAX));
+        assert_eq!(u256_to_u128(max + 1), None);
+    }
+
+    #[test]
+    fn mul_div_rounding() {
+        assert_eq!(mul_div_floor(7, 3, 2), Some(10));
+        assert_eq!(mul_div_ceil(7, 3, 2), Some(11));
+        assert_eq!(mul_div_floor(6, 3, 2), Some(9));
+        assert_eq!(mul_div_ceil(6, 3, 2), Some(9));
+        assert_eq!(mul_div_floor(1, 1, 0), None);
+        assert_eq!(mul_div_ceil(1, 1, 0), None);
+        assert_eq!(mul_div_floor(u128::MAX, 2, 4), None);
+    }
+
+    proptest! {
+        #[test]
+        fn sqrt_matches_bigint(value in any::<u128>()) {
+            let root = sqrt(value);
+            prop_assert_eq!(big(root), big(value).sqrt());
+        }
+
+        #[test]
+        fn mul_div_floor_matches_bigint(a in any::<u64>(), b in any::<u64>(), d in 1..=u128::MAX) {
+            let expected = big(a as u128) * big(b as u128) / big(d);
+            prop_assert_eq!(big(mul_div_floor(a as u128, b as u128, d).unwrap()), expected);
+        }
+
+        #[test]
+        fn mul_div_ceil_matches_bigint(a in any::<u64>(), b in any::<u64>(), d in 1..=u128::MAX) {
+            let product = big(a as u128) * big(b as u128);
+            let d_big = big(d);
+            let expected = (&product + &d_big - 1u32) / &d_big;
+            prop_assert_eq!(big(mul_div_ceil(a as u128, b as u128, d).unwrap()), expected);
+        }
+
+        #[test]
+        fn mul_div_overflow_is_reported(a in any::<u128>(), b in any::<u128>(), d in 1..=u128::MAX) {
+            let product = big(a) * big(b);
+            let fits = product <= big(u128::MAX);
+            prop_assert_eq!(mul_div_floor(a, b, d).is_some(), fits);
+            if fits {
+                prop_assert_eq!(big(mul_div_floor(a, b, d).unwrap()), &product / big(d));
+            }
+        }
+    }
+}
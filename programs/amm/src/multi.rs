@@ -0,0 +1,317 @@
+// Pools of up to MAX_POOL_TOKENS tokens (baskets, index pools), next to the
+// two-sided Pool. They trade on the weighted invariant (see weighted.rs), an
+// equal-weight basket is the N-token constant product. Swaps go between any two
+// of the pool's tokens, deposits and withdrawals are proportional across all of them.
+//
+// Swaps pay Amm::fee, of which Amm::protocol_fee_share is kept aside per token like
+// Pool::protocol_fees_a / _b. Pool::status bits apply through MultiPool::status.
+//
+// Deposit and withdrawal accounts come per token as remaining accounts, in the
+// pool's token order, ACCOUNTS_PER_TOKEN each:
+//   0. mint
+//   1. the pool's vault for it (mut)
+//   2. the user's token account for it (mut), the treasury's in collect_multi_protocol_fees
+//   3. token program of the mint
+use anchor_lang::prelude::*;
+use anchor_lang::solana_program::hash::hashv;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::{math, weighted, ErrorCode, FEE_DENOMINATOR};
+
+// Bumped whenever the MultiPool layout changes
+pub const MULTI_POOL_VERSION: u8 = 1;
+
+pub const MIN_POOL_TOKENS: usize = 2;
+pub const MAX_POOL_TOKENS: usize = 8;
+pub const ACCOUNTS_PER_TOKEN: usize = 4;
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default, PartialEq, Eq, InitSpace)]
+pub struct PoolToken {
+    pub mint: Pubkey,
+    pub vault: Pubkey,
+    pub reserve: u64,
+    pub weight: u16, // basis points, the pool's weights sum to FEE_DENOMINATOR
+    pub protocol_fees: u64, // protocol share of swap fees, in the vault but not in reserve
+}
+
+#[account]
+#[derive(InitSpace, Default)]
+pub struct MultiPool {
+    pub version: u8, // MULTI_POOL_VERSION at the time the account was written
+    pub pool_id: [u8; 32], // see pool_id(), part of the PDA seeds
+    pub bump: u8,
+    pub lp_mint: Pubkey,
+    pub lp_mint_bump: u8,
+    pub creator: Pubkey,
+    pub status: u8, // POOL_*_DISABLED bits, see set_multi_pool_status
+    // Sorted by mint
+    #[max_len(MAX_POOL_TOKENS)]
+    pub tokens: Vec<PoolToken>,
+}
+
+impl MultiPool {
+    pub fn token_index(&self, mint_x: &Pubkey) -> Option<usize> {
+        self.tokens.iter().position(|token| token.mint == *mint_x)
+    }
+
+    // True if both mints are in the pool and differ
+    pub fn is_pair(&self, mint_x: &Pubkey, mint_y: &Pubkey) -> bool {
+        mint_x != mint_y && self.token_index(mint_x).is_some() && self.token_index(mint_y).is_some()
+    }
+
+    // Vault holding mint_x, Pubkey::default() if the pool does not hold it
+    pub fn vault_for(&self, mint_x: &Pubkey) -> Pubkey {
+        self.token_index(mint_x).map_or_else(Pubkey::default, |index| self.tokens[index].vault)
+    }
+
+    pub fn reserves(&self) -> Vec<u64> {
+        self.tokens.iter().map(|token| token.reserve).collect()
+    }
+
+    pub fn weights(&self) -> Vec<u16> {
+        self.tokens.iter().map(|token| token.weight).collect()
+    }
+
+    // Books a trade into the recorded reserves, with the protocol's share of the
+    // swap fee moved out of the input token's reserve (see crate::book_swap)
+    pub fn book_swap(&mut self, index_in: usize, index_out: usize, amount_in: u64, amount_out: u64, protocol_fee: u64) -> Result<()> {
+        let token_in = &mut self.tokens[index_in];
+        token_in.reserve = token_in.reserve
+            .checked_add(amount_in)
+            .and_then(|reserve| reserve.checked_sub(protocol_fee))
+            .ok_or(ErrorCode::MathOverflow)?;
+        token_in.protocol_fees = token_in.protocol_fees.checked_add(protocol_fee).ok_or(ErrorCode::MathOverflow)?;
+
+        let token_out = &mut self.tokens[index_out];
+        token_out.reserve = token_out.reserve.checked_sub(amount_out).ok_or(ErrorCode::MathOverflow)?;
+        Ok(())
+    }
+}
+
+// Identifies a pool by its sorted mints and their weights, so each basket exists once
+pub fn pool_id(mints: &[Pubkey], weights: &[u16]) -> [u8; 32] {
+    let weight_bytes: Vec<[u8; 2]> = weights.iter().map(|weight| weight.to_le_bytes()).collect();
+    let mut parts: Vec<&[u8]> = mints.iter().map(|mint| mint.as_ref()).collect();
+    parts.extend(weight_bytes.iter().map(|bytes| bytes.as_slice()));
+    hashv(&parts).to_bytes()
+}
+
+// Token count, mint order and weights of a new pool
+pub fn validate_tokens(mints: &[Pubkey], weights: &[u16]) -> Result<()> {
+    require!(
+        (MIN_POOL_TOKENS..=MAX_POOL_TOKENS).contains(&mints.len()) && mints.len() == weights.len(),
+        ErrorCode::InvalidTokenCount
+    );
+    require!(mints.windows(2).all(|pair| pair[0] < pair[1]), ErrorCode::InvalidMintOrder);
+    require!(weights.iter().all(|weight| *weight >= weighted::MIN_WEIGHT), ErrorCode::InvalidWeight);
+    require!(
+        weights.iter().map(|weight| *weight as u64).sum::<u64>() == FEE_DENOMINATOR,
+        ErrorCode::InvalidWeight
+    );
+    Ok(())
+}
+
+pub struct TokenAccounts<'info> {
+    pub mint: Box<InterfaceAccount<'info, Mint>>,
+    pub vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub user_token_account: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+// Deserializes and validates the per-token remaining accounts against the pool,
+// the third account of each token must belong to `owner`
+pub fn load_token_accounts<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    pool: &MultiPool,
+    owner: &Pubkey,
+) -> Result<Vec<TokenAccounts<'info>>> {
+    require!(
+        accounts.len() == pool.tokens.len() * ACCOUNTS_PER_TOKEN,
+        ErrorCode::InvalidTokenCount
+    );
+
+    let mut token_accounts = Vec::with_capacity(pool.tokens.len());
+    for (token, token_infos) in pool.tokens.iter().zip(accounts.chunks(ACCOUNTS_PER_TOKEN)) {
+        require_keys_eq!(token_infos[0].key(), token.mint, ErrorCode::InvalidMint);
+        require_keys_eq!(token_infos[1].key(), token.vault, ErrorCode::InvalidVault);
+
+        let token_program = Interface::<TokenInterface>::try_from(&token_infos[3])?;
+        require_keys_eq!(*token_infos[0].owner, token_program.key(), ErrorCode::InvalidMint);
+
+        let user_token_account = Box::new(InterfaceAccount::<TokenAccount>::try_from(&token_infos[2])?);
+        require_keys_eq!(user_token_account.mint, token.mint, ErrorCode::InvalidMint);
+        require_keys_eq!(user_token_account.owner, *owner, ErrorCode::Unauthorized);
+
+        token_accounts.push(TokenAccounts {
+            mint: Box::new(InterfaceAccount::try_from(&token_infos[0])?),
+            vault: Box::new(InterfaceAccount::try_from(&token_infos[1])?),
+            user_token_account,
+            token_program,
+        });
+    }
+    Ok(token_accounts)
+}
+
+// Helper function for Multi-Asset Deposits (N-token calculate_liquidity_deposit)
+// LP = min_i(Max_i * Supply / Reserve_i), each Amount_i = ceil(Reserve_i * LP / Supply)
+// so every token goes in at the pool's ratio and no Amount_i exceeds its maximum.
+// Returns (lp_amount, amounts).
+pub fn calculate_deposit(max_amounts: &[u64], reserves: &[u64], total_supply: u64) -> Option<(u64, Vec<u64>)> {
+    if max_amounts.len() != reserves.len() || total_supply == 0 {
+        return None;
+    }
+
+    let supply = total_supply as u128;
+    let mut lp_amount = u128::MAX;
+    for (max_amount, reserve) in max_amounts.iter().zip(reserves) {
+        lp_amount = lp_amount.min(math::mul_div_floor(*max_amount as u128, supply, *reserve as u128)?);
+    }
+
+    let amounts = reserves
+        .iter()
+        .map(|reserve| math::mul_div_ceil(*reserve as u128, lp_amount, supply).and_then(math::to_u64))
+        .collect::<Option<Vec<u64>>>()?;
+    Some((math::to_u64(lp_amount)?, amounts))
+}
+
+// Helper function for Multi-Asset Withdrawals (N-token calculate_withdraw_amounts)
+// Amount_i = Reserve_i * LP / Supply, rounded down
+pub fn calculate_withdraw(lp_amount: u64, reserves: &[u64], total_supply: u64) -> Result<Vec<u64>> {
+    require!(total_supply > 0, ErrorCode::ZeroLpSupply);
+    require!(lp_amount > 0, ErrorCode::ZeroAmount);
+    require!(lp_amount <= total_supply, ErrorCode::InsufficientBalance);
+
+    let amounts = reserves
+        .iter()
+        .map(|reserve| {
+            math::mul_div_floor(*reserve as u128, lp_amount as u128, total_supply as u128)
+                .and_then(math::to_u64)
+                .ok_or(ErrorCode::MathOverflow)
+        })
+        .collect::<std::result::Result<Vec<u64>, ErrorCode>>()?;
+    require!(amounts.iter().any(|amount| *amount > 0), ErrorCode::ZeroAmount);
+    Ok(amounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn sorted_mints(count: usize) -> Vec<Pubkey> {
+        let mut mints: Vec<Pubkey> = (0..count).map(|_| Pubkey::new_unique()).collect();
+        mints.sort();
+        mints
+    }
+
+    #[test]
+    fn validate_tokens_checks_count_order_and_weights() {
+        let mints = sorted_mints(3);
+        assert!(validate_tokens(&mints, &[3_334, 3_333, 3_333]).is_ok());
+
+        assert_eq!(validate_tokens(&mints[..1], &[10_000]).unwrap_err(), error!(ErrorCode::InvalidTokenCount));
+        assert_eq!(validate_tokens(&sorted_mints(9), &[1_000; 9]).unwrap_err(), error!(ErrorCode::InvalidTokenCount));
+        assert_eq!(validate_tokens(&mints, &[5_000, 5_000]).unwrap_err(), error!(ErrorCode::InvalidTokenCount));
+
+        let unsorted = [mints[1], mints[0], mints[2]];
+        assert_eq!(validate_tokens(&unsorted, &[3_334, 3_333, 3_333]).unwrap_err(), error!(ErrorCode::InvalidMintOrder));
+        let duplicate = [mints[0], mints[0], mints[2]];
+        assert_eq!(validate_tokens(&duplicate, &[3_334, 3_333, 3_333]).unwrap_err(), error!(ErrorCode::InvalidMintOrder));
+
+        assert_eq!(validate_tokens(&mints, &[3_333, 3_333, 3_333]).unwrap_err(), error!(ErrorCode::InvalidWeight));
+        assert_eq!(validate_tokens(&mints, &[9_850, 99, 51]).unwrap_err(), error!(ErrorCode::InvalidWeight));
+    }
+
+    #[test]
+    fn pool_id_depends_on_mints_and_weights() {
+        let mints = sorted_mints(3);
+        let id = pool_id(&mints, &[3_334, 3_333, 3_333]);
+        assert_eq!(id, pool_id(&mints, &[3_334, 3_333, 3_333]));
+        assert_ne!(id, pool_id(&mints, &[3_333, 3_334, 3_333]));
+        assert_ne!(id, pool_id(&sorted_mints(3), &[3_334, 3_333, 3_333]));
+    }
+
+    #[test]
+    fn pool_finds_its_tokens() {
+        let mints = sorted_mints(3);
+        let pool = MultiPool {
+            tokens: mints
+                .iter()
+                .map(|mint| PoolToken { mint: *mint, vault: Pubkey::new_unique(), ..Default::default() })
+                .collect(),
+            ..Default::default()
+        };
+        let stranger = Pubkey::new_unique();
+
+        assert_eq!(pool.token_index(&mints[2]), Some(2));
+        assert!(pool.is_pair(&mints[0], &mints[2]));
+        assert!(!pool.is_pair(&mints[0], &mints[0]));
+        assert!(!pool.is_pair(&mints[0], &stranger));
+        assert_eq!(pool.vault_for(&mints[1]), pool.tokens[1].vault);
+        assert_eq!(pool.vault_for(&stranger), Pubkey::default());
+    }
+
+    #[test]
+    fn book_swap_sets_the_protocol_fee_aside() {
+        let mut pool = MultiPool {
+            tokens: vec![
+                PoolToken { reserve: 1_000, ..Default::default() },
+                PoolToken { reserve: 2_000, ..Default::default() },
+                PoolToken { reserve: 3_000, protocol_fees: 5, ..Default::default() },
+            ],
+            ..Default::default()
+        };
+        pool.book_swap(2, 0, 100, 30, 1).unwrap();
+        assert_eq!(pool.reserves(), vec![970, 2_000, 3_099]);
+        assert_eq!(pool.tokens[2].protocol_fees, 6);
+
+        assert_eq!(pool.book_swap(0, 1, 0, 2_001, 0).unwrap_err(), error!(ErrorCode::MathOverflow));
+    }
+
+    #[test]
+    fn deposit_is_limited_by_the_scarcest_token() {
+        let reserves = [1_000_000, 2_000_000, 4_000_000];
+        let (lp_amount, amounts) = calculate_deposit(&[100_000, 100_000, 1_000_000], &reserves, 1_000_000).unwrap();
+        assert_eq!(lp_amount, 50_000);
+        assert_eq!(amounts, vec![50_000, 100_000, 200_000]);
+
+        // Rounding up keeps the pool's ratio from slipping in the depositor's favour
+        let (lp_amount, amounts) = calculate_deposit(&[10, 10, 10], &[3, 3, 3], 7).unwrap();
+        assert_eq!(lp_amount, 23);
+        assert_eq!(amounts, vec![10, 10, 10]);
+        assert_eq!(calculate_deposit(&[1, 1], &[1, 1], 0), None);
+    }
+
+    #[test]
+    fn withdraw_rejects_zero_and_excess() {
+        let reserves = [1_000, 2_000, 3_000];
+        assert_eq!(calculate_withdraw(100, &reserves, 1_000).unwrap(), vec![100, 200, 300]);
+        assert_eq!(calculate_withdraw(1_000, &reserves, 1_000).unwrap(), reserves.to_vec());
+        assert_eq!(calculate_withdraw(0, &reserves, 1_000).unwrap_err(), error!(ErrorCode::ZeroAmount));
+        assert_eq!(calculate_withdraw(1_001, &reserves, 1_000).unwrap_err(), error!(ErrorCode::InsufficientBalance));
+        assert_eq!(calculate_withdraw(1, &reserves, 0).unwrap_err(), error!(ErrorCode::ZeroLpSupply));
+        assert_eq!(calculate_withdraw(1, &[1, 1, 1], 1_000).unwrap_err(), error!(ErrorCode::ZeroAmount));
+    }
+
+    proptest! {
+        #[test]
+        fn deposit_then_withdraw_never_gains(
+            reserves in prop::collection::vec(1u64..1_000_000_000_000, MIN_POOL_TOKENS..=MAX_POOL_TOKENS),
+            supply in 1u64..1_000_000_000_000,
+            deposit_bps in 1u64..10_000,
+        ) {
+            let max_amounts: Vec<u64> = reserves.iter().map(|reserve| reserve / 10_000 * deposit_bps + 1).collect();
+            let (lp_amount, amounts) = calculate_deposit(&max_amounts, &reserves, supply).unwrap();
+            prop_assume!(lp_amount > 0);
+            for (amount, max_amount) in amounts.iter().zip(&max_amounts) {
+                prop_assert!(amount <= max_amount);
+            }
+
+            let reserves_after: Vec<u64> = reserves.iter().zip(&amounts).map(|(reserve, amount)| reserve + amount).collect();
+            let withdrawn = calculate_withdraw(lp_amount, &reserves_after, supply + lp_amount).unwrap();
+            for (out, amount) in withdrawn.iter().zip(&amounts) {
+                prop_assert!(out <= amount, "withdrew {} after depositing {}", out, amount);
+            }
+        }
+    }
+}
@@ -0,0 +1,295 @@
+// Price oracle: Uniswap v2 style cumulative prices on the Pool, plus a ring buffer
+// of past readings (Uniswap v3 style) so a TWAP can be read over any window the
+// buffer still covers.
+//
+// price_a_cumulative is the sum over time of price_a in UQ64.64, times the seconds
+// it held. price_a is reserve_b / reserve_a for constant product pools and the
+// curve's marginal price for the others, see Pool::spot_prices_q64. TWAP over [t0, t1] is
+//   (cumulative(t1) - cumulative(t0)) / (t1 - t0)
+// with wrapping subtraction, the accumulators are allowed to overflow.
+use anchor_lang::prelude::*;
+
+use crate::{math, ErrorCode, Pool};
+
+pub const OBSERVATION_CAPACITY: usize = 128;
+
+// Minimum spacing between two observations. Trades in between still update the
+// accumulators on the Pool, they just don't take a slot in the buffer, so the
+// buffer always reaches back at least OBSERVATION_CAPACITY * OBSERVATION_INTERVAL seconds.
+pub const OBSERVATION_INTERVAL: i64 = 15;
+
+// Keeps the observe return data well under the 1024 byte limit
+pub const MAX_OBSERVE_POINTS: usize = 16;
+
+#[zero_copy]
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct Observation {
+    pub price_a_cumulative: u128,
+    pub price_b_cumulative: u128,
+    pub timestamp: i64,
+    pub padding: [u8; 8],
+}
+
+#[account(zero_copy)]
+pub struct Observations {
+    pub pool: Pubkey,
+    pub index: u16, // slot of the newest observation
+    pub count: u16, // filled slots, at most OBSERVATION_CAPACITY
+    pub padding: [u8; 12],
+    pub observations: [Observation; OBSERVATION_CAPACITY],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CumulativePrices {
+    pub price_a_cumulative: u128,
+    pub price_b_cumulative: u128,
+}
+
+impl Observations {
+    // Appends the pool's current accumulators, unless the newest observation is
+    // younger than OBSERVATION_INTERVAL
+    pub fn record(&mut self, pool: &Pool) {
+        if self.count > 0 {
+            let newest = &self.observations[self.index as usize];
+            if pool.last_update_timestamp - newest.timestamp < OBSERVATION_INTERVAL {
+                return;
+            }
+            self.index = ((self.index as usize + 1) % OBSERVATION_CAPACITY) as u16;
+        }
+        if (self.count as usize) < OBSERVATION_CAPACITY {
+            self.count += 1;
+        }
+
+        self.observations[self.index as usize] = Observation {
+            price_a_cumulative: pool.price_a_cumulative,
+            price_b_cumulative: pool.price_b_cumulative,
+            timestamp: pool.last_update_timestamp,
+            padding: [0; 8],
+        };
+    }
+
+    // Observations from oldest to newest
+    fn chronological(&self) -> impl Iterator<Item = &Observation> {
+        let count = self.count as usize;
+        let oldest = (self.index as usize + OBSERVATION_CAPACITY + 1 - count) % OBSERVATION_CAPACITY;
+        (0..count).map(move |offset| &self.observations[(oldest + offset) % OBSERVATION_CAPACITY])
+    }
+}
+
+// Brings the accumulators up to `now` with the reserves that held since the last
+// update, then records an observation. Must run before the reserves change.
+pub fn update(pool: &mut Pool, observations: &mut Observations, now: i64) {
+    pool.update_price_accumulators(now);
+    observations.record(pool);
+}
+
+// update() with the cluster clock, for instruction handlers
+pub fn update_with_clock(pool: &mut Pool, observations: &AccountLoader<Observations>) -> Result<()> {
+    update(pool, &mut *observations.load_mut()?, Clock::get()?.unix_timestamp);
+    Ok(())
+}
+
+// Price of the first reserve's token in the second's, UQ64.64
+pub fn price_q64(reserve_base: u64, reserve_quote: u64) -> u128 {
+    if reserve_base == 0 {
+        return 0;
+    }
+    ((reserve_quote as u128) << 64) / reserve_base as u128
+}
+
+// Accumulator values at `target` (unix timestamp, at most `now`).
+// Exact after the pool's last update, linearly interpolated between observations before that.
+pub fn cumulatives_at(pool: &Pool, observations: &Observations, now: i64, target: i64) -> Result<CumulativePrices> {
+    require!(target <= now, ErrorCode::InvalidObservationWindow);
+
+    // Since the last update the price has been constant at the current reserves
+    if target >= pool.last_update_timestamp {
+        let elapsed = (target - pool.last_update_timestamp) as u128;
+        let (price_a, price_b) = pool.spot_prices_q64(target);
+        return Ok(CumulativePrices {
+            price_a_cumulative: pool.price_a_cumulative.wrapping_add(price_a.wrapping_mul(elapsed)),
+            price_b_cumulative: pool.price_b_cumulative.wrapping_add(price_b.wrapping_mul(elapsed)),
+        });
+    }
+
+    // The pool's own accumulators act as the newest point, they may be newer than the buffer
+    let latest = Observation {
+        price_a_cumulative: pool.price_a_cumulative,
+        price_b_cumulative: pool.price_b_cumulative,
+        timestamp: pool.last_update_timestamp,
+        padding: [0; 8],
+    };
+
+    let mut before: Option<&Observation> = None;
+    for observation in observations.chronological().chain(std::iter::once(&latest)) {
+        if observation.timestamp > target {
+            let before = before.ok_or(ErrorCode::ObservationTooOld)?;
+            return interpolate(before, observation, target);
+        }
+        before = Some(observation);
+    }
+
+    // Unreachable, target < latest.timestamp ends the loop above
+    err!(ErrorCode::ObservationTooOld)
+}
+
+fn interpolate(before: &Observation, after: &Observation, target: i64) -> Result<CumulativePrices> {
+    let span = (after.timestamp - before.timestamp) as u128;
+    let elapsed = (target - before.timestamp) as u128;
+
+    let step = |from: u128, to: u128| -> Result<u128> {
+        let delta = math::U256::from(to.wrapping_sub(from)) * math::U256::from(elapsed) / math::U256::from(span);
+        let delta = math::u256_to_u128(delta).ok_or(ErrorCode::MathOverflow)?;
+        Ok(from.wrapping_add(delta))
+    };
+
+    Ok(CumulativePrices {
+        price_a_cumulative: step(before.price_a_cumulative, after.price_a_cumulative)?,
+        price_b_cumulative: step(before.price_b_cumulative, after.price_b_cumulative)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stable, CurveType};
+    use bytemuck::Zeroable;
+
+    const ONE: u128 = 1 << 64;
+
+    fn pool_with_reserves(reserve_a: u64, reserve_b: u64) -> Pool {
+        Pool { reserve_a, reserve_b, ..Default::default() }
+    }
+
+    #[test]
+    fn price_is_uq64_64() {
+        assert_eq!(price_q64(1, 1), ONE);
+        assert_eq!(price_q64(2, 1), ONE / 2);
+        assert_eq!(price_q64(1, 3), 3 * ONE);
+        assert_eq!(price_q64(0, 3), 0);
+        assert_eq!(price_q64(1, u64::MAX), (u64::MAX as u128) << 64);
+    }
+
+    #[test]
+    fn accumulators_use_reserves_before_the_change() {
+        let mut pool = pool_with_reserves(100, 200);
+        pool.last_update_timestamp = 1_000;
+
+        pool.update_price_accumulators(1_010);
+        assert_eq!(pool.price_a_cumulative, 2 * ONE * 10);
+        assert_eq!(pool.price_b_cumulative, ONE / 2 * 10);
+        assert_eq!(pool.last_update_timestamp, 1_010);
+
+        // Same second, nothing accrues
+        pool.update_price_accumulators(1_010);
+        assert_eq!(pool.price_a_cumulative, 2 * ONE * 10);
+    }
+
+    #[test]
+    fn ring_buffer_wraps_and_respects_interval() {
+        let mut observations = Observations::zeroed();
+        let mut pool = pool_with_reserves(1, 1);
+
+        for step in 0..(OBSERVATION_CAPACITY as i64 + 5) {
+            pool.update_price_accumulators(step * OBSERVATION_INTERVAL);
+            observations.record(&pool);
+            // Too soon, skipped
+            pool.update_price_accumulators(step * OBSERVATION_INTERVAL + 1);
+            observations.record(&pool);
+        }
+
+        assert_eq!(observations.count as usize, OBSERVATION_CAPACITY);
+        let timestamps: Vec<i64> = observations.chronological().map(|o| o.timestamp).collect();
+        assert_eq!(timestamps.len(), OBSERVATION_CAPACITY);
+        assert_eq!(timestamps[0], 5 * OBSERVATION_INTERVAL);
+        assert!(timestamps.windows(2).all(|w| w[1] - w[0] == OBSERVATION_INTERVAL));
+    }
+
+    #[test]
+    fn twap_over_a_price_change() {
+        let mut observations = Observations::zeroed();
+        let mut pool = pool_with_reserves(100, 100);
+        pool.last_update_timestamp = 0;
+        update(&mut pool, &mut observations, 0);
+
+        // Price 1 for 100s, then the reserves move to a price of 3
+        update(&mut pool, &mut observations, 100);
+        pool.reserve_b = 300;
+
+        let now = 200;
+        let start = cumulatives_at(&pool, &observations, now, 0).unwrap();
+        let middle = cumulatives_at(&pool, &observations, now, 50).unwrap();
+        let end = cumulatives_at(&pool, &observations, now, now).unwrap();
+
+        let twap = (end.price_a_cumulative - start.price_a_cumulative) / now as u128;
+        assert_eq!(twap, 2 * ONE);
+        assert_eq!(middle.price_a_cumulative, 50 * ONE);
+
+        assert_eq!(
+            cumulatives_at(&pool, &observations, now, -1).unwrap_err(),
+            error!(ErrorCode::ObservationTooOld)
+        );
+        assert!(cumulatives_at(&pool, &observations, now, now + 1).is_err());
+    }
+
+    #[test]
+    fn stable_twap_is_the_marginal_swap_price() {
+        let mut observations = Observations::zeroed();
+        let mut pool = Pool {
+            curve_type: CurveType::StableSwap,
+            amp_target: 100,
+            ..pool_with_reserves(3_000_000_000_000, 1_000_000_000_000)
+        };
+        update(&mut pool, &mut observations, 0);
+
+        let now = 10;
+        let start = cumulatives_at(&pool, &observations, now, 0).unwrap();
+        let end = cumulatives_at(&pool, &observations, now, now).unwrap();
+        let twap = (end.price_a_cumulative - start.price_a_cumulative) / now as u128;
+
+        // A tiny swap of A for B moves along the curve at the marginal price
+        let amount_in = 1_000_000;
+        let amount_out = stable::calculate_output(amount_in, pool.reserve_a, pool.reserve_b, 100, 0).unwrap();
+        let marginal = ((amount_out as u128) << 64) / amount_in as u128;
+        assert!(twap.abs_diff(marginal) < twap / 100_000, "{twap} vs {marginal}");
+        // Far from the constant product price of 1/3
+        assert!(twap > 2 * price_q64(pool.reserve_a, pool.reserve_b));
+    }
+
+    #[test]
+    fn weighted_twap_follows_the_weights() {
+        let mut observations = Observations::zeroed();
+        // 80/20: (2_000 / 0.2) / (1_000 / 0.8) = 8, where x * y = k would give 2
+        let mut pool = Pool {
+            curve_type: CurveType::Weighted,
+            weight_a: 8_000,
+            weight_b: 2_000,
+            ..pool_with_reserves(1_000, 2_000)
+        };
+        update(&mut pool, &mut observations, 0);
+
+        let now = 10;
+        let start = cumulatives_at(&pool, &observations, now, 0).unwrap();
+        let end = cumulatives_at(&pool, &observations, now, now).unwrap();
+        assert_eq!((end.price_a_cumulative - start.price_a_cumulative) / now as u128, 8 * ONE);
+        assert_eq!((end.price_b_cumulative - start.price_b_cumulative) / now as u128, ONE / 8);
+
+        // The accumulators on the pool agree with the read-only path
+        pool.update_price_accumulators(now);
+        assert_eq!(pool.price_a_cumulative, end.price_a_cumulative);
+    }
+
+    #[test]
+    fn twap_survives_accumulator_overflow() {
+        let mut observations = Observations::zeroed();
+        let mut pool = pool_with_reserves(1, 1_000);
+        pool.price_a_cumulative = u128::MAX - ONE;
+        update(&mut pool, &mut observations, 0);
+
+        let now = 20;
+        let start = cumulatives_at(&pool, &observations, now, 0).unwrap();
+        let end = cumulatives_at(&pool, &observations, now, now).unwrap();
+        let twap = end.price_a_cumulative.wrapping_sub(start.price_a_cumulative) / now as u128;
+        assert_eq!(twap, 1_000 * ONE);
+    }
+}
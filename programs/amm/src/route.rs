@@ -0,0 +1,291 @@
+// Hop accounts for swap_route. Each hop is ACCOUNTS_PER_HOP remaining accounts,
+// in this order:
+//   0. pool (mut)
+//   1. the pool's observations (mut)
+//   2. the pool's vault for the token coming in (mut)
+//   3. the pool's vault for the token going out (mut)
+//   4. mint of the token going out
+//   5. token program of that mint
+// The first hop starts with SwapRoute::input_mint and every later hop with the
+// previous hop's output mint, the last hop must end in SwapRoute::output_mint.
+use anchor_lang::prelude::*;
+use anchor_spl::token_interface::{Mint, TokenAccount, TokenInterface};
+
+use crate::{oracle::Observations, ErrorCode, Pool};
+
+pub const ACCOUNTS_PER_HOP: usize = 6;
+pub const MAX_ROUTE_HOPS: usize = 4;
+
+pub struct Hop<'info> {
+    pub pool: Box<Account<'info, Pool>>,
+    pub observations: AccountLoader<'info, Observations>,
+    pub vault_in: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub vault_out: Box<InterfaceAccount<'info, TokenAccount>>,
+    pub mint_out: Box<InterfaceAccount<'info, Mint>>,
+    pub token_program_out: Interface<'info, TokenInterface>,
+    pub is_a_to_b: bool,
+}
+
+impl Hop<'_> {
+    pub fn mint_in(&self) -> Pubkey {
+        if self.is_a_to_b {
+            self.pool.mint_a
+        } else {
+            self.pool.mint_b
+        }
+    }
+}
+
+// Deserializes and validates every hop. A pool may appear only once, each hop
+// holds its own copy of the Pool and a second copy would overwrite the first.
+pub fn load_hops<'info>(
+    accounts: &'info [AccountInfo<'info>],
+    input_mint: Pubkey,
+    output_mint: Pubkey,
+) -> Result<Vec<Hop<'info>>> {
+    // A cycle would pay out into the account the input came from, and the output
+    // measured on it would be net of the input
+    require_keys_neq!(input_mint, output_mint, ErrorCode::InvalidRoute);
+    let hop_count = accounts.len() / ACCOUNTS_PER_HOP;
+    require!(
+        hop_count > 0 && hop_count <= MAX_ROUTE_HOPS && hop_count * ACCOUNTS_PER_HOP == accounts.len(),
+        ErrorCode::InvalidRoute
+    );
+
+    let mut hops: Vec<Hop<'info>> = Vec::with_capacity(hop_count);
+    let mut mint_in = input_mint;
+    for hop_accounts in accounts.chunks(ACCOUNTS_PER_HOP) {
+        let pool = Box::new(Account::<Pool>::try_from(&hop_accounts[0])?);
+        require!(hops.iter().all(|hop| hop.pool.key() != pool.key()), ErrorCode::InvalidRoute);
+
+        let observations = AccountLoader::<Observations>::try_from(&hop_accounts[1])?;
+        require_keys_eq!(observations.load()?.pool, pool.key(), ErrorCode::InvalidRoute);
+
+        let is_a_to_b = mint_in == pool.mint_a;
+        require!(is_a_to_b || mint_in == pool.mint_b, ErrorCode::InvalidMint);
+        let (vault_in, vault_out, mint_out) = if is_a_to_b {
+            (pool.vault_a, pool.vault_b, pool.mint_b)
+        } else {
+            (pool.vault_b, pool.vault_a, pool.mint_a)
+        };
+        require_keys_eq!(hop_accounts[2].key(), vault_in, ErrorCode::InvalidVault);
+        require_keys_eq!(hop_accounts[3].key(), vault_out, ErrorCode::InvalidVault);
+        require_keys_eq!(hop_accounts[4].key(), mint_out, ErrorCode::InvalidMint);
+
+        let token_program_out = Interface::<TokenInterface>::try_from(&hop_accounts[5])?;
+        require_keys_eq!(*hop_accounts[4].owner, token_program_out.key(), ErrorCode::InvalidRoute);
+
+        hops.push(Hop {
+            pool,
+            observations,
+            vault_in: Box::new(InterfaceAccount::try_from(&hop_accounts[2])?),
+            vault_out: Box::new(InterfaceAccount::try_from(&hop_accounts[3])?),
+            mint_out: Box::new(InterfaceAccount::try_from(&hop_accounts[4])?),
+            token_program_out,
+            is_a_to_b,
+        });
+        mint_in = mint_out;
+    }
+
+    require_keys_eq!(mint_in, output_mint, ErrorCode::InvalidRoute);
+    Ok(hops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::{bpf_loader_upgradeable, program_pack::Pack};
+    use anchor_spl::token::spl_token;
+
+    struct TestAccount {
+        key: Pubkey,
+        owner: Pubkey,
+        lamports: u64,
+        data: Vec<u8>,
+        // Start of the account data in `data`, chosen so zero-copy bodies are 16-byte aligned
+        offset: usize,
+        executable: bool,
+    }
+
+    impl TestAccount {
+        fn new(key: Pubkey, owner: Pubkey, data: Vec<u8>) -> Self {
+            let mut buffer = vec![0; data.len() + 16];
+            let offset = (0..16).find(|offset| (buffer.as_ptr() as usize + offset + 8).is_multiple_of(16)).unwrap();
+            buffer[offset..offset + data.len()].copy_from_slice(&data);
+            buffer.truncate(offset + data.len());
+            TestAccount { key, owner, lamports: 0, data: buffer, offset, executable: false }
+        }
+    }
+
+    fn infos(accounts: &mut [TestAccount]) -> Vec<AccountInfo<'_>> {
+        accounts
+            .iter_mut()
+            .map(|account| {
+                AccountInfo::new(
+                    &account.key,
+                    false,
+                    true,
+                    &mut account.lamports,
+                    &mut account.data[account.offset..],
+                    &account.owner,
+                    account.executable,
+                    0,
+                )
+            })
+            .collect()
+    }
+
+    fn mint_account(key: Pubkey) -> TestAccount {
+        let mut data = vec![0; spl_token::state::Mint::LEN];
+        spl_token::state::Mint { decimals: 6, is_initialized: true, ..Default::default() }.pack_into_slice(&mut data);
+        TestAccount::new(key, spl_token::ID, data)
+    }
+
+    fn vault_account(key: Pubkey, mint: Pubkey, pool: Pubkey) -> TestAccount {
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        spl_token::state::Account {
+            mint,
+            owner: pool,
+            state: spl_token::state::AccountState::Initialized,
+            ..Default::default()
+        }
+        .pack_into_slice(&mut data);
+        TestAccount::new(key, spl_token::ID, data)
+    }
+
+    fn pool(mint_a: Pubkey, mint_b: Pubkey) -> (Pubkey, Pool) {
+        let pool = Pool { mint_a, mint_b, vault_a: Pubkey::new_unique(), vault_b: Pubkey::new_unique(), ..Default::default() };
+        (Pubkey::new_unique(), pool)
+    }
+
+    // The ACCOUNTS_PER_HOP accounts selling `mint_in` into `pool`
+    fn hop_accounts((pool_key, pool): &(Pubkey, Pool), mint_in: Pubkey) -> Vec<TestAccount> {
+        let (vault_in, vault_out, mint_out) = if mint_in == pool.mint_a {
+            ((pool.vault_a, pool.mint_a), (pool.vault_b, pool.mint_b), pool.mint_b)
+        } else {
+            ((pool.vault_b, pool.mint_b), (pool.vault_a, pool.mint_a), pool.mint_a)
+        };
+
+        let mut pool_data = Vec::new();
+        pool.try_serialize(&mut pool_data).unwrap();
+        let mut observations_data = vec![0; 8 + std::mem::size_of::<Observations>()];
+        observations_data[..8].copy_from_slice(Observations::DISCRIMINATOR);
+        observations_data[8..40].copy_from_slice(pool_key.as_ref());
+        let mut token_program = TestAccount::new(spl_token::ID, bpf_loader_upgradeable::ID, Vec::new());
+        token_program.executable = true;
+
+        vec![
+            TestAccount::new(*pool_key, crate::ID, pool_data),
+            TestAccount::new(Pubkey::new_unique(), crate::ID, observations_data),
+            vault_account(vault_in.0, vault_in.1, *pool_key),
+            vault_account(vault_out.0, vault_out.1, *pool_key),
+            mint_account(mint_out),
+            token_program,
+        ]
+    }
+
+    fn load(accounts: &mut [TestAccount], input_mint: Pubkey, output_mint: Pubkey) -> Result<Vec<bool>> {
+        let infos = infos(accounts);
+        let hops = load_hops(&infos, input_mint, output_mint)?;
+        Ok(hops.iter().map(|hop| hop.is_a_to_b).collect())
+    }
+
+    fn mints() -> [Pubkey; 3] {
+        let mut mints = [Pubkey::new_unique(), Pubkey::new_unique(), Pubkey::new_unique()];
+        mints.sort();
+        mints
+    }
+
+    #[test]
+    fn loads_a_chained_route_in_either_direction() {
+        let [a, b, c] = mints();
+        let (pool_ab, pool_bc) = (pool(a, b), pool(b, c));
+
+        let mut accounts = hop_accounts(&pool_ab, a);
+        accounts.extend(hop_accounts(&pool_bc, b));
+        assert_eq!(load(&mut accounts, a, c).unwrap(), vec![true, true]);
+
+        let mut accounts = hop_accounts(&pool_bc, c);
+        accounts.extend(hop_accounts(&pool_ab, b));
+        assert_eq!(load(&mut accounts, c, a).unwrap(), vec![false, false]);
+    }
+
+    #[test]
+    fn rejects_bad_hop_counts() {
+        let [a, b, c] = mints();
+        let pools: Vec<_> = (0..=MAX_ROUTE_HOPS).map(|_| pool(a, b)).collect();
+
+        assert_eq!(load(&mut [], a, b).unwrap_err(), error!(ErrorCode::InvalidRoute));
+
+        let mut accounts = hop_accounts(&pools[0], a);
+        accounts.pop();
+        assert_eq!(load(&mut accounts, a, b).unwrap_err(), error!(ErrorCode::InvalidRoute));
+
+        // Alternating directions through distinct pools, one hop too many
+        let mut accounts = Vec::new();
+        for (index, hop_pool) in pools.iter().enumerate() {
+            accounts.extend(hop_accounts(hop_pool, if index % 2 == 0 { a } else { b }));
+        }
+        assert_eq!(load(&mut accounts, a, b).unwrap_err(), error!(ErrorCode::InvalidRoute));
+
+        // Exactly MAX_ROUTE_HOPS, the last one leaving the A/B pools for C
+        accounts.truncate((MAX_ROUTE_HOPS - 1) * ACCOUNTS_PER_HOP);
+        accounts.extend(hop_accounts(&pool(b, c), b));
+        assert_eq!(load(&mut accounts, a, c).unwrap().len(), MAX_ROUTE_HOPS);
+    }
+
+    #[test]
+    fn rejects_a_pool_used_twice() {
+        let [a, b, _] = mints();
+        let pool_ab = pool(a, b);
+        let mut accounts = hop_accounts(&pool_ab, a);
+        accounts.extend(hop_accounts(&pool_ab, b));
+        accounts.extend(hop_accounts(&pool_ab, a));
+        assert_eq!(load(&mut accounts, a, b).unwrap_err(), error!(ErrorCode::InvalidRoute));
+    }
+
+    #[test]
+    fn rejects_a_cycle() {
+        let [a, b, c] = mints();
+        let mut accounts = hop_accounts(&pool(a, b), a);
+        accounts.extend(hop_accounts(&pool(b, c), b));
+        accounts.extend(hop_accounts(&pool(a, c), c));
+        assert_eq!(load(&mut accounts, a, a).unwrap_err(), error!(ErrorCode::InvalidRoute));
+    }
+
+    #[test]
+    fn rejects_wrong_vaults_and_mints() {
+        let [a, b, c] = mints();
+        let pool_ab = pool(a, b);
+
+        let mut accounts = hop_accounts(&pool_ab, a);
+        accounts.swap(2, 3);
+        assert_eq!(load(&mut accounts, a, b).unwrap_err(), error!(ErrorCode::InvalidVault));
+
+        let mut accounts = hop_accounts(&pool_ab, a);
+        accounts[4] = mint_account(c);
+        assert_eq!(load(&mut accounts, a, b).unwrap_err(), error!(ErrorCode::InvalidMint));
+
+        // The route starts with a mint the first pool does not hold
+        let mut accounts = hop_accounts(&pool_ab, a);
+        assert_eq!(load(&mut accounts, c, b).unwrap_err(), error!(ErrorCode::InvalidMint));
+
+        // Observations of another pool
+        let mut accounts = hop_accounts(&pool_ab, a);
+        accounts[1] = hop_accounts(&pool(a, b), a).swap_remove(1);
+        assert_eq!(load(&mut accounts, a, b).unwrap_err(), error!(ErrorCode::InvalidRoute));
+    }
+
+    #[test]
+    fn rejects_a_broken_chain() {
+        let [a, b, c] = mints();
+        // The second hop sells C, but the first one bought B
+        let mut accounts = hop_accounts(&pool(a, b), a);
+        accounts.extend(hop_accounts(&pool(a, c), c));
+        assert_eq!(load(&mut accounts, a, c).unwrap_err(), error!(ErrorCode::InvalidMint));
+
+        // The last hop does not end in the output mint
+        let mut accounts = hop_accounts(&pool(a, b), a);
+        assert_eq!(load(&mut accounts, a, c).unwrap_err(), error!(ErrorCode::InvalidRoute));
+    }
+}
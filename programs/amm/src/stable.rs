@@ -0,0 +1,289 @@
+// StableSwap invariant (Curve v1) for two tokens with the same decimals:
+//   A * n^n * (x + y) + D = A * D * n^n + D^(n+1) / (n^n * x * y),  n = 2
+// D and y have no closed form and are found with Newton's method. Intermediates
+// reach D^3, so everything runs in U256.
+//
+// The swap fee is taken from the input before the curve, exactly like the
+// constant product path, so protocol fee accounting is the same for both curves.
+use crate::math::{self, U256};
+use crate::{amount_after_fee, FEE_DENOMINATOR};
+
+pub const MIN_AMP: u64 = 1;
+pub const MAX_AMP: u64 = 1_000_000;
+
+// A ramp must take at least this long and may move A by at most MAX_AMP_CHANGE x
+pub const MIN_RAMP_DURATION: i64 = 86_400;
+pub const MAX_AMP_CHANGE: u64 = 10;
+
+const N_COINS: u64 = 2;
+const MAX_ITERATIONS: usize = 255;
+
+fn abs_diff(a: U256, b: U256) -> U256 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
+
+// Invariant D for the given reserves
+pub fn compute_d(amp: u64, reserve_a: u64, reserve_b: u64) -> Option<u128> {
+    let sum = U256::from(reserve_a) + U256::from(reserve_b);
+    if sum.is_zero() {
+        return Some(0);
+    }
+    if reserve_a == 0 || reserve_b == 0 {
+        return None;
+    }
+
+    let n = U256::from(N_COINS);
+    let ann = U256::from(amp).checked_mul(n)?;
+    let product = U256::from(reserve_a) * U256::from(reserve_b) * n * n;
+    let mut d = sum;
+    for _ in 0..MAX_ITERATIONS {
+        // D_P = D^3 / (n^n * x * y), one division: Curve's per-coin truncation makes
+        // Newton oscillate instead of converging on very imbalanced pools
+        let d_p = d.checked_mul(d)?.checked_mul(d)? / product;
+
+        let d_prev = d;
+        let numerator = ann.checked_mul(sum)?.checked_add(d_p.checked_mul(n)?)?.checked_mul(d)?;
+        let denominator = ann
+            .checked_sub(U256::one())?
+            .checked_mul(d)?
+            .checked_add(d_p.checked_mul(n + 1)?)?;
+        d = numerator / denominator;
+
+        if abs_diff(d, d_prev) <= U256::one() {
+            return math::u256_to_u128(d);
+        }
+    }
+    None
+}
+
+// The other reserve that keeps D when one reserve is `reserve_x`
+pub fn compute_y(amp: u64, reserve_x: u128, d: u128) -> Option<u128> {
+    if reserve_x == 0 {
+        return None;
+    }
+
+    let n = U256::from(N_COINS);
+    let ann = U256::from(amp).checked_mul(n)?;
+    let x = U256::from(reserve_x);
+    let d = U256::from(d);
+
+    // c = D^3 / (n^n * x * Ann), b = x + D / Ann
+    let c = d.checked_mul(d)? / (x * n);
+    let c = c.checked_mul(d)? / (ann * n);
+    let b = x + d / ann;
+
+    let mut y = d;
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+        let numerator = y.checked_mul(y)?.checked_add(c)?;
+        let denominator = y.checked_mul(U256::from(2))?.checked_add(b)?.checked_sub(d)?;
+        y = numerator / denominator;
+
+        if abs_diff(y, y_prev) <= U256::one() {
+            return math::u256_to_u128(y);
+        }
+    }
+    None
+}
+
+// Helper function for StableSwap Swaps (counterpart of calculate_amm_output)
+// Output = Reserve_Out - y(Reserve_In + Input_After_Fee) - 1
+// The extra unit covers Newton's rounding, so the pool never pays out more than D allows
+pub fn calculate_output(amount_in: u64, reserve_in: u64, reserve_out: u64, amp: u64, fee_bps: u16) -> Option<u64> {
+    let amount_in = amount_after_fee(amount_in, fee_bps)?;
+    let d = compute_d(amp, reserve_in, reserve_out)?;
+    let new_reserve_in = (reserve_in as u128).checked_add(amount_in)?;
+    let new_reserve_out = compute_y(amp, new_reserve_in, d)?;
+
+    let amount_out = (reserve_out as u128).saturating_sub(new_reserve_out.checked_add(1)?);
+    math::to_u64(amount_out)
+}
+
+// Helper function for StableSwap Exact Output Swaps (counterpart of calculate_amm_input)
+// Input_After_Fee = y(Reserve_Out - Output) - Reserve_In + 1
+// Input = ceil(Input_After_Fee * 10_000 / (10_000 - Fee_Bps))
+pub fn calculate_input(amount_out: u64, reserve_in: u64, reserve_out: u64, amp: u64, fee_bps: u16) -> Option<u64> {
+    let remaining_out = reserve_out.checked_sub(amount_out)?;
+    if remaining_out == 0 {
+        return None;
+    }
+
+    let d = compute_d(amp, reserve_in, reserve_out)?;
+    let new_reserve_in = compute_y(amp, remaining_out as u128, d)?;
+    // One unit for Newton's rounding, one more for the - 1 in calculate_output
+    let amount_in_after_fee = new_reserve_in.checked_sub(reserve_in as u128)?.checked_add(2)?;
+
+    let fee_multiplier = FEE_DENOMINATOR.checked_sub(fee_bps as u64)?;
+    let amount_in = math::mul_div_ceil(amount_in_after_fee, FEE_DENOMINATOR as u128, fee_multiplier as u128)?;
+    math::to_u64(amount_in)
+}
+
+// Helper function for StableSwap Single-Sided Deposits (Curve's add_liquidity)
+// The deposit moves the pool away from its current ratio, so the part of each
+// balance that differs from the ideal (ratio-preserving) balance pays half the
+// swap fee. LP = supply * (D_after_fees - D_before) / D_before, rounded down.
+// Returns (lp_amount, fee on the deposited token, fee on the other token).
+pub fn calculate_single_deposit(
+    amount_in: u64,
+    reserve_in: u64,
+    reserve_other: u64,
+    amp: u64,
+    fee_bps: u16,
+    total_supply: u64,
+) -> Option<(u64, u64, u64)> {
+    let d0 = compute_d(amp, reserve_in, reserve_other)?;
+    let new_reserve_in = reserve_in.checked_add(amount_in)?;
+    let d1 = compute_d(amp, new_reserve_in, reserve_other)?;
+
+    // n / (4 (n - 1)) of the swap fee, half of it for two tokens
+    let imbalance_fee = |reserve_before: u64, reserve_after: u64| -> Option<u64> {
+        let ideal = math::mul_div_floor(d1, reserve_before as u128, d0)?;
+        let difference = ideal.abs_diff(reserve_after as u128);
+        math::to_u64(math::mul_div_ceil(difference, fee_bps as u128, 2 * FEE_DENOMINATOR as u128)?)
+    };
+    let fee_in = imbalance_fee(reserve_in, new_reserve_in)?;
+    let fee_other = imbalance_fee(reserve_other, reserve_other)?;
+
+    let d2 = compute_d(
+        amp,
+        new_reserve_in.checked_sub(fee_in)?,
+        reserve_other.checked_sub(fee_other)?,
+    )?;
+    let lp_amount = math::mul_div_floor(total_supply as u128, d2.checked_sub(d0)?, d0)?;
+    Some((math::to_u64(lp_amount)?, fee_in, fee_other))
+}
+
+// Marginal price of the first reserve's token in the second's (-dy/dx on the
+// invariant), UQ64.64. Differentiating with D_P = D^3 / (4 x y) gives
+//   price = y * (Ann * x + D_P) / (x * (Ann * y + D_P))
+// which is y / x as Ann -> 0 and 1 for a balanced pool.
+pub fn spot_price_q64(amp: u64, reserve_base: u64, reserve_quote: u64) -> Option<u128> {
+    let d = U256::from(compute_d(amp, reserve_base, reserve_quote)?);
+    let n = U256::from(N_COINS);
+    let ann = U256::from(amp).checked_mul(n)?;
+    let (x, y) = (U256::from(reserve_base), U256::from(reserve_quote));
+    let d_p = d.checked_mul(d)?.checked_mul(d)? / (x * y * n * n);
+
+    let mut numerator = ann.checked_mul(x)?.checked_add(d_p)?.checked_mul(y)?;
+    let mut denominator = ann.checked_mul(y)?.checked_add(d_p)?.checked_mul(x)?;
+    // Drop the same low bits from both so the << 64 below cannot overflow
+    let excess = numerator.bits().max(denominator.bits()).saturating_sub(192);
+    numerator >>= excess;
+    denominator >>= excess;
+    math::u256_to_u128((numerator << 64) / denominator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculate_amm_output;
+    use proptest::prelude::*;
+
+    #[test]
+    fn balanced_pool_d_is_the_sum() {
+        for amp in [1, 10, 100, 10_000, MAX_AMP] {
+            let d = compute_d(amp, 1_000_000, 1_000_000).unwrap();
+            assert!(d.abs_diff(2_000_000) <= 1, "amp {amp}: {d}");
+        }
+        assert_eq!(compute_d(100, 0, 0), Some(0));
+        assert_eq!(compute_d(100, 0, 5), None);
+    }
+
+    #[test]
+    fn compute_y_recovers_the_other_reserve() {
+        let d = compute_d(100, 3_000_000, 1_000_000).unwrap();
+        let y = compute_y(100, 3_000_000, d).unwrap();
+        assert!(y.abs_diff(1_000_000) <= 2, "{y}");
+    }
+
+    #[test]
+    fn spot_price_runs_from_peg_to_constant_product() {
+        let one = 1u128 << 64;
+        let balanced = spot_price_q64(100, 1_000_000, 1_000_000).unwrap();
+        assert!(balanced.abs_diff(one) <= 1, "{balanced}");
+        // At A = 1 a 4:1 pool prices well away from the peg, towards the 1/4 of x * y = k
+        let low_amp = spot_price_q64(1, 4_000_000, 1_000_000).unwrap();
+        assert!(low_amp > one / 4 && low_amp < one / 2, "{low_amp}");
+        let high_amp = spot_price_q64(MAX_AMP, 4_000_000, 1_000_000).unwrap();
+        assert!(high_amp > one * 99 / 100, "{high_amp}");
+    }
+
+    #[test]
+    fn stable_swap_beats_constant_product_near_peg() {
+        let reserve = 1_000_000_000;
+        let stable = calculate_output(1_000_000, reserve, reserve, 100, 0).unwrap();
+        let constant_product = calculate_amm_output(1_000_000, reserve, reserve, 0).unwrap();
+        assert!(stable > constant_product);
+        assert!(stable <= 1_000_000);
+        assert!(stable >= 999_990, "{stable}");
+    }
+
+    #[test]
+    fn low_amp_behaves_like_constant_product() {
+        let stable = calculate_output(100_000, 1_000_000, 1_000_000, 1, 0).unwrap();
+        let constant_product = calculate_amm_output(100_000, 1_000_000, 1_000_000, 0).unwrap();
+        assert!(stable >= constant_product);
+        assert!(stable - constant_product < 5_000, "{stable} vs {constant_product}");
+    }
+
+    #[test]
+    fn output_never_drains_the_pool() {
+        let out = calculate_output(u64::MAX / 4, 1_000_000, 1_000_000, 1_000, 30).unwrap();
+        assert!(out < 1_000_000);
+        assert_eq!(calculate_input(1_000_000, 1_000_000, 1_000_000, 1_000, 30), None);
+    }
+
+    #[test]
+    fn single_deposit_is_worth_less_than_a_balanced_one() {
+        let supply = 2_000_000;
+        let (lp_single, fee_in, fee_other) =
+            calculate_single_deposit(100_000, 1_000_000, 1_000_000, 100, 30, supply).unwrap();
+        // A balanced deposit of the same total value would mint 200_000
+        assert!(lp_single < 100_000);
+        assert!(lp_single > 99_000, "{lp_single}");
+        assert!(fee_in > 0 && fee_other > 0);
+
+        let (lp_without_fee, _, _) = calculate_single_deposit(100_000, 1_000_000, 1_000_000, 100, 0, supply).unwrap();
+        assert!(lp_without_fee > lp_single);
+    }
+
+    proptest! {
+        #[test]
+        fn swaps_never_decrease_d(
+            reserve_in in 1_000u64..1_000_000_000_000,
+            reserve_out in 1_000u64..1_000_000_000_000,
+            amount_in in 1u64..1_000_000_000_000,
+            amp in MIN_AMP..=10_000,
+            fee in 0u16..=100,
+        ) {
+            prop_assume!(reserve_in / reserve_out < 100 && reserve_out / reserve_in < 100);
+            let amount_out = calculate_output(amount_in, reserve_in, reserve_out, amp, fee).unwrap();
+            prop_assert!(amount_out < reserve_out);
+
+            let d_before = compute_d(amp, reserve_in, reserve_out).unwrap();
+            let d_after = compute_d(amp, reserve_in + amount_in, reserve_out - amount_out).unwrap();
+            prop_assert!(d_after >= d_before);
+        }
+
+        #[test]
+        fn input_always_covers_output(
+            reserve_in in 1_000u64..1_000_000_000_000,
+            reserve_out in 1_000u64..1_000_000_000_000,
+            amount_out_bps in 1u64..9_000,
+            amp in MIN_AMP..=10_000,
+            fee in 0u16..=100,
+        ) {
+            prop_assume!(reserve_in / reserve_out < 100 && reserve_out / reserve_in < 100);
+            let amount_out = reserve_out * amount_out_bps / 10_000;
+            prop_assume!(amount_out > 0);
+
+            let amount_in = calculate_input(amount_out, reserve_in, reserve_out, amp, fee).unwrap();
+            let received = calculate_output(amount_in, reserve_in, reserve_out, amp, fee).unwrap();
+            prop_assert!(received >= amount_out, "in {} got {} wanted {}", amount_in, received, amount_out);
+        }
+    }
+}
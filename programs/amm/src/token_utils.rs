@@ -0,0 +1,362 @@
+// Token program helpers shared by every instruction that moves pool tokens.
+// Pool mints may belong to either the legacy token program or Token-2022, so
+// transfers go through transfer_checked and amounts account for transfer fees.
+use anchor_lang::prelude::*;
+use anchor_spl::token_2022::spl_token_2022::{
+    self,
+    extension::{
+        transfer_fee::TransferFeeConfig, transfer_hook::TransferHook, BaseStateWithExtensions,
+        ExtensionType, StateWithExtensions,
+    },
+};
+use anchor_spl::token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked};
+
+use crate::{clmm::ClmmPool, multi::MultiPool, ErrorCode, Pool};
+
+// Transfer hook programs a pool mint may point at. The pool instructions do not
+// forward the extra accounts a hook needs, so nothing is allowed yet.
+pub const TRANSFER_HOOK_WHITELIST: &[Pubkey] = &[];
+
+// Rejects Token-2022 extensions that let someone other than the pool move or
+// freeze vault funds, or that make the token impossible to trade
+pub fn validate_mint(mint: &InterfaceAccount<Mint>) -> Result<()> {
+    let mint_info = mint.to_account_info();
+    if *mint_info.owner != spl_token_2022::ID {
+        return Ok(());
+    }
+
+    let mint_data = mint_info.try_borrow_data()?;
+    let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+    for extension in mint_state.get_extension_types()? {
+        match extension {
+            ExtensionType::PermanentDelegate | ExtensionType::NonTransferable => {
+                return err!(ErrorCode::UnsupportedMintExtension);
+            }
+            ExtensionType::TransferHook => {
+                let hook = mint_state.get_extension::<TransferHook>()?;
+                let program_id = Option::<Pubkey>::from(hook.program_id);
+                let authority = Option::<Pubkey>::from(hook.authority);
+                // A live hook authority can point an unset hook at any program later on
+                let whitelisted = program_id.is_some_and(|id| TRANSFER_HOOK_WHITELIST.contains(&id));
+                require!(
+                    whitelisted || (program_id.is_none() && authority.is_none()),
+                    ErrorCode::UnsupportedMintExtension
+                );
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Transfer fee config of a Token-2022 mint, None for legacy mints and mints without one
+fn transfer_fee_config(mint: &InterfaceAccount<Mint>) -> Result<Option<TransferFeeConfig>> {
+    let mint_info = mint.to_account_info();
+    if *mint_info.owner != spl_token_2022::ID {
+        return Ok(None);
+    }
+
+    let mint_data = mint_info.try_borrow_data()?;
+    let mint_state = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+    Ok(mint_state.get_extension::<TransferFeeConfig>().ok().copied())
+}
+
+// Amount that lands in the destination when `amount` is sent
+pub fn amount_received(mint: &InterfaceAccount<Mint>, amount: u64) -> Result<u64> {
+    let Some(config) = transfer_fee_config(mint)? else {
+        return Ok(amount);
+    };
+
+    amount_after_transfer_fee(&config, Clock::get()?.epoch, amount)
+}
+
+// `amount` less the fee the mint charges on it in `epoch`, the fee rounds up
+fn amount_after_transfer_fee(config: &TransferFeeConfig, epoch: u64, amount: u64) -> Result<u64> {
+    let fee = config.calculate_epoch_fee(epoch, amount).ok_or(ErrorCode::MathOverflow)?;
+    Ok(amount.checked_sub(fee).ok_or(ErrorCode::MathOverflow)?)
+}
+
+// Smallest amount to send so that at least `net_amount` lands in the destination
+pub fn amount_to_send(mint: &InterfaceAccount<Mint>, net_amount: u64) -> Result<u64> {
+    let Some(config) = transfer_fee_config(mint)? else {
+        return Ok(net_amount);
+    };
+
+    amount_before_transfer_fee(&config, Clock::get()?.epoch, net_amount)
+}
+
+// Inverse of amount_after_transfer_fee, never less than what nets `net_amount`
+fn amount_before_transfer_fee(config: &TransferFeeConfig, epoch: u64, net_amount: u64) -> Result<u64> {
+    let amount = config
+        .get_epoch_fee(epoch)
+        .calculate_pre_fee_amount(net_amount)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(amount)
+}
+
+// User -> Pool, the user signs
+pub fn transfer_from_user<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    authority: &Signer<'info>,
+    amount: u64,
+) -> Result<()> {
+    let cpi_accounts = TransferChecked {
+        from: from.to_account_info(),
+        mint: mint.to_account_info(),
+        to: to.to_account_info(),
+        authority: authority.to_account_info(),
+    };
+    let cpi_ctx = CpiContext::new(token_program.to_account_info(), cpi_accounts);
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)
+}
+
+// PDA -> anyone, `authority` signs with `signer_seeds`
+pub fn transfer_signed<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    authority: AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    let cpi_accounts = TransferChecked {
+        from: from.to_account_info(),
+        mint: mint.to_account_info(),
+        to: to.to_account_info(),
+        authority,
+    };
+    let cpi_ctx = CpiContext::new_with_signer(token_program.to_account_info(), cpi_accounts, signer_seeds);
+    token_interface::transfer_checked(cpi_ctx, amount, mint.decimals)
+}
+
+// Pool -> anyone, the pool PDA signs
+pub fn transfer_from_pool<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    pool: &Account<'info, Pool>,
+    amount: u64,
+) -> Result<()> {
+    let bump = [pool.bump];
+    let seeds = &[b"pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &bump];
+    transfer_signed(token_program, mint, from, to, pool.to_account_info(), &[&seeds[..]], amount)
+}
+
+// ClmmPool -> anyone, the CLMM pool PDA signs
+pub fn transfer_from_clmm_pool<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    pool: &Account<'info, ClmmPool>,
+    amount: u64,
+) -> Result<()> {
+    let tick_spacing = pool.tick_spacing.to_le_bytes();
+    let bump = [pool.bump];
+    let seeds: &[&[u8]] = &[b"clmm_pool", pool.mint_a.as_ref(), pool.mint_b.as_ref(), &tick_spacing, &bump];
+    transfer_signed(token_program, mint, from, to, pool.to_account_info(), &[seeds], amount)
+}
+
+// MultiPool -> anyone, the multi-asset pool PDA signs
+pub fn transfer_from_multi_pool<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    to: &InterfaceAccount<'info, TokenAccount>,
+    pool: &Account<'info, MultiPool>,
+    amount: u64,
+) -> Result<()> {
+    let bump = [pool.bump];
+    let seeds: &[&[u8]] = &[b"multi_pool", pool.pool_id.as_ref(), &bump];
+    transfer_signed(token_program, mint, from, to, pool.to_account_info(), &[seeds], amount)
+}
+
+// User -> Pool vault, returning how much the vault balance actually went up.
+// Measured rather than computed, so fee-on-transfer behaviour the pool does not
+// know about can never make it credit more than it received.
+pub fn deposit_to_vault<'info>(
+    token_program: &Interface<'info, TokenInterface>,
+    mint: &InterfaceAccount<'info, Mint>,
+    from: &InterfaceAccount<'info, TokenAccount>,
+    vault: &mut InterfaceAccount<'info, TokenAccount>,
+    authority: &Signer<'info>,
+    amount: u64,
+) -> Result<u64> {
+    let balance_before = vault.amount;
+    transfer_from_user(token_program, mint, from, vault, authority, amount)?;
+    vault.reload()?;
+
+    Ok(vault.amount.checked_sub(balance_before).ok_or(ErrorCode::MathOverflow)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_lang::solana_program::{program_option::COption, program_pack::Pack};
+    use anchor_spl::token::spl_token;
+    use anchor_spl::token_2022::spl_token_2022::extension::{
+        non_transferable::NonTransferable, permanent_delegate::PermanentDelegate, transfer_fee::TransferFee,
+        BaseStateWithExtensionsMut, StateWithExtensionsMut,
+    };
+    use proptest::prelude::*;
+
+    fn legacy_mint_data() -> Vec<u8> {
+        let mut data = vec![0; spl_token::state::Mint::LEN];
+        let mint = spl_token::state::Mint { decimals: 6, is_initialized: true, ..Default::default() };
+        mint.pack_into_slice(&mut data);
+        data
+    }
+
+    // Token-2022 mint data with `extensions`, each set up by `init`
+    fn token_2022_mint_data(
+        extensions: &[ExtensionType],
+        init: impl FnOnce(&mut StateWithExtensionsMut<spl_token_2022::state::Mint>),
+    ) -> Vec<u8> {
+        let len = ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(extensions).unwrap();
+        let mut data = vec![0; len];
+        let mut state = StateWithExtensionsMut::<spl_token_2022::state::Mint>::unpack_uninitialized(&mut data).unwrap();
+        init(&mut state);
+        state.base = spl_token_2022::state::Mint {
+            mint_authority: COption::None,
+            decimals: 6,
+            is_initialized: true,
+            ..Default::default()
+        };
+        state.pack_base();
+        state.init_account_type().unwrap();
+        data
+    }
+
+    fn transfer_fee_mint_data(older: (u64, u16, u64), newer: (u64, u16, u64)) -> Vec<u8> {
+        let fee = |(epoch, bps, maximum_fee): (u64, u16, u64)| TransferFee {
+            epoch: epoch.into(),
+            transfer_fee_basis_points: bps.into(),
+            maximum_fee: maximum_fee.into(),
+        };
+        token_2022_mint_data(&[ExtensionType::TransferFeeConfig], |state| {
+            let config = state.init_extension::<TransferFeeConfig>(true).unwrap();
+            config.older_transfer_fee = fee(older);
+            config.newer_transfer_fee = fee(newer);
+        })
+    }
+
+    fn with_mint<R>(owner: Pubkey, mut data: Vec<u8>, f: impl FnOnce(&InterfaceAccount<Mint>) -> R) -> R {
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let info = AccountInfo::new(&key, false, false, &mut lamports, &mut data, &owner, false, 0);
+        let mint = InterfaceAccount::<Mint>::try_from(&info).unwrap();
+        f(&mint)
+    }
+
+    fn fee_config(bps: u16, maximum_fee: u64) -> TransferFeeConfig {
+        with_mint(spl_token_2022::ID, transfer_fee_mint_data((0, bps, maximum_fee), (0, bps, maximum_fee)), |mint| {
+            transfer_fee_config(mint).unwrap().unwrap()
+        })
+    }
+
+    #[test]
+    fn mints_without_transfer_fee_move_exact_amounts() {
+        let plain_2022 = token_2022_mint_data(&[], |_| {});
+        for (owner, data) in [(spl_token::ID, legacy_mint_data()), (spl_token_2022::ID, plain_2022)] {
+            with_mint(owner, data, |mint| {
+                assert!(validate_mint(mint).is_ok());
+                assert!(transfer_fee_config(mint).unwrap().is_none());
+                assert_eq!(amount_received(mint, 1_000).unwrap(), 1_000);
+                assert_eq!(amount_to_send(mint, 1_000).unwrap(), 1_000);
+            });
+        }
+    }
+
+    #[test]
+    fn transfer_fee_in_basis_points_rounds_up() {
+        let config = fee_config(100, u64::MAX);
+        assert_eq!(amount_after_transfer_fee(&config, 0, 10_000).unwrap(), 9_900);
+        assert_eq!(amount_before_transfer_fee(&config, 0, 9_900).unwrap(), 10_000);
+        // 1% of 101 is 1.01, charged as 2
+        assert_eq!(amount_after_transfer_fee(&config, 0, 101).unwrap(), 99);
+        // 100 already nets 99, so that is all the pool asks for
+        assert_eq!(amount_before_transfer_fee(&config, 0, 99).unwrap(), 100);
+        assert_eq!(amount_after_transfer_fee(&config, 0, 100).unwrap(), 99);
+    }
+
+    #[test]
+    fn transfer_fee_is_capped_by_maximum_fee() {
+        let config = fee_config(100, 50);
+        assert_eq!(amount_after_transfer_fee(&config, 0, 1_000_000).unwrap(), 999_950);
+        assert_eq!(amount_before_transfer_fee(&config, 0, 999_950).unwrap(), 1_000_000);
+        // Below the cap the rate applies
+        assert_eq!(amount_after_transfer_fee(&config, 0, 1_000).unwrap(), 990);
+    }
+
+    #[test]
+    fn transfer_fee_follows_the_epoch() {
+        let data = transfer_fee_mint_data((0, 100, u64::MAX), (10, 500, u64::MAX));
+        let config = with_mint(spl_token_2022::ID, data, |mint| transfer_fee_config(mint).unwrap().unwrap());
+        assert_eq!(amount_after_transfer_fee(&config, 9, 10_000).unwrap(), 9_900);
+        assert_eq!(amount_after_transfer_fee(&config, 10, 10_000).unwrap(), 9_500);
+        assert_eq!(amount_before_transfer_fee(&config, 10, 9_500).unwrap(), 10_000);
+    }
+
+    #[test]
+    fn validate_mint_rejects_unsafe_extensions() {
+        let unsupported = error!(ErrorCode::UnsupportedMintExtension);
+
+        let delegate = token_2022_mint_data(&[ExtensionType::PermanentDelegate], |state| {
+            state.init_extension::<PermanentDelegate>(true).unwrap().delegate =
+                Some(Pubkey::new_unique()).try_into().unwrap();
+        });
+        with_mint(spl_token_2022::ID, delegate, |mint| assert_eq!(validate_mint(mint).unwrap_err(), unsupported));
+
+        let non_transferable = token_2022_mint_data(&[ExtensionType::NonTransferable], |state| {
+            state.init_extension::<NonTransferable>(true).unwrap();
+        });
+        with_mint(spl_token_2022::ID, non_transferable, |mint| assert_eq!(validate_mint(mint).unwrap_err(), unsupported));
+
+        let hook = token_2022_mint_data(&[ExtensionType::TransferHook], |state| {
+            state.init_extension::<TransferHook>(true).unwrap().program_id =
+                Some(Pubkey::new_unique()).try_into().unwrap();
+        });
+        with_mint(spl_token_2022::ID, hook, |mint| assert_eq!(validate_mint(mint).unwrap_err(), unsupported));
+
+        // No program yet, but the authority could set one after the pool exists
+        let updatable_hook = token_2022_mint_data(&[ExtensionType::TransferHook], |state| {
+            state.init_extension::<TransferHook>(true).unwrap().authority =
+                Some(Pubkey::new_unique()).try_into().unwrap();
+        });
+        with_mint(spl_token_2022::ID, updatable_hook, |mint| assert_eq!(validate_mint(mint).unwrap_err(), unsupported));
+    }
+
+    #[test]
+    fn validate_mint_allows_transfer_fees_and_unset_hooks() {
+        with_mint(spl_token_2022::ID, transfer_fee_mint_data((0, 100, 50), (0, 100, 50)), |mint| {
+            assert!(validate_mint(mint).is_ok());
+        });
+
+        // Neither a program nor an authority, so the hook stays off for good
+        let unset_hook = token_2022_mint_data(&[ExtensionType::TransferHook], |state| {
+            state.init_extension::<TransferHook>(true).unwrap();
+        });
+        with_mint(spl_token_2022::ID, unset_hook, |mint| assert!(validate_mint(mint).is_ok()));
+    }
+
+    proptest! {
+        #[test]
+        fn amount_to_send_always_nets_the_amount(
+            bps in 0u16..10_000,
+            maximum_fee in 0u64..=u64::MAX,
+            net_amount in 0u64..1_000_000_000_000_000,
+        ) {
+            let config = fee_config(bps, maximum_fee);
+            let Ok(amount) = amount_before_transfer_fee(&config, 0, net_amount) else {
+                return Ok(());
+            };
+            prop_assert!(amount >= net_amount);
+            prop_assert!(amount_after_transfer_fee(&config, 0, amount).unwrap() >= net_amount);
+        }
+    }
+}
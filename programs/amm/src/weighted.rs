@@ -0,0 +1,454 @@
+// Weighted pools (Balancer v2) for two or more tokens:
+//   V = prod(B_i ^ W_i),  sum(W_i) = 1
+// An equal-weight two-token pool is constant product, 80/20 or 98/2 pools hold
+// most of their value in one token and trade with proportionally less slippage on it.
+//
+// Weights are basis points of FEE_DENOMINATOR. The curve needs real powers, so
+// this module has its own 18-decimal fixed point with ln/exp, accurate to about
+// 1e-16 relative. pow_up/pow_down widen the result by MAX_POW_RELATIVE_ERROR so
+// every rounding error lands in the pool's favour, like Balancer's LogExpMath.
+use crate::math::{self, U256};
+use crate::{amount_after_fee, FEE_DENOMINATOR};
+
+// 1.0 in 18-decimal fixed point
+pub const ONE: u128 = 1_000_000_000_000_000_000;
+
+// Smallest weight a token may have, 1%
+pub const MIN_WEIGHT: u16 = 100;
+
+// Trades may move at most 30% of a balance, Balancer's limits. Beyond them the
+// power's error margin would no longer be enough.
+pub const MAX_IN_RATIO: u128 = 300_000_000_000_000_000;
+pub const MAX_OUT_RATIO: u128 = 300_000_000_000_000_000;
+
+// 1e-14, comfortably above the ln/exp approximation error
+const MAX_POW_RELATIVE_ERROR: u128 = 10_000;
+
+const LN_2: i128 = 693_147_180_559_945_309;
+const ONE_I: i128 = ONE as i128;
+
+// a * b / denominator in U256, the fixed-point products exceed u128
+fn mul_div(a: u128, b: u128, denominator: u128, round_up: bool) -> Option<u128> {
+    let product = U256::from(a).checked_mul(U256::from(b))?;
+    let denominator = U256::from(denominator);
+    let mut quotient = product.checked_div(denominator)?;
+    if round_up && !(product % denominator).is_zero() {
+        quotient += U256::one();
+    }
+    math::u256_to_u128(quotient)
+}
+
+fn mul_down(a: u128, b: u128) -> Option<u128> {
+    mul_div(a, b, ONE, false)
+}
+
+fn mul_up(a: u128, b: u128) -> Option<u128> {
+    mul_div(a, b, ONE, true)
+}
+
+fn div_down(a: u128, b: u128) -> Option<u128> {
+    mul_div(a, ONE, b, false)
+}
+
+fn div_up(a: u128, b: u128) -> Option<u128> {
+    mul_div(a, ONE, b, true)
+}
+
+// Weight in fixed point, 10_000 basis points = ONE
+fn weight(weight_bps: u16) -> u128 {
+    weight_bps as u128 * (ONE / FEE_DENOMINATOR as u128)
+}
+
+// Natural log of a positive fixed-point number.
+// x = m * 2^k with m in [1, 2), ln(m) = 2 * atanh((m - 1) / (m + 1)) as a series,
+// which converges quickly because (m - 1) / (m + 1) < 1/3.
+pub fn ln(x: u128) -> Option<i128> {
+    if x == 0 {
+        return None;
+    }
+
+    let (m, k) = if x >= ONE {
+        let k = (x / ONE).ilog2();
+        (x >> k, k as i128)
+    } else {
+        // Shift left until x is in [1, 2), exact
+        let k = (ONE / x).ilog2();
+        let m = x << k;
+        if m >= ONE {
+            (m, -(k as i128))
+        } else {
+            (m << 1, -(k as i128) - 1)
+        }
+    };
+    let m = m as i128;
+
+    let z = (m - ONE_I) * ONE_I / (m + ONE_I);
+    let z_squared = z * z / ONE_I;
+    let mut term = z;
+    let mut sum = z;
+    let mut denominator = 1;
+    while term != 0 {
+        term = term * z_squared / ONE_I;
+        denominator += 2;
+        sum += term / denominator;
+    }
+
+    k.checked_mul(LN_2)?.checked_add(2 * sum)
+}
+
+// e^x for a fixed-point x, None if the result does not fit.
+// x = k * ln(2) + r with r in [0, ln(2)), e^r as a Taylor series, then shifted by k.
+pub fn exp(x: i128) -> Option<u128> {
+    let k = x.div_euclid(LN_2);
+    let r = x.rem_euclid(LN_2);
+
+    let mut term = ONE_I;
+    let mut sum = ONE_I;
+    let mut n = 1;
+    while term != 0 {
+        term = term * r / (ONE_I * n);
+        sum += term;
+        n += 1;
+    }
+
+    let sum = sum as u128;
+    if k >= 0 {
+        sum.checked_mul(1u128.checked_shl(u32::try_from(k).ok()?)?)
+    } else if k > -128 {
+        Some(sum >> -k)
+    } else {
+        Some(0)
+    }
+}
+
+// x^y for fixed-point x > 0 and y, without error margin
+fn pow_raw(x: u128, y: u128) -> Option<u128> {
+    if y == 0 || x == ONE {
+        return Some(ONE);
+    }
+    if y == ONE {
+        return Some(x);
+    }
+    let exponent = ln(x)?.checked_mul(i128::try_from(y).ok()?)? / ONE_I;
+    exp(exponent)
+}
+
+// x^y rounded up, at least the exact value
+pub fn pow_up(x: u128, y: u128) -> Option<u128> {
+    let raw = pow_raw(x, y)?;
+    if y == ONE {
+        return Some(raw);
+    }
+    raw.checked_add(mul_up(raw, MAX_POW_RELATIVE_ERROR)?)?.checked_add(1)
+}
+
+// x^y rounded down, at most the exact value
+pub fn pow_down(x: u128, y: u128) -> Option<u128> {
+    let raw = pow_raw(x, y)?;
+    if y == ONE {
+        return Some(raw);
+    }
+    Some(raw.saturating_sub(mul_up(raw, MAX_POW_RELATIVE_ERROR)?.checked_add(1)?))
+}
+
+// Invariant prod(B_i ^ W_i) in token units, rounded down. Equals sqrt(a * b) for
+// a 50/50 pair, so it seeds a new pool's LP supply like the constant product curve.
+pub fn compute_invariant(balances: &[u64], weights_bps: &[u16]) -> Option<u64> {
+    let mut invariant = ONE;
+    for (balance, weight_bps) in balances.iter().zip(weights_bps) {
+        let balance = (*balance as u128).checked_mul(ONE)?;
+        invariant = mul_down(invariant, pow_down(balance, weight(*weight_bps))?)?;
+    }
+    math::to_u64(invariant / ONE)
+}
+
+// Marginal price of the base token in the quote token, UQ64.64:
+//   (B_quote / W_quote) / (B_base / W_base)
+// which is B_quote / B_base for a 50/50 pair, like the constant product curve.
+pub fn spot_price_q64(reserve_base: u64, reserve_quote: u64, weight_base: u16, weight_quote: u16) -> Option<u128> {
+    let numerator = (U256::from(reserve_quote) * U256::from(weight_base)) << 64;
+    let denominator = U256::from(reserve_base) * U256::from(weight_quote);
+    math::u256_to_u128(numerator.checked_div(denominator)?)
+}
+
+// Helper function for Weighted Swaps (counterpart of calculate_amm_output)
+// Output = Reserve_Out * (1 - (Reserve_In / (Reserve_In + Input_After_Fee)) ^ (W_In / W_Out))
+pub fn calculate_output(
+    amount_in: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+    weight_in: u16,
+    weight_out: u16,
+    fee_bps: u16,
+) -> Option<u64> {
+    let amount_in = amount_after_fee(amount_in, fee_bps)?;
+    let (reserve_in, reserve_out) = (reserve_in as u128, reserve_out as u128);
+    if amount_in > mul_down(reserve_in, MAX_IN_RATIO)? {
+        return None;
+    }
+
+    let base = div_up(reserve_in, reserve_in.checked_add(amount_in)?)?;
+    let exponent = div_down(weight(weight_in), weight(weight_out))?;
+    let power = pow_up(base, exponent)?;
+    math::to_u64(mul_down(reserve_out, ONE.saturating_sub(power))?)
+}
+
+// Helper function for Weighted Exact Output Swaps (counterpart of calculate_amm_input)
+// Input_After_Fee = Reserve_In * ((Reserve_Out / (Reserve_Out - Output - 1)) ^ (W_Out / W_In) - 1)
+// Input = ceil(Input_After_Fee * 10_000 / (10_000 - Fee_Bps))
+pub fn calculate_input(
+    amount_out: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+    weight_in: u16,
+    weight_out: u16,
+    fee_bps: u16,
+) -> Option<u64> {
+    let (reserve_in, reserve_out) = (reserve_in as u128, reserve_out as u128);
+    if amount_out as u128 > mul_down(reserve_out, MAX_OUT_RATIO)? {
+        return None;
+    }
+
+    // One more unit out than asked, covers the rounding down in calculate_output
+    let base = div_up(reserve_out, reserve_out.checked_sub(amount_out as u128 + 1)?)?;
+    let exponent = div_up(weight(weight_out), weight(weight_in))?;
+    let power = pow_up(base, exponent)?;
+    let amount_in_after_fee = mul_up(reserve_in, power.checked_sub(ONE)?)?;
+
+    let fee_multiplier = FEE_DENOMINATOR.checked_sub(fee_bps as u64)?;
+    let amount_in = math::mul_div_ceil(amount_in_after_fee, FEE_DENOMINATOR as u128, fee_multiplier as u128)?;
+    math::to_u64(amount_in)
+}
+
+// Helper function for Weighted Joins with any mix of tokens (Balancer's
+// EXACT_TOKENS_IN_FOR_BPT_OUT). The part of each deposit beyond the pool's
+// proportions is effectively a swap and pays the swap fee, a proportional
+// join pays nothing and matches calculate_lp_for_deposit.
+// LP = supply * (prod((B_i + In_i_After_Fee) / B_i) ^ W_i) - 1), rounded down.
+// Returns (lp_amount, fee per token), zero amounts are fine. Like a swap, no
+// token may add more than MAX_IN_RATIO of its balance.
+pub fn calculate_join(
+    amounts_in: &[u64],
+    balances: &[u64],
+    weights_bps: &[u16],
+    fee_bps: u16,
+    total_supply: u64,
+) -> Option<(u64, Vec<u64>)> {
+    if amounts_in.len() != balances.len() || balances.len() != weights_bps.len() {
+        return None;
+    }
+
+    // What the pool's value grows by if nothing paid a fee
+    let mut balance_ratios = Vec::with_capacity(balances.len());
+    let mut invariant_ratio_with_fees = 0u128;
+    for ((amount_in, balance), weight_bps) in amounts_in.iter().zip(balances).zip(weights_bps) {
+        if *amount_in as u128 > mul_down(*balance as u128, MAX_IN_RATIO)? {
+            return None;
+        }
+        let balance_ratio = div_down((*balance as u128).checked_add(*amount_in as u128)?, *balance as u128)?;
+        invariant_ratio_with_fees = invariant_ratio_with_fees.checked_add(mul_down(balance_ratio, weight(*weight_bps))?)?;
+        balance_ratios.push(balance_ratio);
+    }
+
+    let fee_complement = ONE.checked_sub(weight(fee_bps))?;
+    let mut invariant_ratio = ONE;
+    let mut fees = Vec::with_capacity(balances.len());
+    for (index, balance_ratio) in balance_ratios.into_iter().enumerate() {
+        let (amount_in, balance) = (amounts_in[index] as u128, balances[index] as u128);
+
+        // Only the part above the proportional share is taxed
+        let amount_in_after_fee = if balance_ratio > invariant_ratio_with_fees {
+            let non_taxable = mul_down(balance, invariant_ratio_with_fees - ONE)?.min(amount_in);
+            non_taxable.checked_add(mul_down(amount_in - non_taxable, fee_complement)?)?
+        } else {
+            amount_in
+        };
+        fees.push(math::to_u64(amount_in - amount_in_after_fee)?);
+
+        let ratio_after_fee = div_down(balance.checked_add(amount_in_after_fee)?, balance)?;
+        invariant_ratio = mul_down(invariant_ratio, pow_down(ratio_after_fee, weight(weights_bps[index]))?)?;
+    }
+
+    let lp_amount = mul_down(total_supply as u128, invariant_ratio.saturating_sub(ONE))?;
+    Some((math::to_u64(lp_amount)?, fees))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calculate_amm_output;
+    use proptest::prelude::*;
+
+    // Fixed-point value as f64, for comparing against std's float math
+    fn to_f64(x: u128) -> f64 {
+        x as f64 / ONE as f64
+    }
+
+    fn assert_close(actual: f64, expected: f64, relative: f64) {
+        assert!(
+            ((actual - expected) / expected).abs() < relative,
+            "{actual} vs {expected}"
+        );
+    }
+
+    #[test]
+    fn ln_and_exp_match_reference_values() {
+        assert_eq!(ln(ONE), Some(0));
+        assert_eq!(ln(0), None);
+        assert_eq!(exp(0), Some(ONE));
+
+        // ln(2) and e, to about 1e-16
+        assert!(ln(2 * ONE).unwrap().abs_diff(LN_2) <= 2);
+        assert!(exp(ONE_I).unwrap().abs_diff(2_718_281_828_459_045_235) <= 100);
+
+        for x in [1e-12, 0.001, 0.5, 0.999_999, 1.5, 3.0, 1e6, 1.8e19] {
+            let fixed = (x * ONE as f64) as u128;
+            let actual = ln(fixed).unwrap() as f64 / ONE as f64;
+            assert!((actual - x.ln()).abs() < 1e-15 * x.ln().abs().max(1.0), "{actual} vs {}", x.ln());
+        }
+        for x in [-30.0, -1.0, -0.1, 0.1, 1.0, 10.0, 40.0] {
+            // 18 decimals, so tiny results are only accurate in absolute terms
+            let actual = to_f64(exp((x * ONE as f64) as i128).unwrap());
+            assert!((actual - x.exp()).abs() < 1e-15 * x.exp() + 1e-17, "{actual} vs {}", x.exp());
+        }
+        assert_eq!(exp(200 * ONE_I), None);
+    }
+
+    #[test]
+    fn pow_brackets_the_exact_value() {
+        let cases = [(0.8, 4.0), (0.8, 0.25), (1.3, 49.0), (0.77, 1.0 / 49.0), (2.0, 0.5), (1e9, 0.2)];
+        for (x, y) in cases {
+            let (fixed_x, fixed_y) = ((x * ONE as f64) as u128, (y * ONE as f64) as u128);
+            let up = to_f64(pow_up(fixed_x, fixed_y).unwrap());
+            let down = to_f64(pow_down(fixed_x, fixed_y).unwrap());
+            assert!(down < up);
+            assert_close(up, x.powf(y), 1e-12);
+            assert_close(down, x.powf(y), 1e-12);
+        }
+        assert_eq!(pow_up(ONE / 2, ONE), Some(ONE / 2));
+    }
+
+    #[test]
+    fn equal_weights_are_constant_product() {
+        // Same formula, only the rounding differs
+        for (amount_in, reserve_in, reserve_out) in [(1_000, 1_000_000, 2_000_000), (250_000, 1_000_000, 1_000_000)] {
+            let weighted = calculate_output(amount_in, reserve_in, reserve_out, 5_000, 5_000, 30).unwrap();
+            let constant_product = calculate_amm_output(amount_in, reserve_in, reserve_out, 30).unwrap();
+            assert!(weighted <= constant_product && constant_product - weighted <= 1);
+        }
+        assert_eq!(compute_invariant(&[1_000_000, 4_000_000], &[5_000, 5_000]), Some(1_999_999));
+    }
+
+    #[test]
+    fn swaps_match_reference_values() {
+        // 80/20 pool: out = 1_000_000 * (1 - (1_000_000 / 1_010_000)^(0.8 / 0.2)) = 39_019.6...
+        let out = calculate_output(10_000, 1_000_000, 1_000_000, 8_000, 2_000, 0).unwrap();
+        assert_eq!(out, 39_019);
+        // The other way round: out = 1_000_000 * (1 - (1 / 1.01)^0.25) = 2_484.5...
+        let out = calculate_output(10_000, 1_000_000, 1_000_000, 2_000, 8_000, 0).unwrap();
+        assert_eq!(out, 2_484);
+        // in = 1_000_000 * ((1_000_000 / 989_999)^4 - 1) = 41_024.5...
+        let amount_in = calculate_input(10_000, 1_000_000, 1_000_000, 2_000, 8_000, 0).unwrap();
+        assert_eq!(amount_in, 41_025);
+
+        // 98/2 pool holding 98% of its value in A: 98 * 1e9 A against 2e6 B
+        let out = calculate_output(1_000_000, 98_000_000_000, 2_000_000, 9_800, 200, 0).unwrap();
+        let expected = 2e6 * (1.0 - (98e9 / (98e9 + 1e6f64)).powf(49.0));
+        assert!(out as f64 <= expected && expected - out as f64 <= 1.0, "{out} vs {expected}");
+    }
+
+    #[test]
+    fn trades_above_the_ratio_limits_are_rejected() {
+        assert!(calculate_output(300_000, 1_000_000, 1_000_000, 5_000, 5_000, 0).is_some());
+        assert_eq!(calculate_output(300_001, 1_000_000, 1_000_000, 5_000, 5_000, 0), None);
+        assert_eq!(calculate_input(300_001, 1_000_000, 1_000_000, 5_000, 5_000, 0), None);
+    }
+
+    #[test]
+    fn proportional_join_pays_no_fee() {
+        let balances = [8_000_000, 2_000_000];
+        let weights = [8_000, 2_000];
+        let (lp_amount, fees) = calculate_join(&[800_000, 200_000], &balances, &weights, 30, 1_000_000).unwrap();
+        assert_eq!(fees, vec![0, 0]);
+        // 10% more of everything is 10% more LP, less the pow rounding
+        assert!((99_999..=100_000).contains(&lp_amount), "{lp_amount}");
+    }
+
+    #[test]
+    fn spot_price_scales_balances_by_weight() {
+        let one = 1u128 << 64;
+        assert_eq!(spot_price_q64(1_000, 2_000, 8_000, 2_000), Some(8 * one));
+        assert_eq!(spot_price_q64(2_000, 1_000, 2_000, 8_000), Some(one / 8));
+        // 50/50 is the constant product price
+        assert_eq!(spot_price_q64(3, 1, 5_000, 5_000), Some(crate::oracle::price_q64(3, 1)));
+        assert_eq!(spot_price_q64(0, 1, 5_000, 5_000), None);
+    }
+
+    #[test]
+    fn join_rejects_more_than_max_in_ratio() {
+        let balances = [1_000_000, 1_000_000];
+        let weights = [8_000, 2_000];
+        assert!(calculate_join(&[300_000, 0], &balances, &weights, 30, 1_000_000).is_some());
+        assert_eq!(calculate_join(&[300_001, 0], &balances, &weights, 30, 1_000_000), None);
+        assert_eq!(calculate_join(&[0, 300_001], &balances, &weights, 30, 1_000_000), None);
+        // Many times the balance, where pow would be far outside its error margin
+        assert_eq!(calculate_join(&[50_000_000, 0], &balances, &weights, 30, 1_000_000), None);
+    }
+
+    #[test]
+    fn single_asset_join_matches_reference_and_pays_fee() {
+        // Without fee: supply * ((1 + 0.1)^0.8 - 1) = 79_230.3...
+        let balances = [1_000_000, 1_000_000];
+        let weights = [8_000, 2_000];
+        let (lp_amount, fees) = calculate_join(&[100_000, 0], &balances, &weights, 0, 1_000_000).unwrap();
+        assert_eq!(lp_amount, 79_230);
+        assert_eq!(fees, vec![0, 0]);
+
+        // With fee: the 20% of the deposit that is not proportional pays 1%
+        // taxable = 100_000 - 1_000_000 * 0.08 = 20_000, fee = 200
+        let (lp_with_fee, fees) = calculate_join(&[100_000, 0], &balances, &weights, 100, 1_000_000).unwrap();
+        assert_eq!(fees, vec![200, 0]);
+        assert!(lp_with_fee < lp_amount);
+    }
+
+    proptest! {
+        #[test]
+        fn swaps_never_decrease_the_invariant(
+            reserve_in in 1_000_000u64..1_000_000_000_000,
+            reserve_out in 1_000_000u64..1_000_000_000_000,
+            amount_in_bps in 1u64..3_000,
+            weight_in in MIN_WEIGHT..=(10_000 - MIN_WEIGHT),
+            fee in 0u16..=100,
+        ) {
+            let weight_out = 10_000 - weight_in;
+            let amount_in = reserve_in / 10_000 * amount_in_bps;
+            let amount_out = calculate_output(amount_in, reserve_in, reserve_out, weight_in, weight_out, fee).unwrap();
+            prop_assert!(amount_out < reserve_out);
+
+            // Compared in floats, the fixed-point invariant rounds too coarsely for small trades
+            let (w_in, w_out) = (weight_in as f64 / 1e4, weight_out as f64 / 1e4);
+            let before = w_in * (reserve_in as f64).ln() + w_out * (reserve_out as f64).ln();
+            let after = w_in * ((reserve_in + amount_in) as f64).ln() + w_out * ((reserve_out - amount_out) as f64).ln();
+            prop_assert!(after >= before - 1e-12, "{} < {}", after, before);
+        }
+
+        #[test]
+        fn input_always_covers_output(
+            reserve_in in 1_000_000u64..1_000_000_000_000,
+            reserve_out in 1_000_000u64..1_000_000_000_000,
+            amount_out_bps in 1u64..2_500,
+            weight_in in MIN_WEIGHT..=(10_000 - MIN_WEIGHT),
+            fee in 0u16..=100,
+        ) {
+            let weight_out = 10_000 - weight_in;
+            let amount_out = reserve_out / 10_000 * amount_out_bps;
+            let Some(amount_in) = calculate_input(amount_out, reserve_in, reserve_out, weight_in, weight_out, fee) else {
+                // Needs more than MAX_IN_RATIO of the input reserve, a skewed pool's price
+                return Ok(());
+            };
+            if let Some(received) = calculate_output(amount_in, reserve_in, reserve_out, weight_in, weight_out, fee) {
+                prop_assert!(received >= amount_out, "in {} got {} wanted {}", amount_in, received, amount_out);
+            }
+        }
+    }
+}
@@ -62,8 +62,9 @@ describe("amm", () => {
       return;
     }
 
-    const fee = 3; // 3%
-    const tx = await program.methods.initialize(fee)
+    const fee = 30; // 0.30%, in basis points
+    const protocolFeeShare = 1_667; // ~1/6 of the swap fee goes to the protocol
+    const tx = await program.methods.initialize(fee, protocolFeeShare)
       .accounts({
         signer: wallet.publicKey,
         amm: ammPDA,
@@ -164,6 +165,13 @@ describe("amm", () => {
   //     false
   //   );
 
+  //   // LP account owned by the pool that holds the locked MINIMUM_LIQUIDITY
+  //   const lockedLpTokenAccount = await getAssociatedTokenAddress(
+  //     lpMint,
+  //     pool,
+  //     true
+  //   );
+
   //   // 5. Execute Create Pool
   //   try {
   //     // Request more compute units - createPool does a lot of work
@@ -172,7 +180,7 @@ describe("amm", () => {
   //     });
 
   //     const tx = await program.methods
-  //       .createPool(tokenAmountA, tokenAmountB)
+  //       .createPool(tokenAmountA, tokenAmountB, { constantProduct: {} }, new anchor.BN(0), 0)
   //       .accounts({
   //         signer: wallet.publicKey,
   //         mintA: sortedMintA,
@@ -185,6 +193,9 @@ describe("amm", () => {
   //         lpMint: lpMint,
   //         metadataAccount: metadataAccount,
   //         userLpTokenAccount: userLpTokenAccount,
+  //         lockedLpTokenAccount: lockedLpTokenAccount,
+  //         tokenProgramA: TOKEN_PROGRAM_ID,
+  //         tokenProgramB: TOKEN_PROGRAM_ID,
   //         tokenProgram: TOKEN_PROGRAM_ID,
   //         associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
   //         systemProgram: SystemProgram.programId,
@@ -204,6 +215,8 @@ describe("amm", () => {
   it("should add liqudity for the pair created", async ()=>{
     const tokenAmountA = new anchor.BN(1_000_000_000);
     const tokenAmountB = new anchor.BN(2_000_000_000);
+    const minLpOut = new anchor.BN(1);
+    const deadline = new anchor.BN(Math.floor(Date.now() / 1000) + 60);
     try {
       const modifyComputeUnits = ComputeBudgetProgram.setComputeUnitLimit({
         units: 400_000,
@@ -264,7 +277,7 @@ describe("amm", () => {
     );
 
       const tx = await program.methods
-        .addLiquidity(tokenAmountA, tokenAmountB)
+        .addLiquidity(tokenAmountA, tokenAmountB, minLpOut, deadline)
         .accounts({
           signer: wallet.publicKey,
           mintA: sortedMintA,
@@ -276,6 +289,8 @@ describe("amm", () => {
           lpMint: lpMint,
           poolTokenAccountB: poolTokenAccountB,
           userLpTokenAccount: userLpTokenAccount,
+          tokenProgramA: TOKEN_PROGRAM_ID,
+          tokenProgramB: TOKEN_PROGRAM_ID,
           tokenProgram: TOKEN_PROGRAM_ID,
           associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID,
           systemProgram: SystemProgram.programId,